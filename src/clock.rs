@@ -0,0 +1,205 @@
+/*
+Clock of the event_manager and the timers scheduled on it.
+In a backtest the clock is simulated: it only moves when market data
+arrives, to the timestamp of the data, so timers fire in the same order
+with the bars whatever the speed of the replay. In live mode it follows
+the wall time.
+*/
+use crate::shared_structures::*;
+use chrono::{Duration, Utc};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ClockMode {
+    // Driven by the timestamps of the market data, for backtests
+    Simulated,
+    // Driven by the system time in UTC, for live trading
+    WallTime,
+}
+
+struct Timer {
+    name: String,
+    every: Option<Duration>,
+}
+
+pub struct Clock {
+    mode: ClockMode,
+    // Latest time the clock moved to
+    now: Option<Timestamp>,
+    // Next deadline of every timer, ties fire in the order the timers were scheduled
+    deadlines: BinaryHeap<Reverse<(Timestamp, u64)>>,
+    // Live timers by id, cancelled ones are removed here and skipped in deadlines
+    timers: HashMap<u64, Timer>,
+}
+
+impl Clock {
+    pub fn new(mode: ClockMode) -> Self {
+        Clock {
+            mode,
+            now: None,
+            deadlines: BinaryHeap::new(),
+            timers: HashMap::new(),
+        }
+    }
+
+    pub fn mode(&self) -> ClockMode {
+        self.mode
+    }
+
+    pub fn now(&self) -> Option<Timestamp> {
+        // None before the first market data in a backtest
+        match self.mode {
+            ClockMode::Simulated => self.now,
+            ClockMode::WallTime => Some(wall_time()),
+        }
+    }
+
+    pub fn schedule(&mut self, schedule_timer_event: ScheduleTimerEvent) {
+        let ScheduleTimerEvent {
+            timer_id,
+            name,
+            at,
+            every,
+            ..
+        } = schedule_timer_event;
+        // A recurring timer needs a positive period, or it would fire forever
+        let every = every.filter(|every| *every > Duration::zero());
+        self.timers.insert(timer_id, Timer { name, every });
+        self.deadlines.push(Reverse((at, timer_id)));
+    }
+
+    pub fn cancel(&mut self, timer_id: u64) {
+        self.timers.remove(&timer_id);
+    }
+
+    pub fn next_deadline(&mut self) -> Option<Timestamp> {
+        // Drop the deadlines of cancelled timers on the way
+        while let Some(&Reverse((deadline, timer_id))) = self.deadlines.peek() {
+            if self.timers.contains_key(&timer_id) {
+                return Some(deadline);
+            }
+            self.deadlines.pop();
+        }
+        None
+    }
+
+    pub fn advance(&mut self, to: Timestamp, inclusive: bool) -> Vec<Event> {
+        /*
+        Move the clock forward and return a TimerEvent for every deadline up to
+        `to`, in time order. With inclusive false the deadlines at `to` are left
+        for later, so they can fire after the market data at that time.
+        Recurring timers fire once for each period that elapsed.
+        */
+        let mut timer_events = Vec::new();
+        while let Some(deadline) = self.next_deadline() {
+            if deadline > to || (deadline == to && !inclusive) {
+                break;
+            }
+            let Reverse((_, timer_id)) = self.deadlines.pop().unwrap();
+            let timer = &self.timers[&timer_id];
+            timer_events.push(Event::new_timer(timer_id, timer.name.clone(), deadline));
+            match timer.every {
+                Some(every) => self
+                    .deadlines
+                    .push(Reverse((Timestamp(*deadline + every), timer_id))),
+                None => {
+                    self.timers.remove(&timer_id);
+                }
+            }
+            self.now = Some(self.now.map_or(deadline, |now| now.max(deadline)));
+        }
+        if inclusive {
+            self.now = Some(self.now.map_or(to, |now| now.max(to)));
+        }
+        timer_events
+    }
+
+    pub fn advance_to_wall_time(&mut self) -> Vec<Event> {
+        self.advance(wall_time(), true)
+    }
+}
+
+fn wall_time() -> Timestamp {
+    Timestamp(Utc::now().fixed_offset())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(timestamp: &str) -> Timestamp {
+        timestamp.parse().unwrap()
+    }
+
+    fn schedule(clock: &mut Clock, name: &str, first: &str, every: Option<Duration>) -> u64 {
+        match Event::new_schedule_timer(name.to_string(), at(first), every) {
+            Event::ScheduleTimer(schedule_timer_event) => {
+                let timer_id = schedule_timer_event.timer_id;
+                clock.schedule(schedule_timer_event);
+                timer_id
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn fired(timer_events: Vec<Event>) -> Vec<(String, String)> {
+        timer_events
+            .into_iter()
+            .map(|event| match event {
+                Event::Timer(timer_event) => (timer_event.name, timer_event.timestamp.to_string()),
+                _ => unreachable!(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_one_shot_and_recurring_timers() {
+        let mut clock = Clock::new(ClockMode::Simulated);
+        assert_eq!(clock.now(), None);
+        schedule(&mut clock, "hourly", "2024-01-02 10:00:00", Some(Duration::hours(1)));
+        schedule(&mut clock, "once", "2024-01-02 11:30:00", None);
+
+        // Deadlines at the target time wait unless the advance is inclusive
+        assert_eq!(
+            fired(clock.advance(at("2024-01-02 11:00:00"), false)),
+            vec![("hourly".to_string(), "2024-01-02 10:00:00".to_string())]
+        );
+        assert_eq!(
+            fired(clock.advance(at("2024-01-02 11:00:00"), true)),
+            vec![("hourly".to_string(), "2024-01-02 11:00:00".to_string())]
+        );
+        assert_eq!(clock.now(), Some(at("2024-01-02 11:00:00")));
+
+        // A recurring timer fires for every period that elapsed, in order with the others
+        assert_eq!(
+            fired(clock.advance(at("2024-01-02 13:00:00"), true)),
+            vec![
+                ("once".to_string(), "2024-01-02 11:30:00".to_string()),
+                ("hourly".to_string(), "2024-01-02 12:00:00".to_string()),
+                ("hourly".to_string(), "2024-01-02 13:00:00".to_string()),
+            ]
+        );
+        assert_eq!(clock.next_deadline(), Some(at("2024-01-02 14:00:00")));
+    }
+
+    #[test]
+    fn test_cancel_timer() {
+        let mut clock = Clock::new(ClockMode::Simulated);
+        let timer_id = schedule(&mut clock, "daily", "2024-01-02", Some(Duration::days(1)));
+        assert_eq!(fired(clock.advance(at("2024-01-02"), true)).len(), 1);
+        clock.cancel(timer_id);
+        assert_eq!(clock.next_deadline(), None);
+        assert!(clock.advance(at("2024-01-10"), true).is_empty());
+    }
+
+    #[test]
+    fn test_wall_time() {
+        let mut clock = Clock::new(ClockMode::WallTime);
+        schedule(&mut clock, "past", "2024-01-02", None);
+        let now = clock.now().unwrap();
+        schedule(&mut clock, "later", &(now.0 + Duration::hours(1)).to_rfc3339(), None);
+        assert_eq!(fired(clock.advance_to_wall_time()).len(), 1);
+        assert!(clock.next_deadline().unwrap() > now);
+    }
+}
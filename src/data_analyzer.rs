@@ -1,37 +1,295 @@
-use crate::event_manager::ModuleReceive;
+use crate::event_manager::{ModuleHandle, ModuleReceive};
+use crate::robustness::{block_bootstrap, shuffle_trades, RobustnessConfig, RobustnessReport};
 use crate::shared_structures::*;
+use crate::tearsheet::{monthly_returns, Series, Tearsheet};
 use crossbeam::channel::{unbounded, Receiver, Sender};
 use num_traits::cast::ToPrimitive;
 use plotters::prelude::*;
+use plotters::style::Color;
+use serde::Serialize;
 use simplelog::*;
+use std::collections::HashMap;
 use std::error::Error;
+use std::fs;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use chrono::{Datelike, NaiveDate, Weekday};
 use std::sync::{Arc, Mutex};
-use std::thread;
-use std::time::Duration;
+
+// Colors of the rolling windows, for the plot and the tearsheet
+const PALETTE: [(RGBColor, &str); 5] = [
+    (RGBColor(214, 39, 40), "#d62728"),
+    (RGBColor(31, 119, 180), "#1f77b4"),
+    (RGBColor(44, 160, 44), "#2ca02c"),
+    (RGBColor(148, 103, 189), "#9467bd"),
+    (RGBColor(255, 127, 14), "#ff7f0e"),
+];
 
 #[derive(Clone)]
 pub struct DataAnalyzer {
     subscribe_sender: Sender<Event>,
     subscribe_receiver: Receiver<Event>,
-    market_data_history: Arc<Mutex<Vec<(String, f64)>>>,
-    asset_history: Arc<Mutex<Vec<(String, f64)>>>,
-    cash_history: Arc<Mutex<Vec<(String, f64)>>>,
+    market_data_history: Arc<Mutex<Vec<(Timestamp, f64)>>>,
+    asset_history: Arc<Mutex<Vec<(Timestamp, f64)>>>,
+    cash_history: Arc<Mutex<Vec<(Timestamp, f64)>>>,
     local_portfolio: Portfolio,
+    // Name of the benchmark tracked in market_data_history
+    benchmark_symbol: Option<String>,
+    // Closes of a csv benchmark by timestamp, recorded as the bars go by
+    benchmark_prices: HashMap<Timestamp, f64>,
+    metrics_config: MetricsConfig,
+    robustness_config: RobustnessConfig,
+    latest_timestamp: Option<Timestamp>,
+    // The ledger of the mock_exchange, received with the portfolio updates
+    ledger: Ledger,
+    // Round trips still open, by symbol
+    open_trades: HashMap<String, Trade>,
+    closed_trades: Vec<Trade>,
+    // Number of distinct timestamps seen, to measure holding periods in bars
+    bar_count: usize,
+    // Open positions after every portfolio update, as (timestamp, symbol, position)
+    position_history: Vec<(Timestamp, String, Position)>,
+    // Directory the reports of the run are written to
+    output_dir: String,
+}
+
+/*
+A round trip in one symbol, from the fill that opens a position to the
+fill that brings it back to flat (or flips it to the other side).
+pnl is net of the fees paid on the trade.
+*/
+#[derive(Debug, Clone)]
+struct Trade {
+    symbol: String,
+    // Long or short
+    side: &'static str,
+    open_timestamp: Timestamp,
+    close_timestamp: Timestamp,
+    open_bar: usize,
+    close_bar: usize,
+    pnl: f64,
+}
+
+#[derive(Serialize)]
+struct TradeStats {
+    number_of_trades: usize,
+    // Trades closed at exactly zero P&L, neither wins nor losses
+    breakeven_trades: usize,
+    // Share of the winners among the trades that won or lost
+    win_rate: f64,
+    average_win: f64,
+    average_loss: f64,
+    profit_factor: f64,
+    expectancy: f64,
+    largest_winner: f64,
+    largest_loser: f64,
+    // In bars
+    average_holding_period: f64,
+    longest_winning_streak: usize,
+    longest_losing_streak: usize,
+}
+
+impl Metrics {
+    fn rows(&self) -> Vec<(String, String)> {
+        // Name and formatted value of every metric, for the plot and the tearsheet
+        let relative = |value: Option<f64>, format: fn(f64) -> String| value.map_or("n/a".to_string(), format);
+        let mut rows: Vec<(String, String)> = [
+            (
+                "Market Return",
+                relative(self.market_return, |value| format!("{:.2}%", value * 100.0)),
+            ),
+            ("Portfolio Return", format!("{:.2}%", self.portfolio_return * 100.0)),
+            (
+                "Annualized Portfolio Return",
+                format!("{:.2}%", self.annualized_portfolio_return * 100.0),
+            ),
+            ("Volatility", format!("{:.4}", self.volatility)),
+            ("Sharpe Ratio", format!("{:.2}", self.sharpe_ratio)),
+            ("Max Drawdown", format!("{:.2}%", self.max_drawdown * 100.0)),
+            ("Alpha", relative(self.alpha, |value| format!("{:.4}", value))),
+            ("Beta", relative(self.beta, |value| format!("{:.4}", value))),
+            ("Sortino Ratio", format!("{:.4}", self.sortino_ratio)),
+            (
+                "Information Ratio",
+                relative(self.information_ratio, |value| format!("{:.4}", value)),
+            ),
+            ("Tracking Error", relative(self.tracking_error, |value| format!("{:.4}", value))),
+            ("Longest Drawdown Period", format!("{} days", self.longest_drawdown)),
+        ]
+        .into_iter()
+        .map(|(name, value)| (name.to_string(), value))
+        .collect();
+        for value_at_risk in &self.value_at_risk {
+            let confidence = value_at_risk.confidence * 100.0;
+            rows.push((
+                format!("Historical VaR / CVaR {}%", confidence),
+                format!(
+                    "{:.2}% / {:.2}%",
+                    value_at_risk.historical_var * 100.0,
+                    value_at_risk.historical_cvar * 100.0
+                ),
+            ));
+            rows.push((
+                format!("Parametric VaR / CVaR {}%", confidence),
+                format!(
+                    "{:.2}% / {:.2}%",
+                    value_at_risk.parametric_var * 100.0,
+                    value_at_risk.parametric_cvar * 100.0
+                ),
+            ));
+        }
+        rows.extend([
+            ("Calmar Ratio".to_string(), format!("{:.4}", self.calmar_ratio)),
+            ("Omega Ratio".to_string(), format!("{:.4}", self.omega_ratio)),
+            ("Ulcer Index".to_string(), format!("{:.4}", self.ulcer_index)),
+            ("Tail Ratio".to_string(), format!("{:.4}", self.tail_ratio)),
+            ("Skewness".to_string(), format!("{:.4}", self.skewness)),
+            ("Excess Kurtosis".to_string(), format!("{:.4}", self.kurtosis)),
+        ]);
+        rows
+    }
+}
+
+impl TradeStats {
+    fn rows(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("Number of Trades", format!("{}", self.number_of_trades)),
+            ("Breakeven Trades", format!("{}", self.breakeven_trades)),
+            ("Win Rate", format!("{:.2}%", self.win_rate * 100.0)),
+            ("Average Win", format!("{:.2}", self.average_win)),
+            ("Average Loss", format!("{:.2}", self.average_loss)),
+            ("Profit Factor", format!("{:.4}", self.profit_factor)),
+            ("Expectancy", format!("{:.2}", self.expectancy)),
+            ("Largest Winner", format!("{:.2}", self.largest_winner)),
+            ("Largest Loser", format!("{:.2}", self.largest_loser)),
+            (
+                "Average Holding Period",
+                format!("{:.1} bars", self.average_holding_period),
+            ),
+            ("Longest Winning Streak", format!("{}", self.longest_winning_streak)),
+            ("Longest Losing Streak", format!("{}", self.longest_losing_streak)),
+        ]
+    }
+}
+
+#[derive(Serialize)]
+struct RunMetadata {
+    version: &'static str,
+    // Seconds since the unix epoch when the report was written
+    generated_at: u64,
+    benchmark_symbol: Option<String>,
+    symbols: Vec<String>,
+    start: Option<Timestamp>,
+    end: Option<Timestamp>,
+    bars: usize,
+    initial_asset: Option<f64>,
+    final_asset: Option<f64>,
+}
+
+// Content of the JSON summary, metrics are null if they could not be calculated
+#[derive(Serialize)]
+struct Report {
+    metadata: RunMetadata,
+    metrics: Option<Metrics>,
+    trade_stats: TradeStats,
+    // Resampled results, one per method that had enough data
+    robustness: Vec<RobustnessReport>,
+}
+
+#[derive(Debug, Clone)]
+pub enum Benchmark {
+    // The first symbol of the market data, the default
+    FirstSymbol,
+    Symbol(String),
+    /*
+    A separate series such as an index, from a csv file with a header.
+    The close column is used, or the second column if there is none,
+    and its timestamps are read with the format, usually that of the
+    market data so the bars line up.
+    */
+    Csv(String, TimestampFormat),
 }
 
+#[derive(Debug, Clone)]
+pub struct MetricsConfig {
+    // Annual rate
+    pub risk_free_rate: f64,
+    // Bars per year, inferred from the bar interval if None
+    pub periods_per_year: Option<f64>,
+    pub benchmark: Benchmark,
+    // Confidence levels of the Value-at-Risk and Expected Shortfall, e.g. 0.95
+    pub var_confidence_levels: Vec<f64>,
+    // Window sizes in bars of the rolling series
+    pub rolling_windows: Vec<usize>,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        MetricsConfig {
+            risk_free_rate: 0.05,
+            periods_per_year: None,
+            benchmark: Benchmark::FirstSymbol,
+            var_confidence_levels: vec![0.95, 0.99],
+            rolling_windows: vec![63, 252],
+        }
+    }
+}
+
+/*
+Rolling statistics over a trailing window of bars, stamped with the last
+bar of the window. Sharpe and volatility are annualized like the metrics,
+beta uses the returns aligned with the benchmark, and drawdown is measured
+from the peak inside the window.
+*/
+struct RollingSeries {
+    window: usize,
+    sharpe: Vec<(Timestamp, f64)>,
+    volatility: Vec<(Timestamp, f64)>,
+    beta: Vec<(Timestamp, f64)>,
+    drawdown: Vec<(Timestamp, f64)>,
+}
+
+// Picks one statistic out of the rolling series
+type RollingSelector = fn(&RollingSeries) -> &Vec<(Timestamp, f64)>;
+
+#[derive(Serialize)]
 struct Metrics {
-    market_return: f64,
+    // Relative to the benchmark, None if it has too few bars in common with the portfolio
+    market_return: Option<f64>,
     portfolio_return: f64,
     annualized_portfolio_return: f64,
     volatility: f64,
     sharpe_ratio: f64,
     max_drawdown: f64,
-    alpha: f64,
-    beta: f64,
+    alpha: Option<f64>,
+    beta: Option<f64>,
     sortino_ratio: f64,
-    information_ratio: f64,
-    tracking_error: f64,
+    information_ratio: Option<f64>,
+    tracking_error: Option<f64>,
     longest_drawdown: usize,
+    value_at_risk: Vec<ValueAtRisk>,
+    calmar_ratio: f64,
+    omega_ratio: f64,
+    ulcer_index: f64,
+    tail_ratio: f64,
+    skewness: f64,
+    // Excess kurtosis, 0 for normally distributed returns
+    kurtosis: f64,
+}
+
+/*
+One-period Value-at-Risk and Expected Shortfall (CVaR) at a confidence level,
+as positive fractions of the portfolio value. The historical estimates use
+the empirical distribution of the returns, the parametric ones a normal
+distribution with the mean and volatility of the returns.
+*/
+#[derive(Serialize)]
+struct ValueAtRisk {
+    confidence: f64,
+    historical_var: f64,
+    historical_cvar: f64,
+    parametric_var: f64,
+    parametric_cvar: f64,
 }
 
 impl ModuleReceive for DataAnalyzer {
@@ -40,6 +298,37 @@ impl ModuleReceive for DataAnalyzer {
     }
 }
 
+impl ModuleHandle for DataAnalyzer {
+    fn handle_event(&mut self, event: Event) {
+        match event {
+            Event::MarketData(market_data_event) => {
+                self.process_marketevent(market_data_event);
+            }
+            Event::MarketDataBatch(market_data_batch_event) => {
+                for market_data_event in market_data_batch_event.bars {
+                    self.process_marketevent(market_data_event);
+                }
+            }
+            Event::PortfolioInfo(portfolio_info_event) => {
+                self.process_portfolioinfo(portfolio_info_event);
+            }
+            Event::ShutDown(shut_down_event) => {
+                self.shut_down(shut_down_event);
+            }
+            Event::Barrier(_) => {}
+            _ => {
+                println!("DataAnalyzer: Unsupported event: {:?}", event);
+            }
+        }
+    }
+}
+
+impl Default for DataAnalyzer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl DataAnalyzer {
     pub fn new() -> Self {
         let (subscribe_sender, subscribe_receiver) = unbounded();
@@ -55,27 +344,118 @@ impl DataAnalyzer {
             asset_history,
             cash_history,
             local_portfolio,
+            benchmark_symbol: None,
+            benchmark_prices: HashMap::new(),
+            metrics_config: MetricsConfig::default(),
+            robustness_config: RobustnessConfig::default(),
+            latest_timestamp: None,
+            ledger: Ledger::default(),
+            open_trades: HashMap::new(),
+            closed_trades: Vec::new(),
+            bar_count: 0,
+            position_history: Vec::new(),
+            output_dir: "./output".to_string(),
         }
     }
 
-    pub fn run(&mut self) {
+    pub fn set_metrics_config(&mut self, metrics_config: MetricsConfig) -> Result<(), Box<dyn Error>> {
+        // Use a risk-free rate, annualization factor and benchmark for the metrics, a csv benchmark is loaded here
+        self.benchmark_symbol = None;
+        self.benchmark_prices.clear();
+        match &metrics_config.benchmark {
+            Benchmark::FirstSymbol => {}
+            Benchmark::Symbol(symbol) => self.benchmark_symbol = Some(symbol.clone()),
+            Benchmark::Csv(csv_path, timestamp_format) => {
+                self.benchmark_prices = load_benchmark(csv_path, timestamp_format)?;
+                let name = Path::new(csv_path)
+                    .file_stem()
+                    .map_or(csv_path.clone(), |stem| stem.to_string_lossy().to_string());
+                self.benchmark_symbol = Some(name);
+            }
+        }
+        self.metrics_config = metrics_config;
+        Ok(())
+    }
+
+    fn periods_per_year(&self, history: &[(Timestamp, f64)]) -> f64 {
+        self.metrics_config
+            .periods_per_year
+            .unwrap_or_else(|| infer_periods_per_year(history))
+    }
+
+    fn rolling_series(
+        &self,
+        asset_history: &[(Timestamp, f64)],
+        market_data: &[(Timestamp, f64)],
+    ) -> Vec<RollingSeries> {
+        let periods_per_year = self.periods_per_year(asset_history);
+        self.metrics_config
+            .rolling_windows
+            .iter()
+            .map(|&window| {
+                rolling_series(
+                    asset_history,
+                    market_data,
+                    window,
+                    periods_per_year,
+                    self.metrics_config.risk_free_rate,
+                )
+            })
+            .collect()
+    }
+
+    pub fn set_robustness_config(&mut self, robustness_config: RobustnessConfig) {
+        // Number of simulations, block size, confidence and ruin threshold of the resampling
+        self.robustness_config = robustness_config;
+    }
+
+    fn calculate_robustness(&self, asset_history: &[(Timestamp, f64)]) -> Vec<RobustnessReport> {
+        // Block bootstrap of the bar returns and shuffling of the closed trades
+        let config = &self.robustness_config;
+        let risk_free_rate = self.metrics_config.risk_free_rate;
+        let periods_per_year = self.periods_per_year(asset_history);
+        let returns: Vec<f64> = asset_history
+            .windows(2)
+            .map(|window| (window[1].1 - window[0].1) / window[0].1)
+            .collect();
+        // Return of a trade on the asset of the bar before it was opened
+        let mut first_bar: HashMap<Timestamp, usize> = HashMap::new();
+        for (index, &(timestamp, _)) in asset_history.iter().enumerate() {
+            first_bar.entry(timestamp).or_insert(index);
+        }
+        let trade_returns: Vec<f64> = self
+            .closed_trades
+            .iter()
+            .filter_map(|trade| {
+                let index = *first_bar.get(&trade.open_timestamp)?;
+                Some(trade.pnl / asset_history[index.saturating_sub(1)].1)
+            })
+            .collect();
+        // Trades happen at the pace of the run
+        let trades_per_year =
+            trade_returns.len() as f64 * periods_per_year / returns.len().max(1) as f64;
+
+        [
+            block_bootstrap(&returns, config, periods_per_year, risk_free_rate),
+            shuffle_trades(&trade_returns, config, trades_per_year, risk_free_rate),
+        ]
+        .into_iter()
+        .flatten()
+        .collect()
+    }
+
+    pub fn set_output_dir(&mut self, output_dir: String) {
+        // Where shut_down writes the JSON summary and the CSV files
+        self.output_dir = output_dir;
+    }
 
+    pub fn run(&mut self) {
         loop {
             let event = self.subscribe_receiver.recv().unwrap();
-            match event {
-                Event::MarketData(market_data_event) => {
-                    self.process_marketevent(market_data_event);
-                }
-                Event::PortfolioInfo(portfolio_info_event) => {
-                    self.process_portfolioinfo(portfolio_info_event);
-                }
-                Event::ShutDown(shut_down_event) => {
-                    self.shut_down(shut_down_event);
-                    break;
-                }
-                _ => {
-                    println!("DataAnalyzer: Unsupported event: {:?}", event);
-                }
+            let shut_down = matches!(event, Event::ShutDown(_));
+            self.handle_event(event);
+            if shut_down {
+                break;
             }
         }
     }
@@ -94,6 +474,17 @@ impl DataAnalyzer {
             data.clone()
         };
 
+        if let Err(err) = self.export(
+            &market_data_snapshot,
+            &asset_history_snapshot,
+            &cash_history_snapshot,
+        ) {
+            eprintln!("Error exporting the report: {}", err);
+        }
+        if let Err(err) = self.write_tearsheet(&market_data_snapshot, &asset_history_snapshot) {
+            eprintln!("Error writing the tearsheet: {}", err);
+        }
+
         // Plot before drop
         if let Err(err) = self.plot(
             &market_data_snapshot,
@@ -109,19 +500,442 @@ impl DataAnalyzer {
     }
 
     fn process_marketevent(&mut self, market_data_event: MarketDataEvent) {
+        if self.latest_timestamp.as_ref() != Some(&market_data_event.timestamp) {
+            self.bar_count += 1;
+        }
+        self.latest_timestamp = Some(market_data_event.timestamp);
         let mut market_data_history = self.market_data_history.lock().unwrap();
-        market_data_history.push((market_data_event.timestamp.clone(), market_data_event.close));
+        if let Benchmark::Csv(..) = self.metrics_config.benchmark {
+            // Record the benchmark once per timestamp, if the csv has it
+            if let Some(&close) = self.benchmark_prices.get(&market_data_event.timestamp) {
+                if market_data_history.last().map(|(timestamp, _)| timestamp) != Some(&market_data_event.timestamp) {
+                    market_data_history.push((market_data_event.timestamp, close));
+                }
+            }
+            return;
+        }
+        let benchmark_symbol = self
+            .benchmark_symbol
+            .get_or_insert_with(|| market_data_event.symbol.clone());
+        if *benchmark_symbol != market_data_event.symbol {
+            return;
+        }
+
+        market_data_history.push((market_data_event.timestamp, market_data_event.close));
         debug!("Updated market data history: {:?}", market_data_event);
     }
 
+    fn process_fill(&mut self, ledger_entry: LedgerEntry, previous_quantity: i32, quantity: i32) {
+        // Follow the round trips through a fill, which took its position from previous_quantity to quantity
+        let LedgerEntry { fill, realized_pnl } = ledger_entry;
+        debug!("Recorded fill: {:?}, realized P&L: {}", fill, realized_pnl);
+
+        // Split the fee between the closing and the opening part of a flip
+        let closes = previous_quantity != 0 && previous_quantity.signum() != quantity.signum();
+        let closing_fee = if closes {
+            fill.fee * previous_quantity.abs() as f64 / fill.quantity as f64
+        } else {
+            0.0
+        };
+
+        if let Some(trade) = self.open_trades.get_mut(&fill.symbol) {
+            trade.pnl += realized_pnl - if closes { closing_fee } else { fill.fee };
+        }
+        if closes {
+            if let Some(mut trade) = self.open_trades.remove(&fill.symbol) {
+                trade.close_timestamp = fill.timestamp;
+                trade.close_bar = self.bar_count;
+                self.closed_trades.push(trade);
+            }
+        }
+        if quantity != 0 && (previous_quantity == 0 || closes) {
+            self.open_trades.insert(
+                fill.symbol.clone(),
+                Trade {
+                    symbol: fill.symbol.clone(),
+                    side: if quantity > 0 { "Long" } else { "Short" },
+                    open_timestamp: fill.timestamp,
+                    close_timestamp: fill.timestamp,
+                    open_bar: self.bar_count,
+                    close_bar: self.bar_count,
+                    pnl: -(fill.fee - closing_fee),
+                },
+            );
+        }
+
+        self.ledger.record(fill, realized_pnl);
+    }
+
+    fn export(
+        &self,
+        market_data: &[(Timestamp, f64)],
+        asset_history: &[(Timestamp, f64)],
+        cash_history: &[(Timestamp, f64)],
+    ) -> Result<(), Box<dyn Error>> {
+        // Write the JSON summary and the CSV files of the run to the output directory
+        let output_dir = Path::new(&self.output_dir);
+        fs::create_dir_all(output_dir)?;
+
+        let mut symbols: Vec<String> = self.local_portfolio.positions.keys().cloned().collect();
+        symbols.sort();
+        let report = Report {
+            metadata: RunMetadata {
+                version: env!("CARGO_PKG_VERSION"),
+                generated_at: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+                benchmark_symbol: self.benchmark_symbol.clone(),
+                symbols,
+                start: asset_history.first().map(|&(timestamp, _)| timestamp),
+                end: asset_history.last().map(|&(timestamp, _)| timestamp),
+                bars: self.bar_count,
+                initial_asset: asset_history.first().map(|&(_, value)| value),
+                final_asset: asset_history.last().map(|&(_, value)| value),
+            },
+            metrics: self.calculate_metrics().ok(),
+            trade_stats: self.calculate_trade_stats(),
+            robustness: self.calculate_robustness(asset_history),
+        };
+        fs::write(
+            output_dir.join("report.json"),
+            serde_json::to_string_pretty(&report)?,
+        )?;
+
+        write_curve(&output_dir.join("equity_curve.csv"), "asset", asset_history)?;
+        write_curve(&output_dir.join("cash_curve.csv"), "cash", cash_history)?;
+
+        let mut writer = csv::Writer::from_path(output_dir.join("positions.csv"))?;
+        writer.write_record([
+            "timestamp",
+            "symbol",
+            "quantity",
+            "average_entry_price",
+            "market_value",
+            "unrealized_pnl",
+            "realized_pnl",
+        ])?;
+        for (timestamp, symbol, position) in &self.position_history {
+            writer.write_record([
+                timestamp.to_rfc3339(),
+                symbol.clone(),
+                position.quantity.to_string(),
+                position.average_entry_price.to_string(),
+                position.market_value.to_string(),
+                position.unrealized_pnl.to_string(),
+                position.realized_pnl.to_string(),
+            ])?;
+        }
+        writer.flush()?;
+
+        self.ledger.write_csv(&output_dir.join("ledger.csv"))?;
+
+        // Rolling series in long format, one row per timestamp, window and statistic
+        let mut writer = csv::Writer::from_path(output_dir.join("rolling.csv"))?;
+        writer.write_record(["timestamp", "window", "statistic", "value"])?;
+        for series in self.rolling_series(asset_history, market_data) {
+            for (statistic, points) in [
+                ("sharpe", &series.sharpe),
+                ("volatility", &series.volatility),
+                ("beta", &series.beta),
+                ("drawdown", &series.drawdown),
+            ] {
+                for (timestamp, value) in points {
+                    writer.write_record([
+                        timestamp.to_rfc3339(),
+                        series.window.to_string(),
+                        statistic.to_string(),
+                        value.to_string(),
+                    ])?;
+                }
+            }
+        }
+        writer.flush()?;
+        info!("Report exported: {}", self.output_dir);
+        Ok(())
+    }
+
+    fn write_tearsheet(
+        &self,
+        market_data: &[(Timestamp, f64)],
+        asset_history: &[(Timestamp, f64)],
+    ) -> Result<(), Box<dyn Error>> {
+        // Write the HTML tearsheet of the run to the output directory
+        let output_dir = Path::new(&self.output_dir);
+        fs::create_dir_all(output_dir)?;
+
+        // Charts are labelled with the timestamps as text
+        let labelled = |history: &[(Timestamp, f64)]| -> Vec<(String, f64)> {
+            history
+                .iter()
+                .map(|(timestamp, value)| (timestamp.to_string(), *value))
+                .collect()
+        };
+        // Points are drawn by index, so both series take the timestamps they have in common
+        let (equity, benchmark) = equity_vs_benchmark(asset_history, market_data);
+        let equity = labelled(&equity);
+        let benchmark = labelled(&benchmark);
+        let drawdown = labelled(&drawdown_series(asset_history));
+        let rolling = self.rolling_series(asset_history, market_data);
+        let rolling_sharpe_points: Vec<Vec<(String, f64)>> =
+            rolling.iter().map(|series| labelled(&series.sharpe)).collect();
+
+        let mut tearsheet = Tearsheet::new(&format!(
+            "Backtest of {}",
+            self.benchmark_symbol.as_deref().unwrap_or("no data")
+        ));
+        tearsheet.add_line_chart(
+            "Equity vs Benchmark",
+            &[
+                Series {
+                    name: "Strategy",
+                    color: "#d62728",
+                    points: &equity,
+                },
+                Series {
+                    name: self.benchmark_symbol.as_deref().unwrap_or("Benchmark"),
+                    color: "#1f77b4",
+                    points: &benchmark,
+                },
+            ],
+            false,
+        );
+        tearsheet.add_line_chart(
+            "Underwater Drawdown",
+            &[Series {
+                name: "Drawdown",
+                color: "#d62728",
+                points: &drawdown,
+            }],
+            true,
+        );
+        tearsheet.add_heatmap("Monthly Returns", &monthly_returns(asset_history));
+        let names: Vec<String> = rolling
+            .iter()
+            .map(|series| format!("{} bars", series.window))
+            .collect();
+        let rolling_sharpe: Vec<Series> = rolling_sharpe_points
+            .iter()
+            .zip(&names)
+            .zip(PALETTE.iter().cycle())
+            .map(|((points, name), (_, color))| Series {
+                name,
+                color,
+                points,
+            })
+            .collect();
+        tearsheet.add_line_chart("Rolling Sharpe Ratio", &rolling_sharpe, false);
+
+        let mut metric_rows: Vec<Vec<String>> = match self.calculate_metrics() {
+            Ok(metrics) => metrics
+                .rows()
+                .into_iter()
+                .map(|(name, value)| vec![name.to_string(), value])
+                .collect(),
+            Err(_) => Vec::new(),
+        };
+        metric_rows.extend(
+            self.calculate_trade_stats()
+                .rows()
+                .into_iter()
+                .map(|(name, value)| vec![name.to_string(), value]),
+        );
+        tearsheet.add_table("Metrics", &["Metric", "Value"], &metric_rows);
+
+        // Distribution of the simulated equity curves, as percentile bands and a few sample paths
+        let robustness = self.calculate_robustness(asset_history);
+        for report in &robustness {
+            let label = |step: usize| match report.method {
+                "block bootstrap" => asset_history[step].0.to_string(),
+                _ => format!("Trade {}", step),
+            };
+            let to_points = |values: &[f64]| -> Vec<(String, f64)> {
+                values
+                    .iter()
+                    .enumerate()
+                    .map(|(step, &value)| (label(step), value))
+                    .collect()
+            };
+            let bands: Vec<Vec<(String, f64)>> = report.bands.iter().map(|band| to_points(band)).collect();
+            let paths: Vec<Vec<(String, f64)>> = report.sample_paths.iter().map(|path| to_points(path)).collect();
+            let tail = (1.0 - report.confidence) / 2.0 * 100.0;
+            let names = [
+                format!("{:.1}th percentile", tail),
+                "Median".to_string(),
+                format!("{:.1}th percentile", 100.0 - tail),
+            ];
+            let mut series: Vec<Series> = bands
+                .iter()
+                .zip(&names)
+                .zip(["#1f77b4", "#d62728", "#1f77b4"])
+                .map(|((points, name), color)| Series { name, color, points })
+                .collect();
+            series.extend(paths.iter().map(|points| Series {
+                name: "Sample path",
+                color: "#c7c7c7",
+                points,
+            }));
+            tearsheet.add_line_chart(&format!("Simulated Equity ({})", report.method), &series, false);
+        }
+        let interval_rows: Vec<Vec<String>> = robustness
+            .iter()
+            .flat_map(|report| {
+                [
+                    ("Total Return", &report.total_return),
+                    ("Sharpe Ratio", &report.sharpe_ratio),
+                    ("Max Drawdown", &report.max_drawdown),
+                ]
+                .into_iter()
+                .map(|(name, interval)| {
+                    vec![
+                        report.method.to_string(),
+                        name.to_string(),
+                        format!("{:.4}", interval.lower),
+                        format!("{:.4}", interval.median),
+                        format!("{:.4}", interval.upper),
+                    ]
+                })
+                .chain(std::iter::once(vec![
+                    report.method.to_string(),
+                    "Probability of Ruin".to_string(),
+                    String::new(),
+                    format!("{:.4}", report.probability_of_ruin),
+                    String::new(),
+                ]))
+                .collect::<Vec<_>>()
+            })
+            .collect();
+        tearsheet.add_table(
+            &format!("Confidence Intervals ({:.0}%)", self.robustness_config.confidence * 100.0),
+            &["Method", "Statistic", "Lower", "Median", "Upper"],
+            &interval_rows,
+        );
+
+        let trade_rows: Vec<Vec<String>> = self
+            .closed_trades
+            .iter()
+            .map(|trade| {
+                vec![
+                    trade.symbol.clone(),
+                    trade.side.to_string(),
+                    trade.open_timestamp.to_string(),
+                    trade.close_timestamp.to_string(),
+                    (trade.close_bar - trade.open_bar).to_string(),
+                    format!("{:.2}", trade.pnl),
+                ]
+            })
+            .collect();
+        tearsheet.add_table(
+            "Trades",
+            &["Symbol", "Side", "Opened", "Closed", "Bars", "P&L"],
+            &trade_rows,
+        );
+
+        let path = output_dir.join("tearsheet.html");
+        tearsheet.write(&path)?;
+        info!("Tearsheet saved: {}", path.display());
+        Ok(())
+    }
+
+    fn calculate_trade_stats(&self) -> TradeStats {
+        let trades = &self.closed_trades;
+        let wins: Vec<f64> = trades.iter().map(|trade| trade.pnl).filter(|&pnl| pnl > 0.0).collect();
+        let losses: Vec<f64> = trades.iter().map(|trade| trade.pnl).filter(|&pnl| pnl < 0.0).collect();
+        let mean = |values: &[f64]| {
+            if values.is_empty() {
+                0.0
+            } else {
+                values.iter().sum::<f64>() / values.len() as f64
+            }
+        };
+
+        let gross_profit: f64 = wins.iter().sum();
+        let gross_loss: f64 = -losses.iter().sum::<f64>();
+        let profit_factor = if gross_loss > 0.0 {
+            gross_profit / gross_loss
+        } else {
+            f64::INFINITY // Avoid division by zero
+        };
+
+        // Longest runs of consecutive winners and losers, a breakeven trade ends either
+        let mut longest_winning_streak = 0;
+        let mut longest_losing_streak = 0;
+        let mut streak: i64 = 0;
+        for trade in trades {
+            if trade.pnl > 0.0 {
+                streak = streak.max(0) + 1;
+                longest_winning_streak = longest_winning_streak.max(streak as usize);
+            } else if trade.pnl < 0.0 {
+                streak = streak.min(0) - 1;
+                longest_losing_streak = longest_losing_streak.max((-streak) as usize);
+            } else {
+                streak = 0;
+            }
+        }
+
+        let number_of_trades = trades.len();
+        let decided_trades = wins.len() + losses.len();
+        let pnls: Vec<f64> = trades.iter().map(|trade| trade.pnl).collect();
+        let holding_periods: Vec<f64> = trades
+            .iter()
+            .map(|trade| (trade.close_bar - trade.open_bar) as f64)
+            .collect();
+        TradeStats {
+            number_of_trades,
+            breakeven_trades: number_of_trades - decided_trades,
+            win_rate: if decided_trades > 0 {
+                wins.len() as f64 / decided_trades as f64
+            } else {
+                0.0
+            },
+            average_win: mean(&wins),
+            average_loss: mean(&losses),
+            profit_factor,
+            expectancy: mean(&pnls),
+            largest_winner: wins.iter().copied().fold(0.0, f64::max),
+            largest_loser: losses.iter().copied().fold(0.0, f64::min),
+            average_holding_period: mean(&holding_periods),
+            longest_winning_streak,
+            longest_losing_streak,
+        }
+    }
+
     fn process_portfolioinfo(&mut self, portfolio_info_event: PortfolioInfoEvent) {
-        self.local_portfolio = portfolio_info_event.portfolio.clone();
+        // Quantities before the new fills are those of the previous portfolio update
+        let mut quantities: HashMap<String, i32> = HashMap::new();
+        for ledger_entry in portfolio_info_event.ledger_entries {
+            let fill = &ledger_entry.fill;
+            let quantity = quantities.entry(fill.symbol.clone()).or_insert_with(|| {
+                self.local_portfolio
+                    .positions
+                    .get(&fill.symbol)
+                    .map_or(0, |position| position.quantity)
+            });
+            let previous_quantity = *quantity;
+            *quantity += match fill.direction {
+                OrderDirection::Buy => fill.quantity,
+                OrderDirection::Sell => -fill.quantity,
+            };
+            let quantity = *quantity;
+            self.process_fill(ledger_entry, previous_quantity, quantity);
+        }
+
+        self.local_portfolio = portfolio_info_event.portfolio;
         let mut asset_history = self.asset_history.lock().unwrap();
         let mut cash_history = self.cash_history.lock().unwrap();
 
-        if let Some((latest_timestamp, _)) = self.market_data_history.lock().unwrap().last() {
-            asset_history.push((latest_timestamp.clone(), self.local_portfolio.asset));
-            cash_history.push((latest_timestamp.clone(), self.local_portfolio.cash));
+        if let Some(latest_timestamp) = &self.latest_timestamp {
+            asset_history.push((*latest_timestamp, self.local_portfolio.asset));
+            cash_history.push((*latest_timestamp, self.local_portfolio.cash));
+
+            let mut open_positions: Vec<(&String, &Position)> = self
+                .local_portfolio
+                .positions
+                .iter()
+                .filter(|(_, position)| position.quantity != 0)
+                .collect();
+            open_positions.sort_by(|a, b| a.0.cmp(b.0));
+            for (symbol, position) in open_positions {
+                self.position_history
+                    .push((*latest_timestamp, symbol.clone(), position.clone()));
+            }
         }
         debug!("Updated asset history: {:?}", self.local_portfolio);
     }
@@ -129,7 +943,7 @@ impl DataAnalyzer {
     fn calculate_metrics(&self) -> Result<Metrics, Box<dyn Error>> {
         let market_data = self.market_data_history.lock().unwrap();
         let asset_history = self.asset_history.lock().unwrap();
-        if market_data.is_empty() || asset_history.is_empty() {
+        if asset_history.is_empty() {
             return Err("Insufficient data for metrics calculation".into());
         }
 
@@ -139,21 +953,21 @@ impl DataAnalyzer {
             .map(|window| (window[1].1 - window[0].1) / window[0].1)
             .collect();
 
-        let benchmark_returns: Vec<f64> = market_data
-            .windows(2)
-            .map(|window| (window[1].1 - window[0].1) / window[0].1)
-            .collect();
-
-        // Calculate market return
-        let market_return = benchmark_returns.iter().fold(1.0, |acc, r| acc * (1.0 + r)) - 1.0;
+        // Calculate market return, over the bars the portfolio was valued on
+        let aligned = aligned_values(&asset_history, &market_data);
+        let market_return = match (aligned.first(), aligned.last()) {
+            (Some(first), Some(last)) if aligned.len() > 1 => Some(last.2 / first.2 - 1.0),
+            _ => None,
+        };
 
         // Total Return
         let portfolio_return = (asset_history.last().unwrap().1 / asset_history[0].1) - 1.0;
 
         // Annualized Return
         let n = returns.len() as f64;
-        let annualized_market_return = (1.0 + market_return).powf(252.0 / n) - 1.0;
-        let annualized_portfolio_return = (1.0 + portfolio_return).powf(252.0 / n) - 1.0;
+        let periods_per_year = self.periods_per_year(&asset_history);
+        let risk_free_rate = self.metrics_config.risk_free_rate;
+        let annualized_portfolio_return = (1.0 + portfolio_return).powf(periods_per_year / n) - 1.0;
 
         // Volatility
         let mean_return = returns.iter().sum::<f64>() / n;
@@ -165,9 +979,9 @@ impl DataAnalyzer {
         let volatility = variance.sqrt();
 
         // Sharpe Ratio
-        let annualized_mean_return = mean_return * 252.0;
-        let annualized_excess_return = annualized_mean_return - 0.05;
-        let annualized_volatility = volatility * (252.0_f64).sqrt();
+        let annualized_mean_return = mean_return * periods_per_year;
+        let annualized_excess_return = annualized_mean_return - risk_free_rate;
+        let annualized_volatility = volatility * periods_per_year.sqrt();
         let sharpe_ratio = annualized_excess_return / annualized_volatility;
 
         // Max Drawdown
@@ -202,40 +1016,18 @@ impl DataAnalyzer {
             0.0 // Avoid division by zero if no negative returns exist
         };
         let sortino_ratio = if downside_deviation > 0.0 {
-            annualized_excess_return / (downside_deviation * (252.0_f64).sqrt())
+            annualized_excess_return / (downside_deviation * periods_per_year.sqrt())
         } else {
             f64::INFINITY // Avoid division by zero
         };
 
-        // Alpha and Beta
-        let covariance: f64 = returns
-            .iter()
-            .zip(&benchmark_returns)
-            .map(|(&r_p, &r_b)| r_p * r_b)
-            .sum::<f64>();
-        let variance: f64 = benchmark_returns.iter().map(|&r| r.powi(2)).sum::<f64>();
-        let beta = covariance / variance;
-        let risk_free_rate = 0.05;
-        let alpha = annualized_portfolio_return
-            - risk_free_rate
-            - beta * (annualized_market_return - risk_free_rate);
-
-        // Tracking Ratio
-        let excess_returns: Vec<f64> = returns
-            .iter()
-            .zip(&benchmark_returns)
-            .map(|(&r_p, &r_b)| r_p - r_b)
-            .collect();
-        let mean_excess_return = excess_returns.iter().sum::<f64>() / excess_returns.len() as f64;
-        let excess_return_variance = excess_returns
-            .iter()
-            .map(|&r| (r - mean_excess_return).powi(2))
-            .sum::<f64>()
-            / (excess_returns.len() as f64 - 1.0);
-        let tracking_error = excess_return_variance.sqrt();
-
-        // Information Ratio
-        let information_ratio = mean_excess_return / tracking_error;
+        // Alpha, Beta, Tracking Error and Information Ratio on returns aligned by timestamp,
+        // None without enough overlap between portfolio and benchmark
+        let relative = relative_metrics(&asset_history, &market_data, periods_per_year, risk_free_rate);
+        let alpha = relative.as_ref().map(|relative| relative.alpha);
+        let beta = relative.as_ref().map(|relative| relative.beta);
+        let tracking_error = relative.as_ref().map(|relative| relative.tracking_error);
+        let information_ratio = relative.as_ref().map(|relative| relative.information_ratio);
 
         // Longest Drawdown Period
         let mut peak: f64 = asset_history
@@ -263,6 +1055,37 @@ impl DataAnalyzer {
             }
         }
 
+        // Tail risk at every confidence level
+        let value_at_risk = self
+            .metrics_config
+            .var_confidence_levels
+            .iter()
+            .map(|&confidence| value_at_risk(&returns, confidence))
+            .collect();
+
+        // Calmar, Omega and Ulcer index
+        let calmar_ratio = annualized_portfolio_return / max_drawdown.abs();
+        let threshold = risk_free_rate / periods_per_year;
+        let gains: f64 = returns.iter().map(|&r| (r - threshold).max(0.0)).sum();
+        let losses: f64 = returns.iter().map(|&r| (threshold - r).max(0.0)).sum();
+        let omega_ratio = gains / losses;
+        let ulcer_index = (drawdown_series(&asset_history)
+            .iter()
+            .map(|(_, drawdown)| drawdown.powi(2))
+            .sum::<f64>()
+            / asset_history.len() as f64)
+            .sqrt();
+
+        // Tail ratio and the shape of the return distribution
+        let mut sorted_returns = returns.clone();
+        sorted_returns.sort_by(f64::total_cmp);
+        let tail_ratio = (percentile(&sorted_returns, 0.95) / percentile(&sorted_returns, 0.05)).abs();
+        let central_moment = |power: i32| {
+            returns.iter().map(|&r| (r - mean_return).powi(power)).sum::<f64>() / n
+        };
+        let skewness = central_moment(3) / central_moment(2).powf(1.5);
+        let kurtosis = central_moment(4) / central_moment(2).powi(2) - 3.0;
+
         Ok(Metrics {
             market_return,
             portfolio_return,
@@ -276,14 +1099,21 @@ impl DataAnalyzer {
             information_ratio,
             tracking_error,
             longest_drawdown,
+            value_at_risk,
+            calmar_ratio,
+            omega_ratio,
+            ulcer_index,
+            tail_ratio,
+            skewness,
+            kurtosis,
         })
     }
 
     fn plot(
         &self,
-        market_data: &[(String, f64)],
-        asset_history: &[(String, f64)],
-        cash_history: &[(String, f64)],
+        market_data: &[(Timestamp, f64)],
+        asset_history: &[(Timestamp, f64)],
+        cash_history: &[(Timestamp, f64)],
         last_lengths: &mut (usize, usize), // Track the lengths of the histories
         output_path: &str,
     ) -> Result<(), Box<dyn Error>> {
@@ -311,31 +1141,28 @@ impl DataAnalyzer {
             }
         };
 
-        let first_market_value = market_data.get(0).map_or(1.0, |(_, value)| *value);
-        let first_asset_value = asset_history.get(0).map_or(1.0, |(_, value)| *value);
+        let first_market_value = market_data.first().map_or(1.0, |(_, value)| *value);
+        let first_asset_value = asset_history.first().map_or(1.0, |(_, value)| *value);
 
-        let standardized_market_data: Vec<(String, f64)> = market_data
+        let standardized_market_data: Vec<(Timestamp, f64)> = market_data
             .iter()
-            .map(|(timestamp, value)| (timestamp.clone(), value / first_market_value))
+            .map(|(timestamp, value)| (*timestamp, value / first_market_value))
             .collect();
 
-        let standardized_asset_history: Vec<(String, f64)> = asset_history
+        let standardized_asset_history: Vec<(Timestamp, f64)> = asset_history
             .iter()
-            .map(|(timestamp, value)| (timestamp.clone(), value / first_asset_value))
+            .map(|(timestamp, value)| (*timestamp, value / first_asset_value))
             .collect();
 
         // Calculate (asset - cash), which is the position value, and standardize
-        let standardized_difference: Vec<(String, f64)> = asset_history
+        let cash_by_timestamp: HashMap<Timestamp, f64> = cash_history.iter().copied().collect();
+        let standardized_difference: Vec<(Timestamp, f64)> = asset_history
             .iter()
             .map(|(timestamp, asset_value)| {
-                let cash_value = cash_history
-                    .iter()
-                    .find(|(cash_timestamp, _)| cash_timestamp == timestamp)
-                    .map(|(_, value)| *value)
-                    .unwrap_or(0.0);
+                let cash_value = cash_by_timestamp.get(timestamp).copied().unwrap_or(0.0);
 
                 let difference = asset_value - cash_value;
-                (timestamp.clone(), difference / first_asset_value)
+                (*timestamp, difference / first_asset_value)
             })
             .collect();
 
@@ -349,8 +1176,12 @@ impl DataAnalyzer {
 
         let res_x = 3840;
         let res_y = 2160;
-        let root_area = BitMapBackend::new(output_path, (res_x, res_y)).into_drawing_area();
-        root_area.fill(&WHITE)?;
+        // The rolling series are drawn in panels below the main chart
+        let rolling = self.rolling_series(asset_history, market_data);
+        let panel_y = res_y / 4;
+        let root = BitMapBackend::new(output_path, (res_x, res_y + 4 * panel_y)).into_drawing_area();
+        root.fill(&WHITE)?;
+        let (root_area, panel_area) = root.split_vertically(res_y);
 
         let mut chart = ChartBuilder::on(&root_area)
             .caption("Market Data and Asset History", ("sans-serif", res_x / 52))
@@ -371,8 +1202,7 @@ impl DataAnalyzer {
             .x_label_formatter(&|x| {
                 if let Some(index) = x.to_usize() {
                     if index < standardized_market_data.len() {
-                        let label = &standardized_market_data[index].0;
-                        return label.chars().take(10).collect();
+                        return standardized_market_data[index].0.date_naive().to_string();
                     }
                 }
                 "".to_string()
@@ -386,10 +1216,10 @@ impl DataAnalyzer {
                     .iter()
                     .enumerate()
                     .map(|(i, &(_, close))| (i, close)),
-                &BLUE,
+                BLUE,
             ))?
             .label(" Market Data")
-            .legend(|(x, y)| PathElement::new([(x, y), (x + 30, y)], &BLUE));
+            .legend(|(x, y)| PathElement::new([(x, y), (x + 30, y)], BLUE));
 
         // Plot the asset history in red
         chart
@@ -398,10 +1228,10 @@ impl DataAnalyzer {
                     .iter()
                     .enumerate()
                     .map(|(i, &(_, asset))| (i, asset)),
-                &RED,
+                RED,
             ))?
             .label(" Total Asset Value")
-            .legend(|(x, y)| PathElement::new([(x, y), (x + 30, y)], &RED));
+            .legend(|(x, y)| PathElement::new([(x, y), (x + 30, y)], RED));
 
         // Plot the asset-cash difference as a color block (area chart)
         chart
@@ -411,37 +1241,34 @@ impl DataAnalyzer {
                     .enumerate()
                     .map(|(i, &(_, diff))| (i, diff)),
                 0.0,
-                &GREEN.mix(0.4), // Semi-transparent green for the color block
+                GREEN.mix(0.4), // Semi-transparent green for the color block
             ))?
             .label(" Position Value")
-            .legend(|(x, y)| Rectangle::new([(x, y - 6), (x + 30, y + 6)], &GREEN));
-
-        let metrics_text = vec![
-            format!("Market Return: {:.2}%", metrics.market_return * 100.0),
-            format!("Portfolio Return: {:.2}%", metrics.portfolio_return * 100.0),
-            format!(
-                "Annualized Portfolio Return: {:.2}%",
-                metrics.annualized_portfolio_return * 100.0
-            ),
-            format!("Volatility: {:.4}", metrics.volatility),
-            format!("Sharpe Ratio: {:.2}", metrics.sharpe_ratio),
-            format!("Max Drawdown: {:.2}%", metrics.max_drawdown * 100.0),
-            format!("Alpha: {:.4}", metrics.alpha),
-            format!("Beta: {:.4}", metrics.beta),
-            format!("Sortino Ratio: {:.4}", metrics.sortino_ratio),
-            format!("Information Ratio: {:.4}", metrics.information_ratio),
-            format!("Tracking Error: {:.4}", metrics.tracking_error),
-            format!("Longest Drawdown Period: {} days", metrics.longest_drawdown),
-        ];
-        let start_x = standardized_market_data.len() / 50; // X-coordinate
-        let mut start_y = y_max + 1.0 - (y_max - y_min) / 30.0; // Initial Y-coordinate
-        for line in metrics_text {
-            chart.draw_series(std::iter::once(Text::new(
-                line,
-                (start_x, start_y),
-                ("sans-serif", res_x / 77).into_font(),
-            )))?;
-            start_y -= (y_max + 1.0 - y_min) / 42.0; // Increment Y-coordinate for the next line
+            .legend(|(x, y)| Rectangle::new([(x, y - 6), (x + 30, y + 6)], GREEN));
+
+        let metrics_text: Vec<String> = metrics
+            .rows()
+            .into_iter()
+            .map(|(name, value)| format!("{}: {}", name, value))
+            .collect();
+        let trade_stats_text: Vec<String> = self
+            .calculate_trade_stats()
+            .rows()
+            .into_iter()
+            .map(|(name, value)| format!("{}: {}", name, value))
+            .collect();
+        // Equity metrics in the first column, trade statistics in the second
+        for (column, lines) in [metrics_text, trade_stats_text].into_iter().enumerate() {
+            let start_x = standardized_market_data.len() / 50 + column * standardized_market_data.len() / 4; // X-coordinate
+            let mut start_y = y_max + 1.0 - (y_max - y_min) / 30.0; // Initial Y-coordinate
+            for line in lines {
+                chart.draw_series(std::iter::once(Text::new(
+                    line,
+                    (start_x, start_y),
+                    ("sans-serif", res_x / 77).into_font(),
+                )))?;
+                start_y -= (y_max + 1.0 - y_min) / 42.0; // Increment Y-coordinate for the next line
+            }
         }
 
         // Draw the legend
@@ -451,12 +1278,853 @@ impl DataAnalyzer {
                 (res_x / 2 - res_x / 14).to_i32().unwrap(),
                 50,
             ))
-            .background_style(&WHITE.mix(0.2))
-            .border_style(&BLACK)
+            .background_style(WHITE.mix(0.2))
+            .border_style(BLACK)
             .label_font(("sans-serif", res_x / 77)) // legend label
             .draw()?;
 
+        let x_range = 0..market_data.len().max(asset_history.len()) + market_data.len() / 25;
+        let bar_index: HashMap<Timestamp, usize> = asset_history
+            .iter()
+            .enumerate()
+            .map(|(index, &(timestamp, _))| (timestamp, index))
+            .collect();
+        let panels: [(&str, RollingSelector); 4] = [
+            ("Rolling Sharpe Ratio", |series| &series.sharpe),
+            ("Rolling Volatility", |series| &series.volatility),
+            ("Rolling Beta", |series| &series.beta),
+            ("Rolling Drawdown", |series| &series.drawdown),
+        ];
+        for (area, (caption, select)) in panel_area.split_evenly((4, 1)).iter().zip(panels) {
+            let values = rolling
+                .iter()
+                .flat_map(|series| select(series).iter().map(|&(_, value)| value))
+                .filter(|value| value.is_finite());
+            let panel_min = values.clone().fold(f64::INFINITY, f64::min);
+            let panel_max = values.fold(f64::NEG_INFINITY, f64::max);
+            let (panel_min, panel_max) = if panel_min < panel_max {
+                (panel_min, panel_max)
+            } else {
+                (panel_min.min(0.0) - 1.0, panel_max.max(0.0) + 1.0) // No data or a flat series
+            };
+
+            let mut panel = ChartBuilder::on(area)
+                .caption(caption, ("sans-serif", res_x / 90))
+                .x_label_area_size(res_y / 40)
+                .y_label_area_size(res_x / 22)
+                .margin_right(res_x / 60)
+                .build_cartesian_2d(x_range.clone(), panel_min..panel_max)?;
+            panel
+                .configure_mesh()
+                .label_style(("sans-serif", res_x / 110))
+                .x_label_formatter(&|x| {
+                    asset_history
+                        .get(*x)
+                        .map_or(String::new(), |(timestamp, _)| timestamp.date_naive().to_string())
+                })
+                .draw()?;
+            for (series, (color, _)) in rolling.iter().zip(PALETTE.iter().cycle()) {
+                panel
+                    .draw_series(LineSeries::new(
+                        select(series)
+                            .iter()
+                            .filter(|(_, value)| value.is_finite())
+                            .filter_map(|(timestamp, value)| {
+                                bar_index.get(timestamp).map(|&index| (index, *value))
+                            }),
+                        color,
+                    ))?
+                    .label(format!(" {} bars", series.window))
+                    .legend(move |(x, y)| PathElement::new([(x, y), (x + 30, y)], color));
+            }
+            panel
+                .configure_series_labels()
+                .position(SeriesLabelPosition::UpperLeft)
+                .background_style(WHITE.mix(0.6))
+                .border_style(BLACK)
+                .label_font(("sans-serif", res_x / 110))
+                .draw()?;
+        }
+
         info!("Plot saved: {}", output_path);
         Ok(())
     }
 }
+
+fn drawdown_series(history: &[(Timestamp, f64)]) -> Vec<(Timestamp, f64)> {
+    // Distance of every value below the running peak, as a fraction of the peak
+    let mut peak = f64::NEG_INFINITY;
+    history
+        .iter()
+        .map(|(timestamp, value)| {
+            peak = peak.max(*value);
+            (*timestamp, value / peak - 1.0)
+        })
+        .collect()
+}
+
+fn rolling_series(
+    history: &[(Timestamp, f64)],
+    benchmark: &[(Timestamp, f64)],
+    window: usize,
+    periods_per_year: f64,
+    risk_free_rate: f64,
+) -> RollingSeries {
+    let mean = |values: &[f64]| values.iter().sum::<f64>() / values.len() as f64;
+    let sample_variance = |values: &[f64]| {
+        let m = mean(values);
+        values.iter().map(|&v| (v - m).powi(2)).sum::<f64>() / (values.len() as f64 - 1.0)
+    };
+    let mut rolling = RollingSeries {
+        window,
+        sharpe: Vec::new(),
+        volatility: Vec::new(),
+        beta: Vec::new(),
+        drawdown: Vec::new(),
+    };
+    if window < 2 {
+        return rolling;
+    }
+
+    // Return i is between values i and i + 1
+    let returns: Vec<f64> = history
+        .windows(2)
+        .map(|pair| (pair[1].1 - pair[0].1) / pair[0].1)
+        .collect();
+    for (start, window_returns) in returns.windows(window).enumerate() {
+        let timestamp = &history[start + window].0;
+        let volatility = sample_variance(window_returns).sqrt() * periods_per_year.sqrt();
+        let sharpe = (mean(window_returns) * periods_per_year - risk_free_rate) / volatility;
+        rolling.sharpe.push((*timestamp, sharpe));
+        rolling.volatility.push((*timestamp, volatility));
+    }
+
+    let aligned = aligned_values(history, benchmark);
+    let aligned_returns: Vec<(f64, f64)> = aligned
+        .windows(2)
+        .map(|pair| (pair[1].1 / pair[0].1 - 1.0, pair[1].2 / pair[0].2 - 1.0))
+        .collect();
+    for (start, window_returns) in aligned_returns.windows(window).enumerate() {
+        let (returns, benchmark_returns): (Vec<f64>, Vec<f64>) = window_returns.iter().copied().unzip();
+        let (mean_return, mean_benchmark_return) = (mean(&returns), mean(&benchmark_returns));
+        let covariance = window_returns
+            .iter()
+            .map(|&(r_p, r_b)| (r_p - mean_return) * (r_b - mean_benchmark_return))
+            .sum::<f64>()
+            / (window as f64 - 1.0);
+        let beta = covariance / sample_variance(&benchmark_returns);
+        rolling.beta.push((aligned[start + window].0, beta));
+    }
+
+    for values in history.windows(window + 1) {
+        let peak = values.iter().map(|&(_, value)| value).fold(f64::NEG_INFINITY, f64::max);
+        let (timestamp, value) = &values[window];
+        rolling.drawdown.push((*timestamp, value / peak - 1.0));
+    }
+    rolling
+}
+
+/*
+Statistics of the portfolio against the benchmark.
+Alpha and beta are the intercept and slope of the regression of the
+portfolio's excess returns on the benchmark's (Jensen's alpha), the
+tracking error is the standard deviation of the active returns, and the
+information ratio is the mean active return over the tracking error.
+Alpha, tracking error and information ratio are annualized.
+*/
+#[derive(Debug)]
+struct RelativeMetrics {
+    alpha: f64,
+    beta: f64,
+    tracking_error: f64,
+    information_ratio: f64,
+}
+
+fn aligned_values(
+    history: &[(Timestamp, f64)],
+    benchmark: &[(Timestamp, f64)],
+) -> Vec<(Timestamp, f64, f64)> {
+    // (timestamp, value, benchmark value) at the timestamps both series have, the last of repeated ones
+    let benchmark_values: HashMap<Timestamp, f64> = benchmark.iter().copied().collect();
+    let mut common: Vec<(Timestamp, f64, f64)> = Vec::new();
+    for &(timestamp, value) in history {
+        if let Some(&benchmark_value) = benchmark_values.get(&timestamp) {
+            match common.last_mut() {
+                Some(last) if last.0 == timestamp => *last = (timestamp, value, benchmark_value),
+                _ => common.push((timestamp, value, benchmark_value)),
+            }
+        }
+    }
+    common
+}
+
+// Values of a series by timestamp
+type Curve = Vec<(Timestamp, f64)>;
+
+fn equity_vs_benchmark(history: &[(Timestamp, f64)], benchmark: &[(Timestamp, f64)]) -> (Curve, Curve) {
+    // Both series at the timestamps they have in common, starting at 1. The history alone if there are none
+    let aligned = aligned_values(history, benchmark);
+    let Some(&(_, first_value, first_benchmark_value)) = aligned.first() else {
+        let first_value = history.first().map_or(1.0, |&(_, value)| value);
+        let normalized = history.iter().map(|&(timestamp, value)| (timestamp, value / first_value)).collect();
+        return (normalized, Vec::new());
+    };
+    aligned
+        .iter()
+        .map(|&(timestamp, value, benchmark_value)| {
+            (
+                (timestamp, value / first_value),
+                (timestamp, benchmark_value / first_benchmark_value),
+            )
+        })
+        .unzip()
+}
+
+fn aligned_returns(history: &[(Timestamp, f64)], benchmark: &[(Timestamp, f64)]) -> (Vec<f64>, Vec<f64>) {
+    /*
+    Returns of both series between the consecutive timestamps they have in common.
+    A timestamp missing from either series is skipped, so the return over the
+    gap is compared with the return of the other series over the same gap.
+    */
+    aligned_values(history, benchmark)
+        .windows(2)
+        .map(|pair| (pair[1].1 / pair[0].1 - 1.0, pair[1].2 / pair[0].2 - 1.0))
+        .unzip()
+}
+
+fn relative_metrics(
+    history: &[(Timestamp, f64)],
+    benchmark: &[(Timestamp, f64)],
+    periods_per_year: f64,
+    risk_free_rate: f64,
+) -> Option<RelativeMetrics> {
+    let (returns, benchmark_returns) = aligned_returns(history, benchmark);
+    if returns.len() < 2 {
+        return None;
+    }
+    let n = returns.len() as f64;
+    let mean = |values: &[f64]| values.iter().sum::<f64>() / values.len() as f64;
+    let mean_return = mean(&returns);
+    let mean_benchmark_return = mean(&benchmark_returns);
+
+    // Sample covariance and variance
+    let covariance = returns
+        .iter()
+        .zip(&benchmark_returns)
+        .map(|(&r_p, &r_b)| (r_p - mean_return) * (r_b - mean_benchmark_return))
+        .sum::<f64>()
+        / (n - 1.0);
+    let benchmark_variance = benchmark_returns
+        .iter()
+        .map(|&r_b| (r_b - mean_benchmark_return).powi(2))
+        .sum::<f64>()
+        / (n - 1.0);
+    let beta = covariance / benchmark_variance;
+
+    let risk_free_return = risk_free_rate / periods_per_year;
+    let alpha = ((mean_return - risk_free_return) - beta * (mean_benchmark_return - risk_free_return))
+        * periods_per_year;
+
+    let active_returns: Vec<f64> = returns
+        .iter()
+        .zip(&benchmark_returns)
+        .map(|(&r_p, &r_b)| r_p - r_b)
+        .collect();
+    let mean_active_return = mean(&active_returns);
+    let active_variance = active_returns
+        .iter()
+        .map(|&r| (r - mean_active_return).powi(2))
+        .sum::<f64>()
+        / (n - 1.0);
+    let tracking_error = active_variance.sqrt() * periods_per_year.sqrt();
+    let information_ratio = mean_active_return * periods_per_year / tracking_error;
+
+    Some(RelativeMetrics {
+        alpha,
+        beta,
+        tracking_error,
+        information_ratio,
+    })
+}
+
+fn percentile(sorted: &[f64], fraction: f64) -> f64 {
+    // Linear interpolation between the closest ranks of sorted values
+    if sorted.is_empty() {
+        return f64::NAN;
+    }
+    let rank = fraction * (sorted.len() - 1) as f64;
+    let lower = rank.floor() as usize;
+    let upper = rank.ceil() as usize;
+    sorted[lower] + (sorted[upper] - sorted[lower]) * (rank - lower as f64)
+}
+
+fn normal_quantile(p: f64) -> f64 {
+    // Inverse of the standard normal distribution (Acklam's rational approximation)
+    const A: [f64; 6] = [
+        -3.969683028665376e1,
+        2.209460984245205e2,
+        -2.759285104469687e2,
+        1.38357751867269e2,
+        -3.066479806614716e1,
+        2.506628277459239,
+    ];
+    const B: [f64; 5] = [
+        -5.447609879822406e1,
+        1.615858368580409e2,
+        -1.556989798598866e2,
+        6.680131188771972e1,
+        -1.328068155288572e1,
+    ];
+    const C: [f64; 6] = [
+        -7.784894002430293e-3,
+        -3.223964580411365e-1,
+        -2.400758277161838,
+        -2.549732539343734,
+        4.374664141464968,
+        2.938163982698783,
+    ];
+    const D: [f64; 4] = [
+        7.784695709041462e-3,
+        3.224671290700398e-1,
+        2.445134137142996,
+        3.754408661907416,
+    ];
+    let tail = |q: f64| {
+        (((((C[0] * q + C[1]) * q + C[2]) * q + C[3]) * q + C[4]) * q + C[5])
+            / ((((D[0] * q + D[1]) * q + D[2]) * q + D[3]) * q + 1.0)
+    };
+    if p < 0.02425 {
+        tail((-2.0 * p.ln()).sqrt())
+    } else if p > 1.0 - 0.02425 {
+        -tail((-2.0 * (1.0 - p).ln()).sqrt())
+    } else {
+        let q = p - 0.5;
+        let r = q * q;
+        (((((A[0] * r + A[1]) * r + A[2]) * r + A[3]) * r + A[4]) * r + A[5]) * q
+            / (((((B[0] * r + B[1]) * r + B[2]) * r + B[3]) * r + B[4]) * r + 1.0)
+    }
+}
+
+fn value_at_risk(returns: &[f64], confidence: f64) -> ValueAtRisk {
+    let mut sorted = returns.to_vec();
+    sorted.sort_by(f64::total_cmp);
+    let n = returns.len() as f64;
+
+    // Historical: the loss quantile and the mean of the returns beyond it
+    let threshold = percentile(&sorted, 1.0 - confidence);
+    let tail: Vec<f64> = sorted.iter().copied().filter(|&r| r <= threshold).collect();
+    let historical_cvar = -tail.iter().sum::<f64>() / tail.len() as f64;
+
+    // Parametric: normal distribution with the sample mean and volatility
+    let mean = returns.iter().sum::<f64>() / n;
+    let volatility = (returns.iter().map(|&r| (r - mean).powi(2)).sum::<f64>() / (n - 1.0)).sqrt();
+    let z = normal_quantile(1.0 - confidence);
+    let density = (-z * z / 2.0).exp() / (2.0 * std::f64::consts::PI).sqrt();
+
+    ValueAtRisk {
+        confidence,
+        historical_var: -threshold,
+        historical_cvar,
+        parametric_var: -(mean + z * volatility),
+        parametric_cvar: -(mean - volatility * density / (1.0 - confidence)),
+    }
+}
+
+fn infer_periods_per_year(history: &[(Timestamp, f64)]) -> f64 {
+    /*
+    Bars per year from the median interval between bars.
+    Daily and intraday bars use 252 trading days a year, or 365 if there are
+    bars on weekends like in crypto; intraday bars are counted per day.
+    Falls back to 252 with fewer than two bars.
+    */
+    let times: Vec<Timestamp> = history.iter().map(|&(timestamp, _)| timestamp).collect();
+    if times.len() < 2 {
+        return 252.0;
+    }
+    let mut intervals: Vec<i64> = times
+        .windows(2)
+        .map(|pair| (*pair[1] - *pair[0]).num_seconds())
+        .collect();
+    intervals.sort();
+    let median_interval = intervals[intervals.len() / 2];
+
+    const DAY: i64 = 24 * 60 * 60;
+    if median_interval >= 28 * DAY {
+        return 12.0;
+    }
+    if median_interval >= 5 * DAY {
+        return 52.0;
+    }
+    let trades_weekends = times
+        .iter()
+        .any(|time| matches!(time.weekday(), Weekday::Sat | Weekday::Sun));
+    let days_per_year = if trades_weekends { 365.0 } else { 252.0 };
+    if median_interval >= DAY / 2 {
+        return days_per_year;
+    }
+    let mut days: Vec<NaiveDate> = times.iter().map(|time| time.date_naive()).collect();
+    days.dedup();
+    days_per_year * times.len() as f64 / days.len() as f64
+}
+
+fn load_benchmark(csv_path: &str, timestamp_format: &TimestampFormat) -> Result<HashMap<Timestamp, f64>, Box<dyn Error>> {
+    // Closes of a benchmark csv by timestamp
+    let mut reader = csv::ReaderBuilder::new().has_headers(true).from_path(csv_path)?;
+    let close_column = reader
+        .headers()?
+        .iter()
+        .position(|header| header.trim().eq_ignore_ascii_case("close"))
+        .unwrap_or(1);
+    let mut prices = HashMap::new();
+    for result in reader.records() {
+        let record = result?;
+        let close: f64 = record
+            .get(close_column)
+            .ok_or("missing close value")?
+            .trim()
+            .parse()?;
+        prices.insert(timestamp_format.parse(record[0].trim())?, close);
+    }
+    Ok(prices)
+}
+
+fn write_curve(path: &Path, column: &str, history: &[(Timestamp, f64)]) -> Result<(), Box<dyn Error>> {
+    let mut writer = csv::Writer::from_path(path)?;
+    writer.write_record(["timestamp", column])?;
+    for (timestamp, value) in history {
+        writer.write_record([timestamp.to_rfc3339(), value.to_string()])?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::FixedOffset;
+
+    fn at(timestamp: &str) -> Timestamp {
+        timestamp.parse().unwrap()
+    }
+
+    fn fill(direction: OrderDirection, quantity: i32, price: f64) -> Fill {
+        Fill {
+            timestamp: at("2024-01-02 04:00:00"),
+            symbol: "TEST".to_string(),
+            direction,
+            quantity,
+            price,
+            fee: 1.0,
+        }
+    }
+
+    fn apply_fills(portfolio: &mut Portfolio, fills: Vec<Fill>) -> Vec<LedgerEntry> {
+        // Apply the fills to the positions the way the mock_exchange does, returning its ledger entries
+        fills
+            .into_iter()
+            .map(|fill| {
+                let realized_pnl = portfolio.positions.entry(fill.symbol.clone()).or_default().apply_fill(&fill);
+                LedgerEntry { fill, realized_pnl }
+            })
+            .collect()
+    }
+
+    fn send_portfolio(data_analyzer: &mut DataAnalyzer, portfolio: &Portfolio, ledger_entries: Vec<LedgerEntry>) {
+        match Event::new_portfolio_info(portfolio.clone(), ledger_entries) {
+            Event::PortfolioInfo(portfolio_info_event) => data_analyzer.process_portfolioinfo(portfolio_info_event),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_trade_stats() {
+        let mut data_analyzer = DataAnalyzer::new();
+        let mut portfolio = Portfolio::new(1000.);
+        // Win of 100 held 2 bars, then a long flipped to a short at a loss, then the short covered
+        let bars = vec![
+            (1, vec![fill(OrderDirection::Buy, 10, 100.)]),
+            (3, vec![fill(OrderDirection::Sell, 10, 110.2), fill(OrderDirection::Buy, 10, 100.)]),
+            (4, vec![fill(OrderDirection::Sell, 20, 95.)]),
+            (8, vec![fill(OrderDirection::Buy, 10, 96.)]),
+        ];
+        for (bar_count, fills) in bars {
+            data_analyzer.bar_count = bar_count;
+            let ledger_entries = apply_fills(&mut portfolio, fills);
+            send_portfolio(&mut data_analyzer, &portfolio, ledger_entries);
+        }
+
+        let pnls: Vec<f64> = data_analyzer.closed_trades.iter().map(|trade| trade.pnl).collect();
+        assert_eq!(pnls.len(), 3);
+        assert!((pnls[0] - 100.).abs() < 1e-9);
+        assert!((pnls[1] - -51.5).abs() < 1e-9);
+        assert!((pnls[2] - -11.5).abs() < 1e-9);
+
+        let trade_stats = data_analyzer.calculate_trade_stats();
+        assert_eq!(trade_stats.number_of_trades, 3);
+        assert!((trade_stats.win_rate - 1. / 3.).abs() < 1e-9);
+        assert!((trade_stats.average_loss - -31.5).abs() < 1e-9);
+        assert!((trade_stats.profit_factor - 100. / 63.).abs() < 1e-9);
+        assert!((trade_stats.expectancy - 37. / 3.).abs() < 1e-9);
+        assert!((trade_stats.largest_loser - -51.5).abs() < 1e-9);
+        assert!((trade_stats.average_holding_period - 7. / 3.).abs() < 1e-9);
+        assert_eq!(trade_stats.longest_winning_streak, 1);
+        assert_eq!(trade_stats.longest_losing_streak, 2);
+    }
+
+    #[test]
+    fn test_trade_stats_breakeven() {
+        let mut data_analyzer = DataAnalyzer::new();
+        let timestamp: Timestamp = "2024-01-02".parse().unwrap();
+        for (bar, pnl) in [-10., 0., -10., 20., 0.].into_iter().enumerate() {
+            data_analyzer.closed_trades.push(Trade {
+                symbol: "TEST".to_string(),
+                side: "long",
+                open_timestamp: timestamp,
+                close_timestamp: timestamp,
+                open_bar: bar,
+                close_bar: bar + 1,
+                pnl,
+            });
+        }
+
+        // The breakeven trades are neither wins nor losses, and split the two losses
+        let trade_stats = data_analyzer.calculate_trade_stats();
+        assert_eq!(trade_stats.number_of_trades, 5);
+        assert_eq!(trade_stats.breakeven_trades, 2);
+        assert!((trade_stats.win_rate - 1. / 3.).abs() < 1e-9);
+        assert!((trade_stats.average_loss - -10.).abs() < 1e-9);
+        assert!((trade_stats.expectancy - 0.).abs() < 1e-9);
+        assert_eq!(trade_stats.longest_losing_streak, 1);
+        assert_eq!(trade_stats.longest_winning_streak, 1);
+    }
+
+    #[test]
+    fn test_export() {
+        let output_dir = std::env::temp_dir().join("crab_export_test");
+        let _ = fs::remove_dir_all(&output_dir);
+        let mut data_analyzer = DataAnalyzer::new();
+        data_analyzer.set_output_dir(output_dir.to_str().unwrap().to_string());
+
+        let mut portfolio = Portfolio::new(1000.);
+        for (day, close) in [("2024-01-02", 10.), ("2024-01-03", 11.), ("2024-01-04", 12.)] {
+            let timestamp = at(&format!("{} 04:00:00", day));
+            data_analyzer.process_marketevent(MarketDataEvent::new(
+                timestamp,
+                "TEST".to_string(),
+                close,
+                close,
+                close,
+                close,
+                100,
+            ));
+            let mut fills = Vec::new();
+            if day == "2024-01-02" {
+                fills.push(Fill {
+                    timestamp,
+                    symbol: "TEST".to_string(),
+                    direction: OrderDirection::Buy,
+                    quantity: 10,
+                    price: close,
+                    fee: 0.,
+                });
+                portfolio.cash -= 100.;
+            }
+            let ledger_entries = apply_fills(&mut portfolio, fills);
+            portfolio.positions.get_mut("TEST").unwrap().mark(close);
+            portfolio.asset = portfolio.cash + 10. * close;
+            send_portfolio(&mut data_analyzer, &portfolio, ledger_entries);
+        }
+
+        let asset_history = data_analyzer.asset_history.lock().unwrap().clone();
+        let cash_history = data_analyzer.cash_history.lock().unwrap().clone();
+        let market_data_history = data_analyzer.market_data_history.lock().unwrap().clone();
+        data_analyzer
+            .export(&market_data_history, &asset_history, &cash_history)
+            .unwrap();
+
+        let report: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(output_dir.join("report.json")).unwrap())
+                .unwrap();
+        assert_eq!(report["metadata"]["bars"], 3);
+        assert_eq!(report["metadata"]["start"], "2024-01-02T04:00:00+00:00");
+        assert_eq!(report["metadata"]["final_asset"], 1020.);
+        assert!((report["metrics"]["portfolio_return"].as_f64().unwrap() - 0.02).abs() < 1e-9);
+        assert_eq!(report["trade_stats"]["number_of_trades"], 0);
+        // No closed trades to shuffle, only the bootstrap of the returns
+        assert_eq!(report["robustness"].as_array().unwrap().len(), 1);
+        assert_eq!(report["robustness"][0]["method"], "block bootstrap");
+
+        let equity_curve = fs::read_to_string(output_dir.join("equity_curve.csv")).unwrap();
+        assert_eq!(equity_curve.lines().count(), 4);
+        assert!(equity_curve.ends_with("2024-01-04T04:00:00+00:00,1020\n"));
+        let positions = fs::read_to_string(output_dir.join("positions.csv")).unwrap();
+        assert!(positions.contains("2024-01-03T04:00:00+00:00,TEST,10,10,110,10,0"));
+        let ledger = fs::read_to_string(output_dir.join("ledger.csv")).unwrap();
+        assert_eq!(ledger.lines().count(), 2);
+        assert!(output_dir.join("cash_curve.csv").exists());
+        assert!(output_dir.join("rolling.csv").exists());
+    }
+
+    #[test]
+    fn test_equity_vs_benchmark() {
+        let history = vec![(at("2024-01-02"), 100.), (at("2024-01-03"), 110.), (at("2024-01-04"), 121.)];
+        // The benchmark starts before the portfolio and misses one of its bars
+        let benchmark = vec![(at("2023-12-29"), 5.), (at("2024-01-02"), 10.), (at("2024-01-04"), 12.)];
+        let (equity, benchmark_curve) = equity_vs_benchmark(&history, &benchmark);
+        assert_eq!(equity, vec![(at("2024-01-02"), 1.), (at("2024-01-04"), 1.21)]);
+        assert_eq!(benchmark_curve, vec![(at("2024-01-02"), 1.), (at("2024-01-04"), 1.2)]);
+
+        // Without a benchmark the equity curve is still drawn
+        let (equity, benchmark_curve) = equity_vs_benchmark(&history, &[]);
+        assert_eq!(equity.len(), 3);
+        assert!(benchmark_curve.is_empty());
+    }
+
+    fn daily(days: &[&str]) -> Vec<(Timestamp, f64)> {
+        days.iter().map(|day| (at(day), 1.)).collect()
+    }
+
+    #[test]
+    fn test_infer_periods_per_year() {
+        // Weekdays only, with a weekend gap
+        let weekdays = daily(&["2024-01-04", "2024-01-05", "2024-01-08", "2024-01-09"]);
+        assert_eq!(infer_periods_per_year(&weekdays), 252.);
+        let every_day = daily(&["2024-01-05", "2024-01-06", "2024-01-07", "2024-01-08"]);
+        assert_eq!(infer_periods_per_year(&every_day), 365.);
+        let weekly = daily(&["2024-01-01", "2024-01-08", "2024-01-15"]);
+        assert_eq!(infer_periods_per_year(&weekly), 52.);
+
+        // Two days of 7 hourly bars
+        let mut hourly = Vec::new();
+        for day in ["2024-01-02", "2024-01-03"] {
+            for hour in 10..17 {
+                hourly.push((at(&format!("{} {}:00:00", day, hour)), 1.));
+            }
+        }
+        assert_eq!(infer_periods_per_year(&hourly), 252. * 7.);
+    }
+
+    #[test]
+    fn test_csv_benchmark() {
+        let csv_path = std::env::temp_dir().join("crab_benchmark_test.csv");
+        fs::write(
+            &csv_path,
+            "timestamp,open,high,low,close,volume\n01/02/2024,1,1,1,100,0\n01/03/2024,1,1,1,101,0\n",
+        )
+        .unwrap();
+        let mut data_analyzer = DataAnalyzer::new();
+        data_analyzer
+            .set_metrics_config(MetricsConfig {
+                risk_free_rate: 0.,
+                periods_per_year: Some(365.),
+                // Dates of the US market, the bars below come in UTC
+                benchmark: Benchmark::Csv(
+                    csv_path.to_str().unwrap().to_string(),
+                    TimestampFormat::new("%m/%d/%Y", FixedOffset::west_opt(5 * 3600).unwrap()),
+                ),
+                ..MetricsConfig::default()
+            })
+            .unwrap();
+        assert_eq!(data_analyzer.benchmark_symbol.as_deref(), Some("crab_benchmark_test"));
+
+        for (timestamp, symbol) in [
+            ("2024-01-02T05:00:00Z", "A"),
+            ("2024-01-02T05:00:00Z", "B"),
+            ("2024-01-03T05:00:00Z", "A"),
+        ] {
+            data_analyzer.process_marketevent(MarketDataEvent::new(
+                at(timestamp),
+                symbol.to_string(),
+                5.,
+                5.,
+                5.,
+                5.,
+                100,
+            ));
+        }
+        let market_data_history = data_analyzer.market_data_history.lock().unwrap().clone();
+        assert_eq!(
+            market_data_history,
+            vec![(at("2024-01-02T05:00:00Z"), 100.), (at("2024-01-03T05:00:00Z"), 101.)]
+        );
+        assert_eq!(data_analyzer.periods_per_year(&market_data_history), 365.);
+    }
+
+    fn series(values: &[f64]) -> Vec<(Timestamp, f64)> {
+        values
+            .iter()
+            .enumerate()
+            .map(|(day, &value)| (at(&format!("2024-01-{:02}", day + 1)), value))
+            .collect()
+    }
+
+    fn compound(returns: &[f64]) -> Vec<f64> {
+        let mut values = vec![100.];
+        for r in returns {
+            values.push(values.last().unwrap() * (1. + r));
+        }
+        values
+    }
+
+    #[test]
+    fn test_relative_metrics_known_values() {
+        // Portfolio returns are exactly 0.001 + 2 * benchmark returns
+        let benchmark_returns = [0.01, -0.02, 0.015, 0.005, -0.01, 0.02];
+        let portfolio_returns: Vec<f64> = benchmark_returns.iter().map(|r| 0.001 + 2. * r).collect();
+        let benchmark = series(&compound(&benchmark_returns));
+        let history = series(&compound(&portfolio_returns));
+
+        let relative = relative_metrics(&history, &benchmark, 252., 0.).unwrap();
+        assert!((relative.beta - 2.).abs() < 1e-9);
+        assert!((relative.alpha - 0.252).abs() < 1e-9);
+
+        // Active returns are 0.001 + benchmark returns
+        let mean_benchmark_return = benchmark_returns.iter().sum::<f64>() / 6.;
+        let standard_deviation = (benchmark_returns
+            .iter()
+            .map(|r| (r - mean_benchmark_return).powi(2))
+            .sum::<f64>()
+            / 5.)
+            .sqrt();
+        assert!((relative.tracking_error - standard_deviation * 252f64.sqrt()).abs() < 1e-9);
+        assert!(
+            (relative.information_ratio
+                - (0.001 + mean_benchmark_return) * 252. / (standard_deviation * 252f64.sqrt()))
+            .abs()
+                < 1e-9
+        );
+
+        // With a risk-free rate, alpha is the excess over the market-adjusted risk-free return
+        let relative = relative_metrics(&history, &benchmark, 252., 0.0252).unwrap();
+        assert!((relative.alpha - (0.001 + 0.0001) * 252.).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_returns_aligned_by_timestamp() {
+        let history = vec![
+            (at("2024-01-01"), 100.),
+            (at("2024-01-02"), 110.),
+            (at("2024-01-03"), 121.),
+            (at("2024-01-04"), 133.1),
+        ];
+        // The benchmark misses 2024-01-02 and has a bar before the portfolio starts
+        let benchmark = vec![
+            (at("2023-12-29"), 1.),
+            (at("2024-01-01"), 50.),
+            (at("2024-01-03"), 60.),
+            (at("2024-01-04"), 54.),
+        ];
+        let (returns, benchmark_returns) = aligned_returns(&history, &benchmark);
+        assert_eq!(returns.len(), 2);
+        assert!((returns[0] - 0.21).abs() < 1e-9);
+        assert!((returns[1] - 0.1).abs() < 1e-9);
+        assert!((benchmark_returns[0] - 0.2).abs() < 1e-9);
+        assert!((benchmark_returns[1] - -0.1).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_metrics_without_benchmark_overlap() {
+        let mut data_analyzer = DataAnalyzer::new();
+        data_analyzer.metrics_config.periods_per_year = Some(252.);
+        // The benchmark has a long history before the portfolio starts
+        for (day, value) in [("2023-12-01", 10.), ("2023-12-29", 40.), ("2024-01-03", 50.), ("2024-01-04", 55.)] {
+            data_analyzer.market_data_history.lock().unwrap().push((at(day), value));
+        }
+        for (day, value) in [("2024-01-03", 100.), ("2024-01-04", 110.), ("2024-01-05", 99.)] {
+            data_analyzer.asset_history.lock().unwrap().push((at(day), value));
+        }
+
+        // Two bars in common: a market return, but too few for the relative metrics
+        let metrics = data_analyzer.calculate_metrics().unwrap();
+        assert!((metrics.market_return.unwrap() - 0.1).abs() < 1e-9);
+        assert!(metrics.alpha.is_none() && metrics.beta.is_none());
+        assert!(metrics.tracking_error.is_none() && metrics.information_ratio.is_none());
+        assert!((metrics.portfolio_return - -0.01).abs() < 1e-9);
+        let rows = metrics.rows();
+        assert!(rows.contains(&("Alpha".to_string(), "n/a".to_string())));
+
+        // None at all
+        data_analyzer.market_data_history.lock().unwrap().clear();
+        let metrics = data_analyzer.calculate_metrics().unwrap();
+        assert!(metrics.market_return.is_none());
+        assert!(metrics.sharpe_ratio.is_finite());
+    }
+
+    #[test]
+    fn test_value_at_risk() {
+        assert!((normal_quantile(0.975) - 1.959964).abs() < 1e-6);
+        assert!((normal_quantile(0.01) - -2.326348).abs() < 1e-6);
+        assert!((normal_quantile(0.5)).abs() < 1e-12);
+
+        // Returns -10%, -9%, ..., 9%
+        let returns: Vec<f64> = (-10..10).map(|r| r as f64 / 100.).collect();
+        let value_at_risk = value_at_risk(&returns, 0.9);
+        // The 10% quantile sits at rank 1.9, between -9% and -8%
+        assert!((value_at_risk.historical_var - 0.081).abs() < 1e-9);
+        assert!((value_at_risk.historical_cvar - 0.095).abs() < 1e-9);
+
+        let mean = -0.005;
+        let volatility = (returns.iter().map(|r| (r - mean) * (r - mean)).sum::<f64>() / 19.).sqrt();
+        let z = -1.2815515655446004;
+        let density = (-z * z / 2.0_f64).exp() / (2.0 * std::f64::consts::PI).sqrt();
+        assert!((value_at_risk.parametric_var - -(mean + z * volatility)).abs() < 1e-6);
+        assert!((value_at_risk.parametric_cvar - -(mean - volatility * density / 0.1)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_extended_risk_metrics() {
+        let mut data_analyzer = DataAnalyzer::new();
+        let values = [100., 110., 99., 108.9, 119.79, 107.811, 118.5921];
+        for (day, &value) in values.iter().enumerate() {
+            let timestamp = at(&format!("2024-01-{:02}", day + 1));
+            data_analyzer.market_data_history.lock().unwrap().push((timestamp, value));
+            data_analyzer.asset_history.lock().unwrap().push((timestamp, value));
+        }
+        data_analyzer.metrics_config.periods_per_year = Some(252.);
+
+        // Four returns of +10% and two of -10%
+        let metrics = data_analyzer.calculate_metrics().unwrap();
+        assert!((metrics.max_drawdown - -0.1).abs() < 1e-9);
+        assert!((metrics.calmar_ratio - metrics.annualized_portfolio_return / 0.1).abs() < 1e-9);
+        assert!((metrics.tail_ratio - 1.).abs() < 1e-9);
+        // Two-point distribution with p = 2/3
+        assert!((metrics.skewness - -(0.5f64).sqrt()).abs() < 1e-9);
+        assert!((metrics.kurtosis - -1.5).abs() < 1e-9);
+        // Ulcer index of drawdowns 0, 0, -10%, -1%, 0, -10%, -1%
+        assert!((metrics.ulcer_index - (0.0202f64 / 7.).sqrt()).abs() < 1e-9);
+        let threshold = 0.05 / 252.;
+        let omega = (4. * (0.1 - threshold)) / (2. * (0.1 + threshold));
+        assert!((metrics.omega_ratio - omega).abs() < 1e-9);
+        assert_eq!(metrics.value_at_risk.len(), 2);
+    }
+
+    #[test]
+    fn test_rolling_series() {
+        // The portfolio moves twice as much as the benchmark
+        let benchmark_returns = [0.01, -0.02, 0.03, 0.01, -0.01];
+        let benchmark = series(&compound(&benchmark_returns));
+        let doubled: Vec<f64> = benchmark_returns.iter().map(|r| 2. * r).collect();
+        let history = series(&compound(&doubled));
+
+        let rolling = rolling_series(&history, &benchmark, 3, 252., 0.);
+        assert_eq!(rolling.sharpe.len(), 3);
+        assert_eq!(rolling.sharpe[0].0, at("2024-01-04"));
+        for (_, beta) in &rolling.beta {
+            assert!((beta - 2.).abs() < 1e-9);
+        }
+
+        // Returns 0.02, -0.04, 0.06 in the first window
+        let volatility = (((0.02f64 - 0.04 / 3.).powi(2) + (-0.04f64 - 0.04 / 3.).powi(2)
+            + (0.06f64 - 0.04 / 3.).powi(2))
+            / 2.)
+            .sqrt()
+            * 252f64.sqrt();
+        assert!((rolling.volatility[0].1 - volatility).abs() < 1e-9);
+        assert!((rolling.sharpe[0].1 - 0.04 / 3. * 252. / volatility).abs() < 1e-9);
+
+        // The window ending with the last -2% return peaks on the bar before it
+        assert_eq!(rolling.drawdown[0], (at("2024-01-04"), 0.));
+        assert_eq!(rolling.drawdown[2].0, at("2024-01-06"));
+        assert!((rolling.drawdown[2].1 - -0.02).abs() < 1e-9);
+    }
+}
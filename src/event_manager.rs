@@ -1,9 +1,10 @@
+use crate::clock::{Clock, ClockMode};
 use crate::shared_structures::*;
-use crossbeam::channel::{bounded, unbounded, Receiver, Sender};
+use chrono::Duration;
+use crossbeam::channel::{bounded, unbounded, Receiver, Select, Sender};
 use simplelog::*;
 use std::any::TypeId;
 use std::collections::HashMap;
-use std::time::{Duration, Instant};
 
 pub trait ModuleReceive {
     /*
@@ -20,16 +21,58 @@ pub trait ModulePublish {
      */
     fn use_sender(&mut self, sender: Sender<Event>);
 }
+
+pub trait ModuleHandle: ModuleReceive {
+    /*
+    Trait that allows the event manager to call a module directly, for the
+    single-threaded mode. The run loop of the module hands every event it
+    receives to the same function. validate runs before the first event,
+    from the run loop or from proceed_sync, and panics if the module is not
+    set up.
+     */
+    fn handle_event(&mut self, event: Event);
+
+    fn validate(&self) {}
+}
+
+pub trait DataSource {
+    /*
+    Trait that allows the event manager to pull market data from a feeder,
+    for the single-threaded mode. Returns None once the data is exhausted.
+     */
+    fn next_event(&mut self) -> Option<Event>;
+}
+
+struct Subscription {
+    sender: Sender<Event>,
+    // Applied before sending, None to send every event of the type
+    filter: Option<EventFilter>,
+    // Index of the module in the single-threaded mode
+    handler: Option<usize>,
+}
+
 pub struct EventManager {
     /*
     The event_manager will maintain a subscriber_book, and dispatch
     an event to all modules that subscribe to the event type.
     */
-    subscriber_book: HashMap<TypeId, Vec<Sender<Event>>>,
+    subscriber_book: HashMap<TypeId, Vec<Subscription>>,
     lp_sender: Sender<Event>,
     lp_receiver: Receiver<Event>,
     hp_sender: Sender<Event>,
     hp_receiver: Receiver<Event>,
+    // Number of low-priority publishers (data feeders) yet to send an EndOfDataEvent.
+    active_feeders: usize,
+    // Fires the timers the modules schedule, simulated from the market data by default
+    clock: Clock,
+    // Modules called directly in the single-threaded mode, empty otherwise
+    modules: Vec<Box<dyn ModuleHandle>>,
+}
+
+impl Default for EventManager {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl EventManager {
@@ -46,6 +89,30 @@ impl EventManager {
             lp_receiver,
             hp_sender,
             hp_receiver,
+            active_feeders: 0,
+            clock: Clock::new(ClockMode::Simulated),
+            modules: Vec::new(),
+        }
+    }
+
+    pub fn set_clock_mode(&mut self, mode: ClockMode) {
+        // Wall time for live trading, timers scheduled so far are dropped
+        self.clock = Clock::new(mode);
+    }
+
+    pub fn schedule_timer(&mut self, name: String, at: Timestamp, every: Option<Duration>) -> u64 {
+        /*
+        Schedule a timer before the modules run, e.g. a daily rebalance.
+        Modules can also publish a ScheduleTimerEvent (Event::new_schedule_timer)
+        or a CancelTimerEvent at any time. Returns the id of the timer.
+        */
+        match Event::new_schedule_timer(name, at, every) {
+            Event::ScheduleTimer(schedule_timer_event) => {
+                let timer_id = schedule_timer_event.timer_id;
+                self.clock.schedule(schedule_timer_event);
+                timer_id
+            }
+            _ => unreachable!(),
         }
     }
 
@@ -54,23 +121,83 @@ impl EventManager {
         The function will allow a module with ModuleReveive bound to subscribe
         certain type of events. 
         */
-        let type_id = TypeId::of::<E>();
-        let sender = module.get_sender();
+        self.add_subscription(TypeId::of::<E>(), module.get_sender(), None);
+    }
+
+    pub fn subscribe_filtered<E: 'static, T: ModuleReceive>(&mut self, module: &T, filter: EventFilter) {
+        /*
+        Subscribe a module to the events of a type that pass a filter, e.g.
+        EventFilter::symbols(&["AAPL", "MSFT"]) for the bars of two symbols out
+        of a universe. Filtered out events are never sent to the module.
+        */
+        self.add_subscription(TypeId::of::<E>(), module.get_sender(), Some(filter));
+    }
+
+    pub fn unsubscribe<E: 'static, T: ModuleReceive>(&mut self, module: &T) {
+        /*
+        Remove the subscriptions of a module to a type of events. While running,
+        modules publish Event::new_unsubscribe or Event::new_subscribe instead.
+        */
+        self.remove_subscriptions(Some(TypeId::of::<E>()), &module.get_sender());
+    }
+
+    pub fn unsubscribe_all<T: ModuleReceive>(&mut self, module: &T) {
+        self.remove_subscriptions(None, &module.get_sender());
+    }
+
+    fn add_subscription(&mut self, type_id: TypeId, sender: Sender<Event>, filter: Option<EventFilter>) {
+        // In the single-threaded mode the subscriber must be one of the modules it runs
+        let handler = if self.modules.is_empty() {
+            None
+        } else {
+            match module_index(&self.modules, &sender) {
+                Some(index) => Some(index),
+                None => {
+                    warn!("Ignoring a subscription of a module not passed to proceed_sync");
+                    return;
+                }
+            }
+        };
         self.subscriber_book
             .entry(type_id)
-            .or_insert_with(Vec::new)
-            .push(sender);
+            .or_default()
+            .push(Subscription {
+                sender,
+                filter,
+                handler,
+            });
     }
 
-    pub fn allow_publish<T: ModulePublish>(&mut self, priority: String, module: &mut T) {
+    fn remove_subscriptions(&mut self, type_id: Option<TypeId>, sender: &Sender<Event>) {
+        // Emptied types stay in the book, their events are simply not sent
+        for (subscribed_type_id, subscriptions) in self.subscriber_book.iter_mut() {
+            if type_id.is_none_or(|type_id| type_id == *subscribed_type_id) {
+                subscriptions.retain(|subscription| !subscription.sender.same_channel(sender));
+            }
+        }
+    }
+
+    pub fn subscribe_all<T: ModuleReceive>(&mut self, module: &T) {
+        // Every event type the event_manager dispatches, custom events aside, e.g. for a recorder
+        for type_id in dispatched_type_ids() {
+            self.add_subscription(type_id, module.get_sender(), None);
+        }
+    }
+
+    pub fn allow_publish<T: ModulePublish + ?Sized>(&mut self, priority: String, module: &mut T) {
         /*
         The function will allow a module with ModulePublish bound to publish
         events with a 2-level priority. High priority events will be prioritized to be
         published. 
+        Low priority publishers are treated as data feeders: each one must publish
+        an EndOfDataEvent when its data is exhausted, otherwise proceed never returns.
         */
         match priority.as_str() {
             "high" => module.use_sender(self.hp_sender.clone()),
-            "low" => module.use_sender(self.lp_sender.clone()),
+            "low" => {
+                module.use_sender(self.lp_sender.clone());
+                self.active_feeders += 1;
+            }
             _ => panic!(
                 "Invalid priority: expected 'high' or 'low', but got '{}'",
                 priority
@@ -83,19 +210,53 @@ impl EventManager {
         This function dispatches events to all its subscribers
         */
 
-        // Match event type. If an custom type is introduced also match it here.
-        let type_id = match &event {
-            Event::MarketData(_) => TypeId::of::<MarketDataEvent>(),
-            Event::OrderPlace(_) => TypeId::of::<OrderPlaceEvent>(),
-            Event::PortfolioInfo(_) => TypeId::of::<PortfolioInfoEvent>(),
-            Event::ShutDown(_) => TypeId::of::<ShutDownEvent>(),
+        // Timer requests are for the clock only
+        let event = match event {
+            Event::ScheduleTimer(schedule_timer_event) => {
+                self.clock.schedule(schedule_timer_event);
+                return;
+            }
+            Event::CancelTimer(cancel_timer_event) => {
+                self.clock.cancel(cancel_timer_event.timer_id);
+                return;
+            }
+            // So are subscription changes
+            Event::Subscribe(subscribe_event) => {
+                let SubscribeEvent {
+                    type_id,
+                    sender,
+                    filter,
+                    ..
+                } = subscribe_event;
+                self.add_subscription(type_id, sender, filter);
+                return;
+            }
+            Event::Unsubscribe(unsubscribe_event) => {
+                self.remove_subscriptions(unsubscribe_event.type_id, &unsubscribe_event.sender);
+                return;
+            }
+            event => event,
         };
 
-        // Dispatch to subscribers
-        if let Some(subscribers) = self.subscriber_book.get(&type_id) {
-            for dispatch_sender in subscribers {
-                if let Err(e) = dispatch_sender.send(event.clone()) {
-                    eprintln!("Failed to send event to subscriber: {:?}", e);
+        let type_id = event_type_id(&event);
+
+        // Dispatch to subscribers, in the single-threaded mode they handle the event right away
+        if let Some(subscriptions) = self.subscriber_book.get(&type_id) {
+            for subscription in subscriptions {
+                let event = match &subscription.filter {
+                    Some(filter) => match filter.apply(&event) {
+                        Some(event) => event,
+                        None => continue,
+                    },
+                    None => event.clone(),
+                };
+                match subscription.handler {
+                    Some(index) => self.modules[index].handle_event(event),
+                    None => {
+                        if let Err(e) = subscription.sender.send(event) {
+                            eprintln!("Failed to send event to subscriber: {:?}", e);
+                        }
+                    }
                 }
             }
         } else {
@@ -104,38 +265,884 @@ impl EventManager {
         }
     }
 
-    pub fn proceed(&mut self) {
-        // 
-        let event = self.lp_receiver.recv().unwrap();
-        self.dispatch_event(event);
+    fn handle_low_priority(&mut self, event: Event) {
+        // EndOfDataEvents are consumed here to track the remaining feeders.
+        let timestamp = match &event {
+            Event::EndOfData(_) => {
+                match self.active_feeders.checked_sub(1) {
+                    Some(active_feeders) => {
+                        self.active_feeders = active_feeders;
+                        debug!("A data feeder finished, {} still active", self.active_feeders);
+                    }
+                    // A second one, or one from a publisher not allowed as "low"
+                    None => warn!("Ignoring an EndOfDataEvent with no data feeder active"),
+                }
+                return;
+            }
+            Event::Timer(timer_event) => {
+                /*
+                Replayed from a journal: moves the clock to the time the timer
+                fired, rather than being dispatched. The clock fires the timers
+                the modules scheduled again, under their own ids, so none of
+                them arrives twice.
+                */
+                let timer_events = self.clock.advance(timer_event.timestamp, true);
+                self.dispatch_timers(timer_events);
+                return;
+            }
+            event => market_data_timestamp(event),
+        };
+        match (timestamp, self.clock.mode()) {
+            (Some(timestamp), ClockMode::Simulated) => {
+                /*
+                Market data moves the simulated clock. Timers due before the data
+                fire first, and the modules finish handling them, so orders placed
+                on a timer are in the exchange before the bar. Timers due at the
+                timestamp of the data fire after it.
+                */
+                let timer_events = self.clock.advance(timestamp, false);
+                if !timer_events.is_empty() {
+                    self.dispatch_timers(timer_events);
+                    self.wait_for_idle();
+                }
+                self.dispatch_event(event);
+                let timer_events = self.clock.advance(timestamp, true);
+                self.dispatch_timers(timer_events);
+            }
+            _ => self.dispatch_event(event),
+        }
+    }
 
-        let timeout = Duration::from_secs(3);
-        let mut start = Instant::now();
+    fn dispatch_timers(&mut self, timer_events: Vec<Event>) {
+        for timer_event in timer_events {
+            debug!("Timer: {:?}", timer_event);
+            self.dispatch_event(timer_event);
+        }
+    }
+
+    fn wait_for_idle(&mut self) {
+        /*
+        Block until every subscriber has processed all events dispatched so far.
+        A rendezvous channel only accepts a new event once the module is back
+        at recv(), so after a successful round of BarrierEvents anything the
+        modules published in response is already queued on the high-priority
+        channel. Repeat until a round produces no new events.
+        In the single-threaded mode the modules are done as soon as they return,
+        so it only dispatches what they published, and what that gives, in order.
+        */
+        if !self.modules.is_empty() {
+            while let Ok(event) = self.hp_receiver.try_recv() {
+                self.dispatch_event(event);
+            }
+            return;
+        }
+
+        let mut subscribers: Vec<Sender<Event>> = Vec::new();
+        for sender in self.subscriber_book.values().flatten().map(|subscription| &subscription.sender) {
+            if !subscribers.iter().any(|s| s.same_channel(sender)) {
+                subscribers.push(sender.clone());
+            }
+        }
 
         loop {
-            // Continuously process all high-priority events
+            for sender in &subscribers {
+                if let Err(e) = sender.send(Event::new_barrier()) {
+                    eprintln!("Failed to send barrier to subscriber: {:?}", e);
+                }
+            }
+            if self.hp_receiver.is_empty() {
+                break;
+            }
             while let Ok(event) = self.hp_receiver.try_recv() {
                 self.dispatch_event(event);
-                start = Instant::now();
             }
+        }
+    }
+
+    pub fn proceed(&mut self) {
+        /*
+        Dispatch events until every data feeder has published its EndOfDataEvent,
+        then drain all in-flight high-priority events and dispatch a ShutDownEvent.
+        The modules settle after every low-priority event, like in proceed_sync.
+        */
+        let hp_receiver = self.hp_receiver.clone();
+        let lp_receiver = self.lp_receiver.clone();
 
-            // Process low-priority events
-            if let Ok(event) = self.lp_receiver.try_recv() {
+        while self.active_feeders > 0 {
+            // High-priority events first, a bar never overtakes the orders published before it
+            if let Ok(event) = hp_receiver.try_recv() {
                 self.dispatch_event(event);
-                start = Instant::now();
-            } else {
-                // If no events are available, you may want to sleep or handle idle state
-                // For example:
-                if start.elapsed() >= timeout {
-                    // wait until no upcoming event for timeout period
-                    // note that the returning of the event_manager.proceed will terminate the main thread.
-                    info!("All data feeded, CrabQuant shutting up...");
-                    let shut_down_event = Event::new_shut_down();
-                    self.dispatch_event(shut_down_event);
-                    break;
+                continue;
+            }
+
+            // On the wall clock, fire the timers that are due and wake up for the next one
+            let next_deadline = match self.clock.mode() {
+                ClockMode::Simulated => None,
+                ClockMode::WallTime => {
+                    let timer_events = self.clock.advance_to_wall_time();
+                    self.dispatch_timers(timer_events);
+                    self.clock.next_deadline()
+                }
+            };
+
+            if let Ok(event) = lp_receiver.try_recv() {
+                // Every module is done with the event, and with what it published in response, before the next one
+                self.handle_low_priority(event);
+                self.wait_for_idle();
+                continue;
+            }
+
+            // Nothing to do, wait for an event of either priority, or the next timer
+            let mut select = Select::new();
+            select.recv(&hp_receiver);
+            select.recv(&lp_receiver);
+            match next_deadline {
+                Some(deadline) => {
+                    let timeout = (*deadline - *self.clock.now().unwrap())
+                        .to_std()
+                        .unwrap_or_default();
+                    let _ = select.ready_timeout(timeout);
+                }
+                None => {
+                    select.ready();
+                }
+            }
+        }
+
+        self.wait_for_idle();
+        // note that the returning of the event_manager.proceed will terminate the main thread.
+        info!("All data feeded, CrabQuant shutting up...");
+        let shut_down_event = Event::new_shut_down();
+        self.dispatch_event(shut_down_event);
+    }
+
+    pub fn proceed_sync(&mut self, modules: Vec<Box<dyn ModuleHandle>>, mut sources: Vec<Box<dyn DataSource>>) {
+        /*
+        Single-threaded counterpart of proceed, for backtests and research sweeps.
+        Takes the subscribed modules instead of running them on threads, and
+        the data feeders instead of letting them publish, in place of
+        allow_publish("low", ...). Every bar is handled to the end, timers and
+        whatever the modules publish in response included, before the next one,
+        in the order the threaded mode settles in. There is no thread or channel
+        to wait on, so the same run gives the same result, much faster.
+        Bars of several sources are merged by timestamp, ties in the order of sources.
+        A SubscribeEvent for a module not among them is ignored with a warning.
+        */
+        for module in &modules {
+            module.validate();
+        }
+        for subscription in self.subscriber_book.values_mut().flatten() {
+            let index = module_index(&modules, &subscription.sender)
+                .expect("Every subscribed module must be passed to proceed_sync");
+            subscription.handler = Some(index);
+        }
+        self.modules = modules;
+
+        let mut heads: Vec<Option<Event>> = sources.iter_mut().map(|source| source.next_event()).collect();
+        loop {
+            // The source with the earliest next event, events without a timestamp go first
+            let next = heads
+                .iter()
+                .enumerate()
+                .filter_map(|(index, head)| head.as_ref().map(|event| (market_data_timestamp(event), index)))
+                .min();
+            let Some((_, index)) = next else {
+                break;
+            };
+            let event = heads[index].take().unwrap();
+
+            if self.clock.mode() == ClockMode::WallTime {
+                let timer_events = self.clock.advance_to_wall_time();
+                self.dispatch_timers(timer_events);
+            }
+            self.handle_low_priority(event);
+            self.wait_for_idle();
+            // Read on only now, a source may depend on what the modules did with the event
+            heads[index] = sources[index].next_event();
+        }
+
+        info!("All data feeded, CrabQuant shutting up...");
+        let shut_down_event = Event::new_shut_down();
+        self.dispatch_event(shut_down_event);
+    }
+}
+
+/*
+Type of every variant of Event, the key of the subscriber book. Framework
+events are matched by variant, user-defined ones travel as Event::Custom and
+are keyed by the type of their payload. The dispatched variants are those
+subscribers receive, subscribe_all takes the same list; the internal ones
+are consumed by the event_manager itself. A new variant must be added to
+one of the two, or the match below does not compile.
+*/
+macro_rules! event_types {
+    (
+        dispatched: { $($dispatched:ident($dispatched_type:ty)),* $(,)? }
+        internal: { $($internal:ident($internal_type:ty)),* $(,)? }
+    ) => {
+        fn event_type_id(event: &Event) -> TypeId {
+            match event {
+                $(Event::$dispatched(_) => TypeId::of::<$dispatched_type>(),)*
+                $(Event::$internal(_) => TypeId::of::<$internal_type>(),)*
+                Event::Custom(custom_event) => custom_event.type_id,
+            }
+        }
+
+        fn dispatched_type_ids() -> Vec<TypeId> {
+            vec![$(TypeId::of::<$dispatched_type>()),*]
+        }
+    };
+}
+
+event_types! {
+    dispatched: {
+        MarketData(MarketDataEvent),
+        MarketDataBatch(MarketDataBatchEvent),
+        OrderPlace(OrderPlaceEvent),
+        PortfolioInfo(PortfolioInfoEvent),
+        ShutDown(ShutDownEvent),
+        OrderAccepted(OrderAcceptedEvent),
+        OrderFilled(OrderFilledEvent),
+        OrderPartiallyFilled(OrderPartiallyFilledEvent),
+        OrderRejected(OrderRejectedEvent),
+        OrderCancelled(OrderCancelledEvent),
+        OrderCancel(OrderCancelEvent),
+        OrderModify(OrderModifyEvent),
+        OrderModified(OrderModifiedEvent),
+        OrderCancelRejected(OrderCancelRejectedEvent),
+        OrderModifyRejected(OrderModifyRejectedEvent),
+        MarginCall(MarginCallEvent),
+        Timer(TimerEvent),
+    }
+    internal: {
+        // Consumed by proceed, or sent to the modules directly
+        EndOfData(EndOfDataEvent),
+        Barrier(BarrierEvent),
+        // Handled by the clock and the subscriber book
+        ScheduleTimer(ScheduleTimerEvent),
+        CancelTimer(CancelTimerEvent),
+        Subscribe(SubscribeEvent),
+        Unsubscribe(UnsubscribeEvent),
+    }
+}
+
+fn module_index(modules: &[Box<dyn ModuleHandle>], sender: &Sender<Event>) -> Option<usize> {
+    modules
+        .iter()
+        .position(|module| module.get_sender().same_channel(sender))
+}
+
+fn market_data_timestamp(event: &Event) -> Option<Timestamp> {
+    match event {
+        Event::MarketData(market_data_event) => Some(market_data_event.timestamp),
+        Event::MarketDataBatch(market_data_batch_event) => Some(market_data_batch_event.timestamp),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fill_model::MidPrice;
+    use crate::market_data_feeder::{MarketDataFeeder, MarketDataFeederLocal, MarketDataFeederMulti};
+    use crate::mock_exchange::MockExchange;
+    use crate::strategies::moving_average_crossover::MAcross;
+    use crate::strategy_manager::{Strategy, StrategyManager};
+    use std::collections::HashSet;
+    use std::{fs, thread};
+
+    struct TestModule {
+        sender: Sender<Event>,
+        receiver: Receiver<Event>,
+        publish_sender: Option<Sender<Event>>,
+    }
+
+    impl TestModule {
+        fn new() -> Self {
+            let (sender, receiver) = unbounded();
+            TestModule {
+                sender,
+                receiver,
+                publish_sender: None,
+            }
+        }
+    }
+
+    impl ModuleReceive for TestModule {
+        fn get_sender(&self) -> Sender<Event> {
+            self.sender.clone()
+        }
+    }
+
+    impl ModulePublish for TestModule {
+        fn use_sender(&mut self, sender: Sender<Event>) {
+            self.publish_sender = Some(sender);
+        }
+    }
+
+    impl ModuleHandle for TestModule {
+        fn handle_event(&mut self, event: Event) {
+            self.sender.send(event).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_shut_down_after_end_of_data() {
+        let mut event_manager = EventManager::new();
+        let subscriber = TestModule::new();
+        let mut feeder = TestModule::new();
+        event_manager.subscribe::<MarketDataEvent, TestModule>(&subscriber);
+        event_manager.subscribe::<ShutDownEvent, TestModule>(&subscriber);
+        event_manager.allow_publish("low".to_string(), &mut feeder);
+
+        let publish_sender = feeder.publish_sender.unwrap();
+        for i in 0..3 {
+            let event = Event::MarketData(MarketDataEvent::new(
+                format!("2024-01-0{}", i + 1).parse().unwrap(),
+                "TEST".to_string(),
+                1.,
+                1.,
+                1.,
+                1.,
+                100,
+            ));
+            publish_sender.send(event).unwrap();
+        }
+        publish_sender.send(Event::new_end_of_data()).unwrap();
+
+        event_manager.proceed();
+
+        let received: Vec<Event> = subscriber
+            .receiver
+            .try_iter()
+            .filter(|event| !matches!(event, Event::Barrier(_)))
+            .collect();
+        assert_eq!(received.len(), 4);
+        assert!(received[..3]
+            .iter()
+            .all(|event| matches!(event, Event::MarketData(_))));
+        assert!(matches!(received[3], Event::ShutDown(_)));
+    }
+
+    #[test]
+    fn test_timers_in_order_with_market_data() {
+        let mut event_manager = EventManager::new();
+        let subscriber = TestModule::new();
+        let mut feeder = TestModule::new();
+        event_manager.subscribe::<MarketDataEvent, TestModule>(&subscriber);
+        event_manager.subscribe::<TimerEvent, TestModule>(&subscriber);
+        event_manager.allow_publish("low".to_string(), &mut feeder);
+        event_manager.schedule_timer(
+            "daily".to_string(),
+            "2024-01-01".parse().unwrap(),
+            Some(Duration::days(1)),
+        );
+        event_manager.schedule_timer("once".to_string(), "2024-01-02 12:00:00".parse().unwrap(), None);
+
+        let publish_sender = feeder.publish_sender.unwrap();
+        for i in 0..3 {
+            let event = Event::MarketData(MarketDataEvent::new(
+                format!("2024-01-0{}", i + 1).parse().unwrap(),
+                "TEST".to_string(),
+                1.,
+                1.,
+                1.,
+                1.,
+                100,
+            ));
+            publish_sender.send(event).unwrap();
+        }
+        publish_sender.send(Event::new_end_of_data()).unwrap();
+
+        event_manager.proceed();
+
+        let received: Vec<String> = subscriber
+            .receiver
+            .try_iter()
+            .filter_map(|event| match event {
+                Event::MarketData(bar) => Some(format!("bar {}", bar.timestamp)),
+                Event::Timer(timer) => Some(format!("{} {}", timer.name, timer.timestamp)),
+                _ => None,
+            })
+            .collect();
+        // A timer at the time of a bar comes after it, one between two bars before the second
+        assert_eq!(
+            received,
+            vec![
+                "bar 2024-01-01 00:00:00",
+                "daily 2024-01-01 00:00:00",
+                "bar 2024-01-02 00:00:00",
+                "daily 2024-01-02 00:00:00",
+                "once 2024-01-02 12:00:00",
+                "bar 2024-01-03 00:00:00",
+                "daily 2024-01-03 00:00:00",
+            ]
+        );
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct Signal {
+        symbol: String,
+        strength: f64,
+    }
+
+    struct RiskAlert;
+
+    #[test]
+    fn test_custom_events_routed_by_payload_type() {
+        let mut event_manager = EventManager::new();
+        let signal_subscriber = TestModule::new();
+        let alert_subscriber = TestModule::new();
+        let mut publisher = TestModule::new();
+        let mut feeder = TestModule::new();
+        event_manager.subscribe::<Signal, TestModule>(&signal_subscriber);
+        event_manager.subscribe::<RiskAlert, TestModule>(&alert_subscriber);
+        event_manager.allow_publish("high".to_string(), &mut publisher);
+        event_manager.allow_publish("low".to_string(), &mut feeder);
+
+        let publish_sender = publisher.publish_sender.unwrap();
+        publish_sender
+            .send(Event::new_custom(Signal {
+                symbol: "TEST".to_string(),
+                strength: 0.5,
+            }))
+            .unwrap();
+        publish_sender.send(Event::new_custom(RiskAlert)).unwrap();
+        publish_sender.send(Event::new_custom(RiskAlert)).unwrap();
+        feeder.publish_sender.unwrap().send(Event::new_end_of_data()).unwrap();
+
+        event_manager.proceed();
+
+        let custom_events = |module: &TestModule| -> Vec<CustomEvent> {
+            module
+                .receiver
+                .try_iter()
+                .filter_map(|event| match event {
+                    Event::Custom(custom_event) => Some(custom_event),
+                    _ => None,
+                })
+                .collect()
+        };
+        let signals = custom_events(&signal_subscriber);
+        assert_eq!(signals.len(), 1);
+        assert_eq!(
+            signals[0].payload::<Signal>(),
+            Some(&Signal {
+                symbol: "TEST".to_string(),
+                strength: 0.5,
+            })
+        );
+        assert!(signals[0].payload::<RiskAlert>().is_none());
+        let alerts = custom_events(&alert_subscriber);
+        assert_eq!(alerts.len(), 2);
+        assert!(alerts[0].payload::<RiskAlert>().is_some());
+    }
+
+    fn backtest(csv_path: &str, single_threaded: bool) -> Vec<String> {
+        // Portfolio updates and fills seen by a recording module, floats by their bits
+        let mut event_manager = EventManager::new();
+        let mut strategy_manager = StrategyManager::new();
+        strategy_manager.add_strategy(Box::new(MAcross::new(5, 10)));
+        let mut mock_exchange = MockExchange::new(|trade_cost| trade_cost * 0.001, Box::new(MidPrice));
+        let recorder = TestModule::new();
+        let receiver = recorder.receiver.clone();
+        event_manager.subscribe::<MarketDataEvent, StrategyManager>(&strategy_manager);
+        event_manager.subscribe::<PortfolioInfoEvent, StrategyManager>(&strategy_manager);
+        event_manager.subscribe::<OrderFilledEvent, StrategyManager>(&strategy_manager);
+        event_manager.allow_publish("high".to_string(), &mut strategy_manager);
+        event_manager.subscribe::<MarketDataEvent, MockExchange>(&mock_exchange);
+        event_manager.subscribe::<OrderPlaceEvent, MockExchange>(&mock_exchange);
+        event_manager.allow_publish("high".to_string(), &mut mock_exchange);
+        event_manager.subscribe::<PortfolioInfoEvent, TestModule>(&recorder);
+        event_manager.subscribe::<OrderFilledEvent, TestModule>(&recorder);
+        event_manager.subscribe::<OrderPartiallyFilledEvent, TestModule>(&recorder);
+        let mut feeder = MarketDataFeederLocal::new("TEST".to_string(), csv_path.to_string());
+
+        if single_threaded {
+            event_manager.proceed_sync(
+                vec![Box::new(strategy_manager), Box::new(mock_exchange), Box::new(recorder)],
+                vec![Box::new(feeder)],
+            );
+        } else {
+            event_manager.allow_publish("low".to_string(), &mut feeder);
+            thread::spawn(move || mock_exchange.run());
+            thread::spawn(move || strategy_manager.run());
+            thread::spawn(move || feeder.start_feeding());
+            event_manager.proceed();
+        }
+
+        receiver
+            .try_iter()
+            .filter_map(|event| match event {
+                Event::PortfolioInfo(portfolio_info_event) => Some(format!(
+                    "portfolio {} {}",
+                    portfolio_info_event.portfolio.asset.to_bits(),
+                    portfolio_info_event.portfolio.cash.to_bits()
+                )),
+                Event::OrderFilled(OrderFilledEvent { fill, .. })
+                | Event::OrderPartiallyFilled(OrderPartiallyFilledEvent { fill, .. }) => Some(format!(
+                    "fill {} {:?} {} {} {}",
+                    fill.timestamp,
+                    fill.direction,
+                    fill.quantity,
+                    fill.price.to_bits(),
+                    fill.fee.to_bits()
+                )),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_single_threaded_matches_threaded() {
+        let path = std::env::temp_dir().join("crab_event_manager_test.csv");
+        let mut content = "timestamp,open,high,low,close,volume\n".to_string();
+        let start: Timestamp = "2024-01-01".parse().unwrap();
+        for day in 0..200 {
+            let price = 100. + 20. * (day as f64 / 8.).sin();
+            content.push_str(&format!(
+                "{},{},{},{},{},100000\n",
+                Timestamp(*start + Duration::days(day)),
+                price,
+                price + 1.,
+                price - 1.,
+                price + 0.5
+            ));
+        }
+        fs::write(&path, content).unwrap();
+        let path = path.to_str().unwrap();
+
+        let threaded = backtest(path, false);
+        let single_threaded = backtest(path, true);
+        assert!(threaded.iter().filter(|line| line.starts_with("fill")).count() > 2);
+        assert_eq!(threaded, single_threaded);
+    }
+
+    #[test]
+    #[should_panic(expected = "Number of weights should equal number of strategies")]
+    fn test_single_threaded_validates_modules() {
+        // Checked as in StrategyManager::run, here with no strategy for the weight of 1
+        let mut event_manager = EventManager::new();
+        let mut strategy_manager = StrategyManager::new();
+        event_manager.subscribe::<MarketDataEvent, StrategyManager>(&strategy_manager);
+        event_manager.allow_publish("high".to_string(), &mut strategy_manager);
+        event_manager.proceed_sync(vec![Box::new(strategy_manager)], Vec::new());
+    }
+
+    // Buys 10 of every symbol the first time it sees one
+    struct BuyEverySymbol {
+        bought: HashSet<String>,
+    }
+
+    impl Strategy for BuyEverySymbol {
+        fn process(&mut self, market_data_event: MarketDataEvent) -> Option<Event> {
+            if !self.bought.insert(market_data_event.symbol.clone()) {
+                return None;
+            }
+            Some(Event::new_order_place(Order::Market(MarketOrder {
+                symbol: market_data_event.symbol,
+                amount: 10,
+                direction: OrderDirection::Buy,
+                time_in_force: TimeInForce::Gtc,
+            })))
+        }
+
+        fn update(&mut self, _portfolio: Portfolio) {}
+    }
+
+    #[test]
+    fn test_multi_symbol_backtest() {
+        let mut paths = Vec::new();
+        for (symbol, price) in [("A", 10.), ("B", 20.)] {
+            let path = std::env::temp_dir().join(format!("crab_event_manager_test_{}.csv", symbol));
+            let mut content = "timestamp,open,high,low,close,volume\n".to_string();
+            for day in 1..=3 {
+                content.push_str(&format!("2024-01-0{} 00:00:00,{},{},{},{},1000\n", day, price, price, price, price));
+            }
+            fs::write(&path, content).unwrap();
+            paths.push((symbol.to_string(), path.to_str().unwrap().to_string()));
+        }
+
+        let mut event_manager = EventManager::new();
+        let mut strategy_manager = StrategyManager::new();
+        strategy_manager.add_strategy(Box::new(BuyEverySymbol { bought: HashSet::new() }));
+        let mut mock_exchange = MockExchange::new(|_| 0., Box::new(MidPrice));
+        let recorder = TestModule::new();
+        event_manager.subscribe::<MarketDataBatchEvent, StrategyManager>(&strategy_manager);
+        event_manager.subscribe::<PortfolioInfoEvent, StrategyManager>(&strategy_manager);
+        event_manager.subscribe::<OrderFilledEvent, StrategyManager>(&strategy_manager);
+        event_manager.allow_publish("high".to_string(), &mut strategy_manager);
+        event_manager.subscribe::<MarketDataBatchEvent, MockExchange>(&mock_exchange);
+        event_manager.subscribe::<OrderPlaceEvent, MockExchange>(&mock_exchange);
+        event_manager.allow_publish("high".to_string(), &mut mock_exchange);
+        event_manager.subscribe::<MarketDataBatchEvent, TestModule>(&recorder);
+        event_manager.subscribe::<PortfolioInfoEvent, TestModule>(&recorder);
+        event_manager.subscribe::<OrderFilledEvent, TestModule>(&recorder);
+        let mut feeder = MarketDataFeederMulti::new(paths);
+        event_manager.allow_publish("low".to_string(), &mut feeder);
+
+        thread::spawn(move || mock_exchange.run());
+        thread::spawn(move || strategy_manager.run());
+        thread::spawn(move || feeder.start_feeding());
+        event_manager.proceed();
+
+        let events: Vec<Event> = recorder.receiver.try_iter().collect();
+        let batches: Vec<usize> = events
+            .iter()
+            .filter_map(|event| match event {
+                Event::MarketDataBatch(batch) => Some(batch.bars.len()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(batches, vec![2, 2, 2]);
+        // The modules settle after every batch: both orders placed on the first fill on the second
+        let mut fills: Vec<(String, i32, String)> = events
+            .iter()
+            .filter_map(|event| match event {
+                Event::OrderFilled(OrderFilledEvent { fill, .. }) => {
+                    Some((fill.symbol.clone(), fill.quantity, fill.timestamp.date_naive().to_string()))
                 }
-                // break;
+                _ => None,
+            })
+            .collect();
+        fills.sort();
+        assert_eq!(
+            fills,
+            vec![
+                ("A".to_string(), 10, "2024-01-02".to_string()),
+                ("B".to_string(), 10, "2024-01-02".to_string()),
+            ]
+        );
+        let portfolio = events
+            .iter()
+            .rev()
+            .find_map(|event| match event {
+                Event::PortfolioInfo(portfolio_info_event) => Some(&portfolio_info_event.portfolio),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(portfolio.positions["A"].quantity, 10);
+        assert_eq!(portfolio.positions["B"].quantity, 10);
+    }
+
+    fn bar(day: u32, symbol: &str, close: f64) -> MarketDataEvent {
+        MarketDataEvent::new(
+            format!("2024-01-0{}", day).parse().unwrap(),
+            symbol.to_string(),
+            close,
+            close,
+            close,
+            close,
+            100,
+        )
+    }
+
+    fn received_bars(receiver: &Receiver<Event>) -> Vec<String> {
+        receiver
+            .try_iter()
+            .flat_map(|event| match event {
+                Event::MarketData(bar) => vec![bar],
+                Event::MarketDataBatch(batch) => batch.bars,
+                _ => Vec::new(),
+            })
+            .map(|bar| format!("{} {}", bar.symbol, bar.timestamp.date_naive()))
+            .collect()
+    }
+
+    #[test]
+    fn test_filtered_subscriptions() {
+        let mut event_manager = EventManager::new();
+        let symbol_subscriber = TestModule::new();
+        let predicate_subscriber = TestModule::new();
+        let unsubscribed = TestModule::new();
+        let mut feeder = TestModule::new();
+        event_manager.subscribe_filtered::<MarketDataEvent, TestModule>(&symbol_subscriber, EventFilter::symbols(&["A"]));
+        event_manager
+            .subscribe_filtered::<MarketDataBatchEvent, TestModule>(&symbol_subscriber, EventFilter::symbols(&["A"]));
+        event_manager.subscribe_filtered::<MarketDataEvent, TestModule>(
+            &predicate_subscriber,
+            EventFilter::predicate(|event| matches!(event, Event::MarketData(bar) if bar.close > 1.5)),
+        );
+        event_manager.subscribe::<MarketDataEvent, TestModule>(&unsubscribed);
+        event_manager.subscribe::<MarketDataBatchEvent, TestModule>(&unsubscribed);
+        event_manager.unsubscribe::<MarketDataEvent, TestModule>(&unsubscribed);
+        event_manager.allow_publish("low".to_string(), &mut feeder);
+
+        let publish_sender = feeder.publish_sender.unwrap();
+        publish_sender.send(Event::MarketData(bar(1, "A", 1.))).unwrap();
+        publish_sender.send(Event::MarketData(bar(1, "B", 2.))).unwrap();
+        let timestamp = "2024-01-02".parse().unwrap();
+        publish_sender
+            .send(Event::new_market_data_batch(timestamp, vec![bar(2, "A", 1.), bar(2, "B", 2.), bar(2, "C", 3.)]))
+            .unwrap();
+        publish_sender.send(Event::new_end_of_data()).unwrap();
+
+        event_manager.proceed();
+
+        // Batches are cut down to the subscribed symbols
+        assert_eq!(received_bars(&symbol_subscriber.receiver), vec!["A 2024-01-01", "A 2024-01-02"]);
+        assert_eq!(received_bars(&predicate_subscriber.receiver), vec!["B 2024-01-01"]);
+        assert_eq!(
+            received_bars(&unsubscribed.receiver),
+            vec!["A 2024-01-02", "B 2024-01-02", "C 2024-01-02"]
+        );
+    }
+
+    // Publishes its events once it handled its first bar
+    struct Handover {
+        module: TestModule,
+        events: Vec<Event>,
+    }
+
+    impl ModuleReceive for Handover {
+        fn get_sender(&self) -> Sender<Event> {
+            self.module.get_sender()
+        }
+    }
+
+    impl ModuleHandle for Handover {
+        fn handle_event(&mut self, event: Event) {
+            self.module.handle_event(event);
+            let publish_sender = self.module.publish_sender.as_ref().unwrap();
+            for event in self.events.drain(..) {
+                publish_sender.send(event).unwrap();
             }
         }
     }
+
+    struct Bars(std::vec::IntoIter<Event>);
+
+    impl DataSource for Bars {
+        fn next_event(&mut self) -> Option<Event> {
+            self.0.next()
+        }
+    }
+
+    #[test]
+    fn test_subscribe_while_running() {
+        let mut event_manager = EventManager::new();
+        let successor = TestModule::new();
+        let mut handover = Handover {
+            module: TestModule::new(),
+            events: Vec::new(),
+        };
+        handover.events = vec![
+            Event::new_unsubscribe_all(handover.get_sender()),
+            Event::new_subscribe::<MarketDataEvent>(successor.get_sender(), Some(EventFilter::symbols(&["B"]))),
+        ];
+        event_manager.subscribe::<MarketDataEvent, Handover>(&handover);
+        event_manager.allow_publish("high".to_string(), &mut handover.module);
+        let handover_receiver = handover.module.receiver.clone();
+        let successor_receiver = successor.receiver.clone();
+
+        let bars: Vec<Event> = [(1, "A"), (2, "B"), (3, "A"), (4, "B")]
+            .iter()
+            .map(|&(day, symbol)| Event::MarketData(bar(day, symbol, 1.)))
+            .collect();
+        event_manager.proceed_sync(
+            vec![Box::new(handover), Box::new(successor)],
+            vec![Box::new(Bars(bars.into_iter()))],
+        );
+
+        // The subscriptions change before the next bar
+        assert_eq!(received_bars(&handover_receiver), vec!["A 2024-01-01"]);
+        assert_eq!(received_bars(&successor_receiver), vec!["B 2024-01-02", "B 2024-01-04"]);
+    }
+
+    #[test]
+    fn test_single_threaded_ignores_unknown_subscriber() {
+        // A module not passed to proceed_sync cannot handle events, its subscription is left out
+        let mut event_manager = EventManager::new();
+        let stranger = TestModule::new();
+        let mut handover = Handover {
+            module: TestModule::new(),
+            events: vec![Event::new_subscribe::<MarketDataEvent>(stranger.get_sender(), None)],
+        };
+        event_manager.subscribe::<MarketDataEvent, Handover>(&handover);
+        event_manager.allow_publish("high".to_string(), &mut handover.module);
+        let handover_receiver = handover.module.receiver.clone();
+
+        let bars: Vec<Event> = [(1, "A"), (2, "B")]
+            .iter()
+            .map(|&(day, symbol)| Event::MarketData(bar(day, symbol, 1.)))
+            .collect();
+        event_manager.proceed_sync(vec![Box::new(handover)], vec![Box::new(Bars(bars.into_iter()))]);
+
+        assert_eq!(received_bars(&handover_receiver), vec!["A 2024-01-01", "B 2024-01-02"]);
+        assert!(received_bars(&stranger.receiver).is_empty());
+    }
+
+    #[test]
+    fn test_subscribe_while_running_threaded() {
+        // The handover of test_subscribe_while_running, on its own thread
+        let mut event_manager = EventManager::new();
+        let successor = TestModule::new();
+        let (sender, receiver) = bounded(0);
+        let mut handover = TestModule {
+            sender,
+            receiver,
+            publish_sender: None,
+        };
+        let mut feeder = TestModule::new();
+        event_manager.subscribe::<MarketDataEvent, TestModule>(&handover);
+        event_manager.allow_publish("high".to_string(), &mut handover);
+        event_manager.allow_publish("low".to_string(), &mut feeder);
+
+        let successor_sender = successor.get_sender();
+        let handover_thread = thread::spawn(move || {
+            let TestModule {
+                sender,
+                receiver,
+                publish_sender,
+            } = handover;
+            let publish_sender = publish_sender.unwrap();
+            let mut sender = Some(sender);
+            let mut bars = Vec::new();
+            // Until the event_manager drops the last sender of the unsubscribed module
+            while let Ok(event) = receiver.recv() {
+                if let Event::MarketData(bar) = event {
+                    bars.push(format!("{} {}", bar.symbol, bar.timestamp.date_naive()));
+                }
+                if let Some(sender) = sender.take() {
+                    publish_sender.send(Event::new_unsubscribe_all(sender)).unwrap();
+                    publish_sender
+                        .send(Event::new_subscribe::<MarketDataEvent>(
+                            successor_sender.clone(),
+                            Some(EventFilter::symbols(&["B"])),
+                        ))
+                        .unwrap();
+                }
+            }
+            bars
+        });
+
+        let publish_sender = feeder.publish_sender.unwrap();
+        for (day, symbol) in [(1, "A"), (2, "B"), (3, "A"), (4, "B")] {
+            publish_sender.send(Event::MarketData(bar(day, symbol, 1.))).unwrap();
+        }
+        publish_sender.send(Event::new_end_of_data()).unwrap();
+
+        event_manager.proceed();
+
+        // The modules settle after every bar, so the subscriptions change before the next one
+        assert_eq!(handover_thread.join().unwrap(), vec!["A 2024-01-01"]);
+        assert_eq!(received_bars(&successor.receiver), vec!["B 2024-01-02", "B 2024-01-04"]);
+    }
+
+    #[test]
+    fn test_stray_end_of_data() {
+        let mut event_manager = EventManager::new();
+        let subscriber = TestModule::new();
+        event_manager.subscribe::<MarketDataEvent, TestModule>(&subscriber);
+        let receiver = subscriber.receiver.clone();
+
+        let events = vec![
+            Event::MarketData(bar(1, "A", 1.)),
+            Event::new_end_of_data(),
+            Event::MarketData(bar(2, "A", 1.)),
+        ];
+        event_manager.proceed_sync(vec![Box::new(subscriber)], vec![Box::new(Bars(events.into_iter()))]);
+
+        assert_eq!(received_bars(&receiver), vec!["A 2024-01-01", "A 2024-01-02"]);
+    }
 }
@@ -0,0 +1,172 @@
+/*
+Fill models decide the price a marketable order gets on a bar.
+The mock_exchange asks its fill model for the price of market orders and
+triggered stops, and caps it by the limit for limit orders.
+Slippage models wrap another model and move its price against the order,
+so they can be combined, e.g. FixedBps::new(Box::new(NextBarOpen), 5.).
+*/
+use crate::shared_structures::{MarketDataEvent, OrderDirection};
+
+pub trait FillModel {
+    /// Price a market order of `amount` in `direction` is filled at on `bar`.
+    fn fill_price(&self, bar: &MarketDataEvent, direction: OrderDirection, amount: i32) -> f64;
+}
+
+fn slip(price: f64, direction: OrderDirection, fraction: f64) -> f64 {
+    // Move a price against the order by a fraction of it
+    match direction {
+        OrderDirection::Buy => price * (1.0 + fraction),
+        OrderDirection::Sell => price * (1.0 - fraction),
+    }
+}
+
+// Mean of high and low, the original behavior of the mock_exchange
+pub struct MidPrice;
+
+impl FillModel for MidPrice {
+    fn fill_price(&self, bar: &MarketDataEvent, _: OrderDirection, _: i32) -> f64 {
+        (bar.high + bar.low) / 2.0
+    }
+}
+
+/*
+Open of the bar. Orders are matched against the bar after the one they were
+placed on, so this is the open of the next bar.
+*/
+pub struct NextBarOpen;
+
+impl FillModel for NextBarOpen {
+    fn fill_price(&self, bar: &MarketDataEvent, _: OrderDirection, _: i32) -> f64 {
+        bar.open
+    }
+}
+
+pub struct Close;
+
+impl FillModel for Close {
+    fn fill_price(&self, bar: &MarketDataEvent, _: OrderDirection, _: i32) -> f64 {
+        bar.close
+    }
+}
+
+// Typical price (high + low + close) / 3 as a proxy for the bar's VWAP
+pub struct VwapProxy;
+
+impl FillModel for VwapProxy {
+    fn fill_price(&self, bar: &MarketDataEvent, _: OrderDirection, _: i32) -> f64 {
+        (bar.high + bar.low + bar.close) / 3.0
+    }
+}
+
+// Fixed slippage in basis points of the base price
+pub struct FixedBps {
+    base: Box<dyn FillModel + Send>,
+    bps: f64,
+}
+
+impl FixedBps {
+    pub fn new(base: Box<dyn FillModel + Send>, bps: f64) -> Self {
+        FixedBps { base, bps }
+    }
+}
+
+impl FillModel for FixedBps {
+    fn fill_price(&self, bar: &MarketDataEvent, direction: OrderDirection, amount: i32) -> f64 {
+        let price = self.base.fill_price(bar, direction, amount);
+        slip(price, direction, self.bps / 10_000.0)
+    }
+}
+
+// Slippage of a fraction of the bar's high-low range
+pub struct VolatilityScaled {
+    base: Box<dyn FillModel + Send>,
+    range_fraction: f64,
+}
+
+impl VolatilityScaled {
+    pub fn new(base: Box<dyn FillModel + Send>, range_fraction: f64) -> Self {
+        VolatilityScaled {
+            base,
+            range_fraction,
+        }
+    }
+}
+
+impl FillModel for VolatilityScaled {
+    fn fill_price(&self, bar: &MarketDataEvent, direction: OrderDirection, amount: i32) -> f64 {
+        let price = self.base.fill_price(bar, direction, amount);
+        let slippage = self.range_fraction * (bar.high - bar.low);
+        match direction {
+            OrderDirection::Buy => price + slippage,
+            OrderDirection::Sell => price - slippage,
+        }
+    }
+}
+
+/*
+Square-root market impact: the price moves by
+coefficient * volatility * sqrt(amount / volume),
+with the bar's relative range (high - low) / close as the volatility.
+*/
+pub struct SquareRootImpact {
+    base: Box<dyn FillModel + Send>,
+    coefficient: f64,
+}
+
+impl SquareRootImpact {
+    pub fn new(base: Box<dyn FillModel + Send>, coefficient: f64) -> Self {
+        SquareRootImpact { base, coefficient }
+    }
+}
+
+impl FillModel for SquareRootImpact {
+    fn fill_price(&self, bar: &MarketDataEvent, direction: OrderDirection, amount: i32) -> f64 {
+        let price = self.base.fill_price(bar, direction, amount);
+        if bar.volume <= 0 || bar.close <= 0.0 {
+            return price;
+        }
+        let volatility = (bar.high - bar.low) / bar.close;
+        let participation = amount.abs() as f64 / bar.volume as f64;
+        slip(price, direction, self.coefficient * volatility * participation.sqrt())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bar() -> MarketDataEvent {
+        MarketDataEvent::new(
+            "2024-01-02 04:00:00".parse().unwrap(),
+            "TEST".to_string(),
+            100.,
+            104.,
+            105.,
+            95.,
+            10_000,
+        )
+    }
+
+    #[test]
+    fn test_base_models() {
+        assert_eq!(MidPrice.fill_price(&bar(), OrderDirection::Buy, 100), 100.);
+        assert_eq!(NextBarOpen.fill_price(&bar(), OrderDirection::Buy, 100), 100.);
+        assert_eq!(Close.fill_price(&bar(), OrderDirection::Buy, 100), 104.);
+        assert_eq!(VwapProxy.fill_price(&bar(), OrderDirection::Buy, 100), 304. / 3.);
+    }
+
+    #[test]
+    fn test_slippage_models() {
+        let fixed_bps = FixedBps::new(Box::new(NextBarOpen), 10.);
+        assert!((fixed_bps.fill_price(&bar(), OrderDirection::Buy, 100) - 100.1).abs() < 1e-9);
+        assert!((fixed_bps.fill_price(&bar(), OrderDirection::Sell, 100) - 99.9).abs() < 1e-9);
+
+        let volatility_scaled = VolatilityScaled::new(Box::new(NextBarOpen), 0.1);
+        assert!((volatility_scaled.fill_price(&bar(), OrderDirection::Buy, 100) - 101.).abs() < 1e-9);
+
+        // volatility 10 / 104, participation 1%
+        let impact = SquareRootImpact::new(Box::new(NextBarOpen), 1.);
+        let expected = 100. * (1. - 10. / 104. * 0.1);
+        assert!((impact.fill_price(&bar(), OrderDirection::Sell, 100) - expected).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,488 @@
+/*
+Journal of the events of a run, to replay it later.
+The EventRecorder receives every event the event_manager dispatches and
+writes them, in the order they were dispatched, to a compact binary file:
+a header with a magic string and the format version, then the events one
+after the other, serialized with bincode. Barriers and custom events are
+left out, and the ShutDownEvent closes the journal.
+The JournalReplayFeeder reads a journal and publishes its events again
+through the event_manager: the market data, the portfolio snapshots the
+exchange sent and the order events, so a strategy can be debugged against
+exactly the sequence it saw.
+*/
+use crate::event_manager::{DataSource, ModuleHandle, ModulePublish, ModuleReceive};
+use crate::shared_structures::*;
+use crossbeam::channel::{unbounded, Receiver, Sender};
+use simplelog::*;
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::time::Duration;
+
+const MAGIC: &[u8; 8] = b"CRABJRNL";
+// How long to wait for the replayed strategy to place an order of the journal
+const ORDER_TIMEOUT: Duration = Duration::from_secs(1);
+// Bumped whenever the serialized events change, older journals are refused
+pub const JOURNAL_VERSION: u32 = 2;
+
+pub struct EventRecorder {
+    subscribe_sender: Sender<Event>,
+    subscribe_receiver: Receiver<Event>,
+    path: String,
+    // Opened on the first event, closed on shut down
+    writer: Option<BufWriter<File>>,
+    recorded: usize,
+}
+
+impl ModuleReceive for EventRecorder {
+    fn get_sender(&self) -> Sender<Event> {
+        self.subscribe_sender.clone()
+    }
+}
+
+impl ModuleHandle for EventRecorder {
+    fn handle_event(&mut self, event: Event) {
+        match event {
+            Event::Barrier(_) => {}
+            Event::Custom(custom_event) => {
+                debug!("Journal: custom event {} not recorded", custom_event.type_name);
+            }
+            Event::ShutDown(_) => {
+                if let Some(mut writer) = self.writer.take() {
+                    writer.flush().expect("Failed to write the journal");
+                }
+                info!("Journal of {} events written to {}", self.recorded, self.path);
+            }
+            event => {
+                let writer = self.writer.get_or_insert_with(|| create_journal(&self.path));
+                bincode::serialize_into(writer, &event).expect("Failed to write the journal");
+                self.recorded += 1;
+            }
+        }
+    }
+}
+
+impl EventRecorder {
+    pub fn new(path: String) -> Self {
+        // Unbounded, so recording never holds up the other modules
+        let (subscribe_sender, subscribe_receiver) = unbounded();
+        EventRecorder {
+            subscribe_sender,
+            subscribe_receiver,
+            path,
+            writer: None,
+            recorded: 0,
+        }
+    }
+
+    pub fn run(&mut self) {
+        loop {
+            let event = self.subscribe_receiver.recv().unwrap();
+            let shut_down = matches!(event, Event::ShutDown(_));
+            self.handle_event(event);
+            if shut_down {
+                break;
+            }
+        }
+    }
+}
+
+fn create_journal(path: &str) -> BufWriter<File> {
+    if let Some(parent) = Path::new(path).parent() {
+        fs::create_dir_all(parent).expect("Failed to create the journal directory");
+    }
+    let mut writer = BufWriter::new(File::create(path).expect("Failed to create the journal"));
+    writer.write_all(MAGIC).expect("Failed to write the journal");
+    writer
+        .write_all(&JOURNAL_VERSION.to_le_bytes())
+        .expect("Failed to write the journal");
+    writer
+}
+
+/*
+Publishes the events of a journal as a low-priority publisher, in place of
+the market data feeder. Modules subscribe to them as in a backtest: usually
+the strategy_manager alone, since the portfolio snapshots and order events
+come from the journal rather than from an exchange.
+What the strategy produced is not replayed, it produces it again. Its order
+requests are left out. Its timers are fired by the clock, which the
+TimerEvents of the journal move to the time they fired.
+The orders it places get new ids, so the order events of the journal are
+pointed at them: the n-th order placed in the replay stands for the n-th
+order of the journal. The ReplayOrderListener passes the orders of the
+replay on, subscribe it to OrderPlaceEvent. Without one the order events
+keep the ids of the recording.
+*/
+pub struct JournalReplayFeeder {
+    publish_sender: Option<Sender<Event>>,
+    reader: BufReader<File>,
+    // Orders the ReplayOrderListener receives, None until it is created
+    order_receiver: Option<Receiver<Event>>,
+    // Orders of the journal not paired with an order of the replay yet
+    journal_orders: VecDeque<u64>,
+    // Id of the replay order standing for each order of the journal, None if it was not placed again
+    order_ids: HashMap<u64, Option<u64>>,
+}
+
+impl ModulePublish for JournalReplayFeeder {
+    fn use_sender(&mut self, sender: Sender<Event>) {
+        self.publish_sender = Some(sender);
+    }
+}
+
+impl DataSource for JournalReplayFeeder {
+    fn next_event(&mut self) -> Option<Event> {
+        loop {
+            let at_end = self
+                .reader
+                .fill_buf()
+                .expect("Failed to read the journal")
+                .is_empty();
+            if at_end {
+                return None;
+            }
+            let event = bincode::deserialize_from(&mut self.reader).expect("Corrupt journal");
+            if let Some(event) = self.replay(event) {
+                return Some(event);
+            }
+        }
+    }
+}
+
+/*
+Receives the OrderPlaceEvents of the replay for the JournalReplayFeeder,
+which is a publisher only. In the single-threaded mode pass it to
+proceed_sync with the other modules.
+*/
+pub struct ReplayOrderListener {
+    sender: Sender<Event>,
+}
+
+impl ModuleReceive for ReplayOrderListener {
+    fn get_sender(&self) -> Sender<Event> {
+        self.sender.clone()
+    }
+}
+
+impl ModuleHandle for ReplayOrderListener {
+    fn handle_event(&mut self, event: Event) {
+        self.sender.send(event).unwrap();
+    }
+}
+
+impl JournalReplayFeeder {
+    pub fn new(path: &str) -> Result<Self, String> {
+        // Fails if the file is not a journal, or one of another version
+        let file = File::open(path).map_err(|err| format!("Failed to open journal {}: {}", path, err))?;
+        let mut reader = BufReader::new(file);
+        let mut header = [0u8; 12];
+        reader
+            .read_exact(&mut header)
+            .map_err(|_| format!("{} is not an event journal", path))?;
+        if &header[..8] != MAGIC {
+            return Err(format!("{} is not an event journal", path));
+        }
+        let version = u32::from_le_bytes(header[8..].try_into().unwrap());
+        if version != JOURNAL_VERSION {
+            return Err(format!(
+                "Journal {} has version {}, expected {}",
+                path, version, JOURNAL_VERSION
+            ));
+        }
+        Ok(JournalReplayFeeder {
+            publish_sender: None,
+            reader,
+            order_receiver: None,
+            journal_orders: VecDeque::new(),
+            order_ids: HashMap::new(),
+        })
+    }
+
+    pub fn order_listener(&mut self) -> ReplayOrderListener {
+        // Unbounded, the orders wait there until an event of the journal needs them
+        let (sender, receiver) = unbounded();
+        self.order_receiver = Some(receiver);
+        ReplayOrderListener { sender }
+    }
+
+    fn replay(&mut self, mut event: Event) -> Option<Event> {
+        // The event to publish for an event of the journal, None to leave it out
+        match &mut event {
+            Event::OrderPlace(order_place_event) => {
+                self.journal_orders.push_back(order_place_event.order_id);
+                return None;
+            }
+            Event::OrderCancel(_) | Event::OrderModify(_) => return None,
+            Event::PortfolioInfo(portfolio_info_event) => {
+                let open_orders = std::mem::take(&mut portfolio_info_event.portfolio.open_orders);
+                portfolio_info_event.portfolio.open_orders = open_orders
+                    .into_iter()
+                    .filter_map(|mut open_order| {
+                        open_order.order_id = self.replay_order_id(open_order.order_id)?;
+                        Some(open_order)
+                    })
+                    .collect();
+            }
+            event => {
+                if let Some(order_id) = event.order_id_mut() {
+                    *order_id = self.replay_order_id(*order_id)?;
+                }
+            }
+        }
+        Some(event)
+    }
+
+    fn replay_order_id(&mut self, journal_order_id: u64) -> Option<u64> {
+        // Pair the orders of the journal with those of the replay, in the order they were placed
+        let Some(order_receiver) = &self.order_receiver else {
+            return Some(journal_order_id);
+        };
+        while !self.order_ids.contains_key(&journal_order_id) {
+            let Some(next_journal_order_id) = self.journal_orders.pop_front() else {
+                warn!("Replay: order {} is not in the journal, its event is left out", journal_order_id);
+                return None;
+            };
+            let replay_order_id = loop {
+                match order_receiver.recv_timeout(ORDER_TIMEOUT) {
+                    Ok(Event::OrderPlace(order_place_event)) => break Some(order_place_event.order_id),
+                    // Barriers of the threaded mode
+                    Ok(_) => continue,
+                    Err(_) => {
+                        warn!(
+                            "Replay: the strategy did not place order {} again, its events are left out",
+                            next_journal_order_id
+                        );
+                        break None;
+                    }
+                }
+            };
+            self.order_ids.insert(next_journal_order_id, replay_order_id);
+        }
+        self.order_ids[&journal_order_id]
+    }
+
+    fn publish(&self, event: Event) {
+        if let Some(publish_sender) = &self.publish_sender {
+            publish_sender.send(event).unwrap();
+        } else {
+            panic!("publish_sender is not initialized!");
+        }
+    }
+
+    pub fn start_feeding(&mut self) {
+        while let Some(event) = self.next_event() {
+            debug!("Replay event: {:?}", event);
+            self.publish(event);
+        }
+
+        // Tell the event manager this feeder is done
+        self.publish(Event::new_end_of_data());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event_manager::EventManager;
+    use crate::fill_model::MidPrice;
+    use crate::market_data_feeder::MarketDataFeederLocal;
+    use crate::mock_exchange::MockExchange;
+    use crate::strategy_manager::{Strategy, StrategyManager};
+    use chrono::FixedOffset;
+    use std::sync::{Arc, Mutex};
+
+    fn journal_path(name: &str) -> String {
+        std::env::temp_dir().join(name).to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_record_and_replay() {
+        let path = journal_path("crab_journal_test.bin");
+        let new_york = FixedOffset::west_opt(5 * 3600).unwrap();
+        let timestamp = TimestampFormat::new("%Y-%m-%d %H:%M:%S", new_york)
+            .parse("2024-01-02 09:30:00")
+            .unwrap();
+        let bar = MarketDataEvent::new(timestamp, "TEST".to_string(), 10., 10.5, 11., 9.5, 1000);
+        let order = Event::new_order_place(Order::LimitPrice(LimitPriceOrder {
+            symbol: "TEST".to_string(),
+            amount: 10,
+            limit_price: 10.2,
+            direction: OrderDirection::Buy,
+            time_in_force: TimeInForce::Gtd(timestamp),
+        }));
+        let fill = Fill {
+            timestamp,
+            symbol: "TEST".to_string(),
+            direction: OrderDirection::Buy,
+            quantity: 10,
+            price: 10.2,
+            fee: 0.1 + 0.2,
+        };
+        let mut portfolio = Portfolio::new(1000.);
+        portfolio.cash -= 102.3;
+        let realized_pnl = portfolio.positions.entry("TEST".to_string()).or_default().apply_fill(&fill);
+        let ledger_entries = vec![LedgerEntry {
+            fill: fill.clone(),
+            realized_pnl,
+        }];
+        let events = [
+            Event::MarketData(bar),
+            Event::new_order_accepted(order.order_id().unwrap()),
+            Event::new_order_filled(order.order_id().unwrap(), fill),
+            Event::new_portfolio_info(portfolio, ledger_entries),
+            Event::new_timer(7, "close".to_string(), timestamp),
+        ];
+
+        let mut recorder = EventRecorder::new(path.clone());
+        recorder.handle_event(events[0].clone());
+        // Recorded, but left for the replayed strategy to place again
+        recorder.handle_event(order.clone());
+        for event in events[1..].iter().cloned() {
+            recorder.handle_event(event);
+        }
+        // Neither barriers nor custom events are recorded
+        recorder.handle_event(Event::new_barrier());
+        recorder.handle_event(Event::new_custom(0u8));
+        recorder.handle_event(Event::new_shut_down());
+
+        let (sender, receiver) = unbounded();
+        let mut feeder = JournalReplayFeeder::new(&path).unwrap();
+        feeder.use_sender(sender);
+        feeder.start_feeding();
+        let replayed: Vec<Event> = receiver.try_iter().collect();
+
+        // Every field survives, the timezone of the timestamps and the bits of the floats included.
+        // Without an order listener the order events keep the ids of the recording
+        assert_eq!(replayed.len(), events.len() + 1);
+        for (event, replayed) in events.iter().zip(&replayed) {
+            assert_eq!(format!("{:?}", event), format!("{:?}", replayed));
+        }
+        match &replayed[0] {
+            Event::MarketData(bar) => assert_eq!(bar.timestamp.offset(), &new_york),
+            _ => unreachable!(),
+        }
+        assert!(matches!(replayed[events.len()], Event::EndOfData(_)));
+    }
+
+    // Buys 1 on a daily timer, logs what it sees with its orders numbered in the order placed
+    struct TimedBuyer {
+        log: Arc<Mutex<Vec<String>>>,
+        scheduled: bool,
+        placed: Vec<u64>,
+    }
+
+    impl Strategy for TimedBuyer {
+        fn process(&mut self, market_data_event: MarketDataEvent) -> Option<Event> {
+            if self.scheduled {
+                return None;
+            }
+            self.scheduled = true;
+            Some(Event::new_schedule_timer(
+                "buy".to_string(),
+                market_data_event.timestamp,
+                Some(chrono::Duration::days(1)),
+            ))
+        }
+
+        fn update(&mut self, _portfolio: Portfolio) {}
+
+        fn on_order_event(&mut self, order_event: Event) {
+            let order_id = order_event.order_id().unwrap();
+            let number = self.placed.iter().position(|&placed| placed == order_id).unwrap();
+            let kind = match order_event {
+                Event::OrderAccepted(_) => "accepted",
+                Event::OrderFilled(_) => "filled",
+                _ => "other",
+            };
+            self.log.lock().unwrap().push(format!("{} #{}", kind, number));
+        }
+
+        fn on_timer(&mut self, timer_event: TimerEvent) -> Vec<Event> {
+            let order = Event::new_order_place(Order::Market(MarketOrder {
+                symbol: "TEST".to_string(),
+                amount: 1,
+                direction: OrderDirection::Buy,
+                time_in_force: TimeInForce::Gtc,
+            }));
+            self.placed.push(order.order_id().unwrap());
+            self.log
+                .lock()
+                .unwrap()
+                .push(format!("timer {}, place #{}", timer_event.timestamp, self.placed.len() - 1));
+            vec![order]
+        }
+    }
+
+    fn timed_buyer(event_manager: &mut EventManager, log: &Arc<Mutex<Vec<String>>>) -> StrategyManager {
+        let mut strategy_manager = StrategyManager::new();
+        strategy_manager.add_strategy(Box::new(TimedBuyer {
+            log: log.clone(),
+            scheduled: false,
+            placed: Vec::new(),
+        }));
+        event_manager.subscribe::<MarketDataEvent, StrategyManager>(&strategy_manager);
+        event_manager.subscribe::<TimerEvent, StrategyManager>(&strategy_manager);
+        event_manager.subscribe::<OrderAcceptedEvent, StrategyManager>(&strategy_manager);
+        event_manager.subscribe::<OrderFilledEvent, StrategyManager>(&strategy_manager);
+        event_manager.allow_publish("high".to_string(), &mut strategy_manager);
+        strategy_manager
+    }
+
+    #[test]
+    fn test_replay_backtest() {
+        let csv_path = std::env::temp_dir().join("crab_journal_test.csv");
+        let mut content = "timestamp,open,high,low,close,volume\n".to_string();
+        for day in 1..=5 {
+            content.push_str(&format!("2024-01-0{} 00:00:00,10,11,9,10,100000\n", day));
+        }
+        fs::write(&csv_path, content).unwrap();
+        let csv_path = csv_path.to_str().unwrap().to_string();
+        let path = journal_path("crab_journal_test_backtest.bin");
+
+        // Record a backtest
+        let recorded = Arc::new(Mutex::new(Vec::new()));
+        let mut event_manager = EventManager::new();
+        let strategy_manager = timed_buyer(&mut event_manager, &recorded);
+        let mut mock_exchange = MockExchange::new(|_| 0., Box::new(MidPrice));
+        let event_recorder = EventRecorder::new(path.clone());
+        event_manager.subscribe::<MarketDataEvent, MockExchange>(&mock_exchange);
+        event_manager.subscribe::<OrderPlaceEvent, MockExchange>(&mock_exchange);
+        event_manager.allow_publish("high".to_string(), &mut mock_exchange);
+        event_manager.subscribe_all(&event_recorder);
+        event_manager.proceed_sync(
+            vec![Box::new(strategy_manager), Box::new(mock_exchange), Box::new(event_recorder)],
+            vec![Box::new(MarketDataFeederLocal::new("TEST".to_string(), csv_path))],
+        );
+
+        // Replay it to the same strategy, whose orders get new ids
+        let replayed = Arc::new(Mutex::new(Vec::new()));
+        let mut event_manager = EventManager::new();
+        let strategy_manager = timed_buyer(&mut event_manager, &replayed);
+        let mut feeder = JournalReplayFeeder::new(&path).unwrap();
+        let order_listener = feeder.order_listener();
+        event_manager.subscribe::<OrderPlaceEvent, ReplayOrderListener>(&order_listener);
+        event_manager.proceed_sync(
+            vec![Box::new(strategy_manager), Box::new(order_listener)],
+            vec![Box::new(feeder)],
+        );
+
+        // Every timer fires once, and the events of every order reach the strategy
+        let recorded = recorded.lock().unwrap().clone();
+        assert_eq!(recorded.iter().filter(|line| line.starts_with("timer")).count(), 5);
+        assert!(recorded.contains(&"filled #3".to_string()));
+        assert_eq!(*replayed.lock().unwrap(), recorded);
+    }
+
+    #[test]
+    fn test_refuse_other_files() {
+        let path = journal_path("crab_journal_test_version.bin");
+        let mut content = MAGIC.to_vec();
+        content.extend_from_slice(&(JOURNAL_VERSION + 1).to_le_bytes());
+        fs::write(&path, content).unwrap();
+        assert!(JournalReplayFeeder::new(&path).err().unwrap().contains("version"));
+
+        fs::write(&path, "timestamp,open,high,low,close,volume\n").unwrap();
+        assert!(JournalReplayFeeder::new(&path).err().unwrap().contains("not an event journal"));
+    }
+}
@@ -0,0 +1,15 @@
+pub mod clock;
+pub mod data_analyzer;
+pub mod event_manager;
+pub mod fill_model;
+pub mod journal;
+pub mod market_data_feeder;
+pub mod mock_exchange;
+pub mod robustness;
+pub mod shared_structures;
+pub mod strategies;
+pub mod strategy_manager;
+pub mod tearsheet;
+mod util;
+
+use shared_structures::*;
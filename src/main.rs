@@ -1,22 +1,17 @@
-mod data_analyzer;
-mod event_manager;
-mod market_data_feeder;
-mod mock_exchange;
-mod shared_structures;
-mod strategies;
-mod strategy_manager;
-mod util;
-
-use crate::event_manager::EventManager;
-
-use market_data_feeder::MarketDataFeederLocal;
-use mock_exchange::MockExchange;
-use data_analyzer::DataAnalyzer;
-use strategy_manager::StrategyManager;
-use shared_structures::*;
+use crab::event_manager::EventManager;
+
+use crab::fill_model::MidPrice;
+use crab::journal::EventRecorder;
+use crab::market_data_feeder::{MarketDataFeeder, MarketDataFeederLocal, MarketDataFeederMulti};
+use crab::mock_exchange::MockExchange;
+use crab::data_analyzer::{Benchmark, DataAnalyzer, MetricsConfig};
+use crab::robustness::RobustnessConfig;
+use crab::strategy_manager::StrategyManager;
+use crab::shared_structures::*;
 use std::thread;
-use strategies::moving_average_crossover::MAcross;
+use crab::strategies::moving_average_crossover::MAcross;
 
+use chrono::FixedOffset;
 use simplelog::*;
 use std::fs::File;
 
@@ -50,9 +45,25 @@ fn main() {
     let mut strategy_manager = StrategyManager::new();
     strategy_manager.add_strategy(Box::new(strategy_ma_cross));
 
-    // Let strategy_manager subscribe to MarketDataEvent and PortfolioInfoEvent.
+    // Let strategy_manager subscribe to market data and PortfolioInfoEvent.
+    // In a large universe subscribe_filtered keeps only the symbols traded, e.g.
+    // event_manager.subscribe_filtered::<MarketDataBatchEvent, StrategyManager>(&strategy_manager, EventFilter::symbols(&["AAPL", "MSFT"]))
     event_manager.subscribe::<MarketDataEvent, StrategyManager>(&strategy_manager);
+    event_manager.subscribe::<MarketDataBatchEvent, StrategyManager>(&strategy_manager);
     event_manager.subscribe::<PortfolioInfoEvent, StrategyManager>(&strategy_manager);
+    // Order lifecycle events are routed back to the strategy that placed the order.
+    event_manager.subscribe::<OrderAcceptedEvent, StrategyManager>(&strategy_manager);
+    event_manager.subscribe::<OrderFilledEvent, StrategyManager>(&strategy_manager);
+    event_manager.subscribe::<OrderPartiallyFilledEvent, StrategyManager>(&strategy_manager);
+    event_manager.subscribe::<OrderRejectedEvent, StrategyManager>(&strategy_manager);
+    event_manager.subscribe::<OrderCancelledEvent, StrategyManager>(&strategy_manager);
+    event_manager.subscribe::<OrderModifiedEvent, StrategyManager>(&strategy_manager);
+    event_manager.subscribe::<OrderCancelRejectedEvent, StrategyManager>(&strategy_manager);
+    event_manager.subscribe::<OrderModifyRejectedEvent, StrategyManager>(&strategy_manager);
+    event_manager.subscribe::<MarginCallEvent, StrategyManager>(&strategy_manager);
+    // Timers the strategies schedule, e.g. a daily rebalance with
+    // event_manager.schedule_timer("rebalance".to_string(), first_time, Some(chrono::Duration::days(1)))
+    event_manager.subscribe::<TimerEvent, StrategyManager>(&strategy_manager);
     // Allow strategy_manager to publish events.
     event_manager.allow_publish("high".to_string(), &mut strategy_manager);
 
@@ -65,26 +76,96 @@ fn main() {
         // 0.1% percentage and 0 fixed fee
         trade_cost * 0.001+ 0.0
     }
-    let mut mock_exchange: MockExchange = MockExchange::new(fee_function);
-    // Let event_manager subscribe to MarketDataEvent and OrderPlaceEvent.
+    /*
+    Fill model for the price of market orders and triggered stops.
+    See fill_model.rs for the others, e.g. NextBarOpen, Close, VwapProxy,
+    or a slippage model wrapping one of them such as
+    FixedBps::new(Box::new(NextBarOpen), 5.)
+    */
+    let fill_model = Box::new(MidPrice);
+    let mut mock_exchange: MockExchange = MockExchange::new(fee_function, fill_model);
+    /*
+    By default orders fill in full and sells are limited to the shares held. To fill at most 10%
+    of a bar's volume, the rest of an order carrying over to later bars:
+    mock_exchange.set_max_participation(0.1);
+    To allow short selling under Reg-T like margins with a 3% annual borrow rate:
+    mock_exchange.set_margin(MarginConfig { initial_margin: 0.5, maintenance_margin: 0.3, borrow_rate: 0.03 });
+    */
+    // Let mock_exchange subscribe to market data and order requests.
     event_manager.subscribe::<MarketDataEvent, MockExchange>(&mock_exchange);
+    event_manager.subscribe::<MarketDataBatchEvent, MockExchange>(&mock_exchange);
     event_manager.subscribe::<OrderPlaceEvent, MockExchange>(&mock_exchange);
+    event_manager.subscribe::<OrderCancelEvent, MockExchange>(&mock_exchange);
+    event_manager.subscribe::<OrderModifyEvent, MockExchange>(&mock_exchange);
     // Allow event_manager to publish events.
     event_manager.allow_publish("high".to_string(), &mut mock_exchange);
 
     // Initialize market_data_feeder.
-    let mut market_data_feeder =
-        MarketDataFeederLocal::new("TSLA".to_string(), "./data/TSLA_DAY_10Y.csv".to_string());
-    // Allow the market data feeder to publish low-priority events
-    event_manager.allow_publish("low".to_string(), &mut market_data_feeder);
+    // (symbol, csv_path) pairs to trade; more than one are merged into MarketDataBatchEvents
+    let universe = vec![("TSLA".to_string(), "./data/TSLA_DAY_10Y.csv".to_string())];
+    let mut market_data_feeder: Box<dyn MarketDataFeeder> = if universe.len() == 1 {
+        let (symbol, csv_path) = universe[0].clone();
+        Box::new(MarketDataFeederLocal::new(symbol, csv_path))
+    } else {
+        Box::new(MarketDataFeederMulti::new(universe))
+    };
+    // Timestamps of the csv, as a chrono format in the timezone of the data
+    let timestamp_format = TimestampFormat::new("%Y-%m-%d %H:%M:%S", FixedOffset::east_opt(0).unwrap());
+    market_data_feeder.set_timestamp_format(timestamp_format.clone());
 
     // Initialize a data_analyzer
     let mut data_analyzer = DataAnalyzer::new();
+    // JSON summary and CSV files of the run are written here
+    data_analyzer.set_output_dir("./output".to_string());
+    // The benchmark can also be another symbol, or an index with
+    // Benchmark::Csv("path/to/index.csv".to_string(), timestamp_format.clone())
+    data_analyzer
+        .set_metrics_config(MetricsConfig {
+            risk_free_rate: 0.05,
+            periods_per_year: None, // inferred from the bar interval
+            benchmark: Benchmark::FirstSymbol,
+            var_confidence_levels: vec![0.95, 0.99],
+            rolling_windows: vec![63, 252],
+        })
+        .expect("Invalid metrics configuration");
+    // Resample the run to get confidence intervals and the probability of losing half the capital
+    data_analyzer.set_robustness_config(RobustnessConfig {
+        simulations: 1000,
+        block_size: 21,
+        confidence: 0.95,
+        ruin_threshold: 0.5,
+        seed: 42,
+    });
     // Let the data analyzer subscribe to all event types it needs
     event_manager.subscribe::<MarketDataEvent, DataAnalyzer>(&data_analyzer);
+    event_manager.subscribe::<MarketDataBatchEvent, DataAnalyzer>(&data_analyzer);
     event_manager.subscribe::<PortfolioInfoEvent, DataAnalyzer>(&data_analyzer);
     event_manager.subscribe::<ShutDownEvent, DataAnalyzer>(&data_analyzer);
 
+    // Record every event of the run, to replay it with journal::JournalReplayFeeder
+    let mut event_recorder = EventRecorder::new("./output/journal.bin".to_string());
+    event_manager.subscribe_all::<EventRecorder>(&event_recorder);
+
+    /*
+    Run every module on its own thread, or call them all in turn on this
+    thread. The single-threaded mode gives the same result, deterministically
+    and much faster, which suits backtests and parameter sweeps.
+    */
+    let single_threaded = false;
+    if single_threaded {
+        info!("Modules initialized, start data feeding on a single thread ...");
+        event_manager.proceed_sync(
+            vec![
+                Box::new(strategy_manager),
+                Box::new(mock_exchange),
+                Box::new(data_analyzer),
+                Box::new(event_recorder),
+            ],
+            vec![market_data_feeder],
+        );
+        return;
+    }
+
     // Run modules
     let _mock_exchange_thread = thread::spawn(move || {
         mock_exchange.run();
@@ -98,6 +179,13 @@ fn main() {
         data_analyzer.run();
     });
 
+    let _event_recorder_thread = thread::spawn(move || {
+        event_recorder.run();
+    });
+
+    // Allow the market data feeder to publish low-priority events
+    event_manager.allow_publish("low".to_string(), &mut *market_data_feeder);
+
     // Start feeding data
     let _market_data_feeder_thread = thread::spawn(move || {
         market_data_feeder.start_feeding();
@@ -108,5 +196,5 @@ fn main() {
     );
     event_manager.proceed();
     _data_analyzer_thread.join().unwrap();
-    return;
+    _event_recorder_thread.join().unwrap();
 }
@@ -1,16 +1,73 @@
-use crate::event_manager::ModulePublish;
+use crate::event_manager::{DataSource, ModulePublish};
 use crate::shared_structures::*;
 
 use crossbeam::channel::Sender;
-use csv::ReaderBuilder;
+use csv::{ReaderBuilder, StringRecord, StringRecordsIntoIter};
 use simplelog::*;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
 use std::fs::File;
 use std::thread;
 
+fn parse_record(record: &StringRecord, symbol: &str, timestamp_format: &TimestampFormat) -> MarketDataEvent {
+    // Parse the timestamp
+    let timestamp = timestamp_format
+        .parse(&record[0])
+        .expect("Invalid timestamp value");
+
+    // Parse the Open price
+    let open: f64 = record[1]
+        .parse()
+        .expect("Invalid open value");
+
+    // Parse the High price
+    let high: f64 = record[2]
+        .parse()
+        .expect("Invalid high value");
+
+    // Parse the Low price
+    let low: f64 = record[3]
+        .parse()
+        .expect("Invalid low value");
+
+    // Parse the Close price
+    let close: f64 = record[4]
+        .parse()
+        .expect("Invalid close value");
+
+    // Parse the Volume
+    let volume: i32 = record[5]
+        .parse()
+        .expect("Invalid volume value");
+
+    MarketDataEvent::new(
+        timestamp,
+        symbol.to_string(),
+        open,
+        close,
+        high,
+        low,
+        volume,
+    )
+}
+
+/*
+What main needs of a feeder of either kind: one symbol fed bar by bar, or
+several merged into batches. It publishes from its own thread with
+start_feeding, or is read with next_event in the single-threaded mode.
+*/
+pub trait MarketDataFeeder: ModulePublish + DataSource + Send {
+    fn set_timestamp_format(&mut self, timestamp_format: TimestampFormat);
+    fn start_feeding(&mut self);
+}
+
 pub struct MarketDataFeederLocal {
     publish_sender: Option<Sender<Event>>,
     csv_path: String,
     symbol: String,
+    timestamp_format: TimestampFormat,
+    // Records left to feed, opened on the first read
+    records: Option<StringRecordsIntoIter<File>>,
 }
 
 impl ModulePublish for MarketDataFeederLocal {
@@ -19,12 +76,25 @@ impl ModulePublish for MarketDataFeederLocal {
     }
 }
 
+impl DataSource for MarketDataFeederLocal {
+    fn next_event(&mut self) -> Option<Event> {
+        let records = self.records.get_or_insert_with(|| {
+            let file = File::open(&self.csv_path).expect("Failed to open CSV file");
+            ReaderBuilder::new().has_headers(true).from_reader(file).into_records()
+        });
+        let record = records.next()?.expect("Failed to read record");
+        Some(Event::MarketData(parse_record(&record, &self.symbol, &self.timestamp_format)))
+    }
+}
+
 impl MarketDataFeederLocal {
     pub fn new(symbol: String, csv_path: String) -> Self {
         MarketDataFeederLocal {
             publish_sender: None,
             csv_path,
             symbol,
+            timestamp_format: TimestampFormat::default(),
+            records: None,
         }
     }
 
@@ -35,56 +105,19 @@ impl MarketDataFeederLocal {
             panic!("publish_sender is not initialized!");
         }
     }
+}
 
-    pub fn start_feeding(&self) {
-        let file = File::open(&self.csv_path).expect("Failed to open CSV file");
-        let mut reader = ReaderBuilder::new().has_headers(true).from_reader(file);
+impl MarketDataFeeder for MarketDataFeederLocal {
+    fn set_timestamp_format(&mut self, timestamp_format: TimestampFormat) {
+        // Format and timezone of the timestamp column, "%Y-%m-%d %H:%M:%S" in UTC by default
+        self.timestamp_format = timestamp_format;
+    }
 
+    fn start_feeding(&mut self) {
         #[cfg(feature = "random_sleep_test")]
         let mut rng = rand::thread_rng();
 
-        for result in reader.records() {
-            let record = result.expect("Failed to read record");
-
-            // Parse the timestamp
-            let timestamp = record[0].to_string();
-
-            // Parse the Open price
-            let open: f64 = record[1]
-                .parse()
-                .expect("Invalid open value");
-
-            // Parse the High price
-            let high: f64 = record[2]
-                .parse()
-                .expect("Invalid high value");
-
-            // Parse the Low price
-            let low: f64 = record[3]
-                .parse()
-                .expect("Invalid low value");
-
-            // Parse the Close price
-            let close: f64 = record[4]
-                .parse()
-                .expect("Invalid close value");
-
-            // Parse the Volume
-            let volume: i32 = record[5]
-                .parse()
-                .expect("Invalid volume value");
-
-            // Create a MarketDataEvent
-            let market_data_event = Event::new_market_data(
-                timestamp,
-                self.symbol.clone(),
-                open,
-                close,
-                high,
-                low,
-                volume,
-            );
-            
+        while let Some(market_data_event) = self.next_event() {
             // Send data through the channel
             #[cfg(feature = "random_sleep_test")]
             {
@@ -97,5 +130,193 @@ impl MarketDataFeederLocal {
 
             thread::sleep(std::time::Duration::from_millis(1));
         }
+
+        // Tell the event manager this feeder is done
+        self.publish(Event::new_end_of_data());
+    }
+}
+
+/*
+Feeds several symbols at once, each from its own csv file.
+Every file must be sorted by timestamp. The files are k-way merged
+so the bars go out in timestamp order, and all bars sharing a timestamp
+are published together as a single MarketDataBatchEvent.
+*/
+pub struct MarketDataFeederMulti {
+    publish_sender: Option<Sender<Event>>,
+    // (symbol, csv_path) pairs
+    sources: Vec<(String, String)>,
+    timestamp_format: TimestampFormat,
+    // Records left to feed of every source, opened on the first read
+    readers: Option<Vec<StringRecordsIntoIter<File>>>,
+    // Next bar of every source
+    heads: Vec<Option<MarketDataEvent>>,
+    // The heads ordered by (timestamp, source index)
+    heap: BinaryHeap<Reverse<(Timestamp, usize)>>,
+}
+
+impl ModulePublish for MarketDataFeederMulti {
+    fn use_sender(&mut self, sender: Sender<Event>) {
+        self.publish_sender = Some(sender.clone());
+    }
+}
+
+impl DataSource for MarketDataFeederMulti {
+    fn next_event(&mut self) -> Option<Event> {
+        if self.readers.is_none() {
+            let readers: Vec<StringRecordsIntoIter<File>> = self
+                .sources
+                .iter()
+                .map(|(_, csv_path)| {
+                    let file = File::open(csv_path).expect("Failed to open CSV file");
+                    ReaderBuilder::new()
+                        .has_headers(true)
+                        .from_reader(file)
+                        .into_records()
+                })
+                .collect();
+            self.heads = vec![None; readers.len()];
+            self.readers = Some(readers);
+            for index in 0..self.sources.len() {
+                self.advance(index);
+            }
+        }
+
+        let &Reverse((timestamp, _)) = self.heap.peek()?;
+        let mut bars = Vec::new();
+        while let Some(&Reverse((next_timestamp, index))) = self.heap.peek() {
+            if next_timestamp != timestamp {
+                break;
+            }
+            self.heap.pop();
+            bars.push(self.heads[index].take().unwrap());
+            self.advance(index);
+        }
+        Some(Event::new_market_data_batch(timestamp, bars))
+    }
+}
+
+impl MarketDataFeederMulti {
+    pub fn new(sources: Vec<(String, String)>) -> Self {
+        MarketDataFeederMulti {
+            publish_sender: None,
+            sources,
+            timestamp_format: TimestampFormat::default(),
+            readers: None,
+            heads: Vec::new(),
+            heap: BinaryHeap::new(),
+        }
+    }
+
+    fn publish(&self, event: Event) {
+        if let Some(publish_sender) = &self.publish_sender {
+            publish_sender.send(event).unwrap();
+        } else {
+            panic!("publish_sender is not initialized!");
+        }
+    }
+
+    fn advance(&mut self, index: usize) {
+        // Read the next bar of a source and push it onto the heap
+        let readers = self.readers.as_mut().unwrap();
+        if let Some(result) = readers[index].next() {
+            let record = result.expect("Failed to read record");
+            let bar = parse_record(&record, &self.sources[index].0, &self.timestamp_format);
+            self.heap.push(Reverse((bar.timestamp, index)));
+            self.heads[index] = Some(bar);
+        }
+    }
+}
+
+impl MarketDataFeeder for MarketDataFeederMulti {
+    fn set_timestamp_format(&mut self, timestamp_format: TimestampFormat) {
+        // Format and timezone of the timestamp column of every file
+        self.timestamp_format = timestamp_format;
+    }
+
+    fn start_feeding(&mut self) {
+        while let Some(market_data_batch_event) = self.next_event() {
+            debug!("Market data batch event: {:?}", market_data_batch_event);
+            self.publish(market_data_batch_event);
+        }
+
+        // Tell the event manager this feeder is done
+        self.publish(Event::new_end_of_data());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{FixedOffset, Timelike};
+    use crossbeam::channel::unbounded;
+    use std::fs;
+
+    fn write_csv(name: &str, rows: &[&str]) -> String {
+        let path = std::env::temp_dir().join(name);
+        let mut content = "timestamp,open,high,low,close,volume\n".to_string();
+        for row in rows {
+            content.push_str(row);
+            content.push('\n');
+        }
+        fs::write(&path, content).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_multi_feeder_merges_by_timestamp() {
+        let path_a = write_csv(
+            "crab_feeder_test_a.csv",
+            &["2024-01-01,1,1,1,1,10", "2024-01-03,3,3,3,3,30"],
+        );
+        let path_b = write_csv(
+            "crab_feeder_test_b.csv",
+            &["2024-01-01,2,2,2,2,20", "2024-01-02,4,4,4,4,40"],
+        );
+
+        let (sender, receiver) = unbounded();
+        let mut feeder = MarketDataFeederMulti::new(vec![
+            ("A".to_string(), path_a),
+            ("B".to_string(), path_b),
+        ]);
+        feeder.set_timestamp_format(TimestampFormat::new("%Y-%m-%d", FixedOffset::east_opt(0).unwrap()));
+        feeder.use_sender(sender);
+        feeder.start_feeding();
+
+        let batches: Vec<(String, Vec<String>)> = receiver
+            .try_iter()
+            .filter_map(|event| match event {
+                Event::MarketDataBatch(batch) => Some((
+                    batch.timestamp.date_naive().to_string(),
+                    batch.bars.into_iter().map(|bar| bar.symbol).collect(),
+                )),
+                _ => None,
+            })
+            .collect();
+
+        assert_eq!(
+            batches,
+            vec![
+                ("2024-01-01".to_string(), vec!["A".to_string(), "B".to_string()]),
+                ("2024-01-02".to_string(), vec!["B".to_string()]),
+                ("2024-01-03".to_string(), vec!["A".to_string()]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_timestamp_format_and_timezone() {
+        let record = StringRecord::from(vec!["02/01/2024 09:30", "1", "1", "1", "1", "10"]);
+        let new_york = FixedOffset::west_opt(5 * 3600).unwrap();
+        let bar = parse_record(&record, "A", &TimestampFormat::new("%d/%m/%Y %H:%M", new_york));
+        // Written in the timezone of the data, the same instant as 14:30 UTC
+        assert_eq!(bar.timestamp.to_string(), "2024-01-02 09:30:00");
+        assert_eq!(bar.timestamp.hour(), 9);
+        assert_eq!(bar.timestamp, "2024-01-02T14:30:00Z".parse().unwrap());
+
+        // An offset in the data wins over the timezone of the format
+        let record = StringRecord::from(vec!["2024-01-02 09:30:00 +0100", "1", "1", "1", "1", "10"]);
+        let bar = parse_record(&record, "A", &TimestampFormat::new("%Y-%m-%d %H:%M:%S %z", new_york));
+        assert_eq!(bar.timestamp, "2024-01-02T08:30:00Z".parse().unwrap());
     }
 }
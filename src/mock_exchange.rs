@@ -1,10 +1,15 @@
-use crate::event_manager::{ModulePublish, ModuleReceive};
+use crate::event_manager::{ModuleHandle, ModulePublish, ModuleReceive};
+use crate::fill_model::FillModel;
 use crate::shared_structures::{
-    Event, MarketDataEvent, Order, OrderDirection, OrderPlaceEvent, Portfolio,
+    Event, Fill, MarketDataBatchEvent, MarketDataEvent, OpenOrder, Order, OrderDirection,
+    OrderCancelEvent, OrderModifyEvent, OrderPlaceEvent, Ledger, Portfolio, TimeInForce,
+    Timestamp, TrailingOffset,
 };
 use crate::PortfolioUpdater;
+use chrono::NaiveDate;
 use crossbeam::channel::{bounded, Receiver, Sender};
 use simplelog::debug;
+use std::collections::HashMap;
 
 pub struct MockExchange {
     /*
@@ -25,8 +30,182 @@ pub struct MockExchange {
     will receive a local copy of which.
     */
     portfolio: Portfolio,
-    pending_orders: Vec<Order>,
+    pending_orders: Vec<PendingOrder>,
+    // Final state of every order no longer pending, to answer late cancel or modify requests
+    closed_orders: HashMap<u64, &'static str>,
     fee_function: fn(f64) -> f64,
+    fill_model: Box<dyn FillModel + Send>,
+    // Largest fraction of a bar's volume the orders can fill on that bar together, None if unlimited
+    max_participation: Option<f64>,
+    // Short selling is only allowed with a margin configuration
+    margin: Option<MarginConfig>,
+    // Latest close of every symbol seen, to value positions
+    last_prices: HashMap<String, f64>,
+    // Trading day of the latest bar, borrow fees are charged when it changes
+    current_trading_day: Option<NaiveDate>,
+    ledger: Ledger,
+    // Number of ledger entries already sent with a portfolio update
+    ledger_reported: usize,
+}
+
+/*
+Margin requirements for short positions, as fractions of the short market value.
+Shorts must be opened with equity of at least initial_margin times the
+short market value, and are force-liquidated when equity falls below
+maintenance_margin times it. Borrow fees are charged every trading day
+at borrow_rate / 252 of the short market value.
+*/
+#[derive(Debug, Clone, Copy)]
+pub struct MarginConfig {
+    pub initial_margin: f64,
+    pub maintenance_margin: f64,
+    // Annual rate
+    pub borrow_rate: f64,
+}
+
+/*
+An order resting in the exchange, with the state needed to trigger
+stop orders and to trail the price for trailing stops.
+*/
+struct PendingOrder {
+    order_id: u64,
+    order: Order,
+    // Whether the stop price of a stop order has been reached
+    triggered: bool,
+    // Highest (trailing sell) or lowest (trailing buy) price seen so far
+    extreme_price: Option<f64>,
+    // Trading day of the first bar the order was matched against
+    trading_day: Option<NaiveDate>,
+    // Quantity filled so far
+    filled: i32,
+}
+
+impl PendingOrder {
+    fn new(order_id: u64, order: Order) -> Self {
+        PendingOrder {
+            order_id,
+            order,
+            triggered: false,
+            extreme_price: None,
+            trading_day: None,
+            filled: 0,
+        }
+    }
+
+    fn remaining(&self) -> i32 {
+        self.order.amount() - self.filled
+    }
+
+    fn is_expired(&mut self, bar: &MarketDataEvent) -> bool {
+        // Check the time in force before the order is matched against the bar
+        let bar_day = bar.timestamp.date_naive();
+        match self.order.time_in_force() {
+            TimeInForce::Day => *self.trading_day.get_or_insert(bar_day) != bar_day,
+            TimeInForce::Gtd(expire_time) => bar.timestamp > *expire_time,
+            TimeInForce::Gtc | TimeInForce::Ioc | TimeInForce::Fok => false,
+        }
+    }
+
+    fn match_bar(&mut self, bar: &MarketDataEvent, market_price: f64) -> Option<f64> {
+        /*
+        Match the order against one bar and return the fill price if it fills.
+        market_price is the price a market order would get on this bar.
+        - Market: fills at market_price.
+        - Limit: fills if the bar traded through the limit, at market_price
+          capped by the limit.
+        - Stop: triggers if the bar traded through the stop, and fills at
+          market_price but never better than the stop. What is left after a
+          partial fill is a market order.
+        - StopLimit: once triggered behaves as a limit order. On the bar that
+          triggers it, the fill is between the stop and the limit.
+        - TrailingStop: the stop is set from the extreme price before this bar,
+          so the bar is checked against it before the extreme is updated.
+          A new order starts trailing from the bar's open.
+        */
+        match &self.order {
+            Order::Market(_) => Some(market_price),
+            Order::Stop(_) | Order::TrailingStop(_) if self.triggered => Some(market_price),
+            Order::LimitPrice(order) => match order.direction {
+                OrderDirection::Buy if bar.low <= order.limit_price => {
+                    Some(market_price.min(order.limit_price))
+                }
+                OrderDirection::Sell if bar.high >= order.limit_price => {
+                    Some(market_price.max(order.limit_price))
+                }
+                _ => None,
+            },
+            Order::Stop(order) => match order.direction {
+                OrderDirection::Buy if bar.high >= order.stop_price => {
+                    self.triggered = true;
+                    Some(market_price.max(order.stop_price))
+                }
+                OrderDirection::Sell if bar.low <= order.stop_price => {
+                    self.triggered = true;
+                    Some(market_price.min(order.stop_price))
+                }
+                _ => None,
+            },
+            Order::StopLimit(order) => {
+                let already_triggered = self.triggered;
+                if !already_triggered {
+                    self.triggered = match order.direction {
+                        OrderDirection::Buy => bar.high >= order.stop_price,
+                        OrderDirection::Sell => bar.low <= order.stop_price,
+                    };
+                }
+                if !self.triggered {
+                    return None;
+                }
+                match order.direction {
+                    OrderDirection::Buy if bar.low <= order.limit_price => {
+                        let price = if already_triggered {
+                            market_price
+                        } else {
+                            market_price.max(order.stop_price)
+                        };
+                        Some(price.min(order.limit_price))
+                    }
+                    OrderDirection::Sell if bar.high >= order.limit_price => {
+                        let price = if already_triggered {
+                            market_price
+                        } else {
+                            market_price.min(order.stop_price)
+                        };
+                        Some(price.max(order.limit_price))
+                    }
+                    _ => None,
+                }
+            }
+            Order::TrailingStop(order) => {
+                let extreme_price = self.extreme_price.unwrap_or(bar.open);
+                match order.direction {
+                    OrderDirection::Buy => {
+                        let stop_price = match order.trail {
+                            TrailingOffset::Amount(amount) => extreme_price + amount,
+                            TrailingOffset::Percent(percent) => extreme_price * (1.0 + percent),
+                        };
+                        if bar.high >= stop_price {
+                            self.triggered = true;
+                            return Some(market_price.max(stop_price));
+                        }
+                        self.extreme_price = Some(extreme_price.min(bar.low));
+                    }
+                    OrderDirection::Sell => {
+                        let stop_price = match order.trail {
+                            TrailingOffset::Amount(amount) => extreme_price - amount,
+                            TrailingOffset::Percent(percent) => extreme_price * (1.0 - percent),
+                        };
+                        if bar.low <= stop_price {
+                            self.triggered = true;
+                            return Some(market_price.min(stop_price));
+                        }
+                        self.extreme_price = Some(extreme_price.max(bar.high));
+                    }
+                }
+                None
+            }
+        }
+    }
 }
 
 impl ModuleReceive for MockExchange {
@@ -43,8 +222,40 @@ impl ModulePublish for MockExchange {
     }
 }
 
+impl ModuleHandle for MockExchange {
+    fn validate(&self) {
+        if self.publish_sender.is_none() {
+            panic!("MEX: publish_sender is not initialized!");
+        }
+    }
+
+    fn handle_event(&mut self, event: Event) {
+        match event {
+            Event::MarketData(market_data_event) => {
+                self.process_marketevent(market_data_event);
+            }
+            Event::MarketDataBatch(market_data_batch_event) => {
+                self.process_marketbatch(market_data_batch_event);
+            }
+            Event::OrderPlace(order_place_event) => {
+                self.process_orderplace(order_place_event);
+            }
+            Event::OrderCancel(order_cancel_event) => {
+                self.process_ordercancel(order_cancel_event);
+            }
+            Event::OrderModify(order_modify_event) => {
+                self.process_ordermodify(order_modify_event);
+            }
+            Event::Barrier(_) => {}
+            _ => {
+                println!("MEX: Unsupported event: {:?}", event);
+            }
+        }
+    }
+}
+
 impl MockExchange {
-    pub fn new(fee_function: fn(f64) -> f64) -> Self {
+    pub fn new(fee_function: fn(f64) -> f64, fill_model: Box<dyn FillModel + Send>) -> Self {
         let (subscribe_sender, subscribe_receiver) = bounded(0);
         let portfolio = Portfolio::new(1000000.);
         let pending_orders = Vec::new();
@@ -54,11 +265,161 @@ impl MockExchange {
             publish_sender: None,
             portfolio,
             pending_orders,
+            closed_orders: HashMap::new(),
             fee_function,
+            fill_model,
+            max_participation: None,
+            margin: None,
+            last_prices: HashMap::new(),
+            current_trading_day: None,
+            ledger: Ledger::default(),
+            ledger_reported: 0,
         }
     }
 
-    fn publish(&mut self, event: Event) -> () {
+    pub fn set_margin(&mut self, margin: MarginConfig) {
+        // Allow sells beyond the position, opening shorts under the margin requirements
+        self.margin = Some(margin);
+    }
+
+    fn short_market_value(&self) -> f64 {
+        self.portfolio
+            .positions
+            .iter()
+            .filter(|(_, position)| position.quantity < 0)
+            .map(|(symbol, position)| {
+                -(position.quantity as f64) * self.last_prices.get(symbol).copied().unwrap_or(0.0)
+            })
+            .sum()
+    }
+
+    fn equity(&self) -> f64 {
+        // Cash plus every position valued at its latest price
+        self.portfolio.cash
+            + self
+                .portfolio
+                .positions
+                .iter()
+                .map(|(symbol, position)| {
+                    position.quantity as f64 * self.last_prices.get(symbol).copied().unwrap_or(0.0)
+                })
+                .sum::<f64>()
+    }
+
+    fn record_fill(&mut self, fill: &Fill) {
+        // Apply an executed fill to its position and append it to the ledger
+        let realized_pnl = self
+            .portfolio
+            .positions
+            .entry(fill.symbol.clone())
+            .or_default()
+            .apply_fill(fill);
+        self.ledger.record(fill.clone(), realized_pnl);
+    }
+
+    fn mark_to_market(&mut self) {
+        // Value every position at its latest price and update the total asset value
+        for (symbol, position) in self.portfolio.positions.iter_mut() {
+            if let Some(&price) = self.last_prices.get(symbol) {
+                position.mark(price);
+            }
+        }
+        self.portfolio.asset = self.portfolio.cash
+            + self
+                .portfolio
+                .positions
+                .values()
+                .map(|position| position.market_value)
+                .sum::<f64>();
+    }
+
+    fn start_bar(&mut self, market_data_event: &MarketDataEvent) {
+        // Charge borrow fees when a new trading day starts, then record the latest price
+        let bar_day = market_data_event.timestamp.date_naive();
+        if self.current_trading_day != Some(bar_day) {
+            let new_day = self.current_trading_day.is_some();
+            self.current_trading_day = Some(bar_day);
+
+            // The shorts were held overnight at the marks of the previous day
+            if let (true, Some(margin)) = (new_day, self.margin) {
+                let borrow_fee = self.short_market_value() * margin.borrow_rate / 252.0;
+                if borrow_fee > 0.0 {
+                    debug!("Charging borrow fee {} on {}", borrow_fee, bar_day);
+                    self.portfolio.cash -= borrow_fee;
+                }
+            }
+        }
+
+        self.last_prices
+            .insert(market_data_event.symbol.clone(), market_data_event.close);
+    }
+
+    fn check_margin(&mut self, timestamp: Timestamp) {
+        // Force-liquidate all short positions if equity is below the maintenance margin
+        let margin = match self.margin {
+            Some(margin) => margin,
+            None => return,
+        };
+        let short_market_value = self.short_market_value();
+        let equity = self.equity();
+        let requirement = margin.maintenance_margin * short_market_value;
+        if short_market_value <= 0.0 || equity >= requirement {
+            return;
+        }
+
+        debug!(
+            "Margin call: equity {} below maintenance requirement {}",
+            equity, requirement
+        );
+        let mut shorts: Vec<(String, i32)> = self
+            .portfolio
+            .positions
+            .iter()
+            .filter(|(_, position)| position.quantity < 0)
+            .map(|(symbol, position)| (symbol.clone(), -position.quantity))
+            .collect();
+        shorts.sort();
+
+        let mut fills = Vec::new();
+        for (symbol, quantity) in shorts {
+            let price = self.last_prices[&symbol];
+            let fee = (self.fee_function)(price * quantity as f64);
+            // Covering is forced, even if it takes cash below zero
+            self.portfolio.cash -= price * quantity as f64 + fee;
+            let fill = Fill {
+                timestamp,
+                symbol,
+                direction: OrderDirection::Buy,
+                quantity,
+                price,
+                fee,
+            };
+            self.record_fill(&fill);
+            fills.push(fill);
+        }
+        self.portfolio.asset = self.equity();
+        self.portfolio.available_cash = self.portfolio.cash;
+
+        self.mark_to_market();
+        self.publish(Event::new_margin_call(
+            timestamp,
+            equity,
+            requirement,
+            fills,
+        ));
+    }
+
+    pub fn set_max_participation(&mut self, rate: f64) {
+        /*
+        Cap the fills of a bar, all orders on its symbol together, to
+        rate * volume of the bar. Orders are filled in the order they were
+        placed; those over what is left are partially filled and the rest
+        carries over to later bars, subject to their time in force.
+        */
+        self.max_participation = Some(rate);
+    }
+
+    fn publish(&mut self, event: Event) {
         // To push an Event to EventManager.
         if let Some(publish_sender) = &self.publish_sender {
             publish_sender.send(event).unwrap();
@@ -67,166 +428,418 @@ impl MockExchange {
         }
     }
 
-    pub fn run(&mut self) -> () {
-        if self.publish_sender.is_none() {
-            panic!("MEX: publish_sender is not initialized!");
-        }
+    pub fn run(&mut self) {
+        self.validate();
 
         #[cfg(feature = "random_sleep_test")]
         let mut rng = rand::thread_rng();
 
         loop {
             let event = self.subscribe_receiver.recv().unwrap();
-
-            match event {
-                Event::MarketData(market_data_event) => {
-                    self.process_marketevent(market_data_event);
-                }
-                Event::OrderPlace(order_place_event) => {
-                    self.process_orderplace(order_place_event);
-                }
-                _ => {
-                    println!("MEX: Unsupported event: {:?}", event);
-                }
-            }
+            self.handle_event(event);
         }
     }
 
     fn process_marketevent(&mut self, market_data_event: MarketDataEvent) {
         debug!("Received market data: {:?}", market_data_event);
 
-        // Calculate the mean price from market data
-        let mean_price = (market_data_event.high + market_data_event.low) / 2.0;
-
-        // Vector to store filled orders
-        let mut filled_orders = Vec::new();
-
-        // Iterate through pending orders, mark filled ones
-        self.pending_orders.retain(|order| {
-            match order {
-                Order::LimitPrice(limit_order) => {
-                    let symbol = limit_order.symbol.clone();
-                    let amount = limit_order.amount;
-                    let limit_price = limit_order.limit_price;
-                    let direction = limit_order.direction;
-
-                    match direction {
-                        OrderDirection::Buy => {
-                            if mean_price <= limit_price {
-                                debug!(
-                                    "Filling order (market id = {:?}): {:?}",
-                                    market_data_event.id, order
-                                );
-                                filled_orders.push((symbol, amount, mean_price, direction));
-                                return false; // Remove this order
-                            } else {
-                                debug!(
-                                    "Dropping order (market id = {:?}): {:?}",
-                                    market_data_event.id, order
-                                );
-                            }
-                        }
-                        OrderDirection::Sell => {
-                            if mean_price >= limit_price {
-                                debug!(
-                                    "Filling order (market id = {:?}): {:?}",
-                                    market_data_event.id, order
-                                );
-                                filled_orders.push((symbol, amount, mean_price, direction));
-                                return false; // Remove this order
-                            } else {
-                                debug!(
-                                    "Dropping order (market id = {:?}): {:?}",
-                                    market_data_event.id, order
-                                );
-                            }
-                        }
+        let timestamp = market_data_event.timestamp;
+        self.start_bar(&market_data_event);
+        self.match_orders(&market_data_event);
+        self.update_asset(market_data_event);
+        self.check_margin(timestamp);
+
+        let portfolio_info_event = self.portfolio_info();
+        debug!("Publishing portfolio: {:?}", portfolio_info_event);
+        self.publish(portfolio_info_event);
+    }
+
+    fn process_marketbatch(&mut self, market_data_batch_event: MarketDataBatchEvent) {
+        // Match every bar of the cross-section before publishing a single portfolio update
+        debug!("Received market data batch: {:?}", market_data_batch_event);
+
+        for market_data_event in &market_data_batch_event.bars {
+            self.start_bar(market_data_event);
+        }
+        for market_data_event in market_data_batch_event.bars {
+            self.match_orders(&market_data_event);
+            self.update_asset(market_data_event);
+        }
+        self.check_margin(market_data_batch_event.timestamp);
+
+        let portfolio_info_event = self.portfolio_info();
+        debug!("Publishing portfolio: {:?}", portfolio_info_event);
+        self.publish(portfolio_info_event);
+    }
+
+    fn portfolio_info(&mut self) -> Event {
+        // Portfolio update with the ledger entries recorded since the previous one
+        let ledger_entries = self.ledger.entries()[self.ledger_reported..].to_vec();
+        self.ledger_reported += ledger_entries.len();
+        Event::new_portfolio_info(self.snapshot_portfolio(), ledger_entries)
+    }
+
+    fn snapshot_portfolio(&mut self) -> Portfolio {
+        // Copy of the portfolio to publish, including the orders still resting
+        self.portfolio.open_orders = self
+            .pending_orders
+            .iter()
+            .map(|pending_order| OpenOrder {
+                order_id: pending_order.order_id,
+                order: pending_order.order.clone(),
+                remaining: pending_order.remaining(),
+            })
+            .collect();
+        self.portfolio.clone()
+    }
+
+    fn match_orders(&mut self, market_data_event: &MarketDataEvent) {
+        // Only orders on the symbol of this bar are matched, others wait for their own bar
+
+        // Quantity of this bar left to the orders on its symbol, None if unlimited.
+        // Only what is actually filled takes from it, so together the fills stay
+        // within the participation cap and a rejected fill leaves it to the next order.
+        let mut capacity = self
+            .max_participation
+            .map(|rate| (rate * market_data_event.volume as f64).floor() as i32);
+
+        // Orders are matched one at a time, each fill applied before the next order
+        let pending_orders = std::mem::take(&mut self.pending_orders);
+        for mut pending_order in pending_orders {
+            if self.match_order(&mut pending_order, market_data_event, &mut capacity) {
+                self.pending_orders.push(pending_order);
+            }
+        }
+    }
+
+    fn match_order(
+        &mut self,
+        pending_order: &mut PendingOrder,
+        market_data_event: &MarketDataEvent,
+        capacity: &mut Option<i32>,
+    ) -> bool {
+        // Match an order against a bar, returns whether it keeps resting
+        let order = &pending_order.order;
+        if order.symbol() != market_data_event.symbol {
+            return true; // Keep it for a bar of its own symbol
+        }
+        let order_id = pending_order.order_id;
+
+        if pending_order.is_expired(market_data_event) {
+            debug!(
+                "Order expired (market id = {:?}): {:?}",
+                market_data_event.id, pending_order.order
+            );
+            self.cancel_order(order_id, "expired");
+            return false;
+        }
+
+        let remaining = pending_order.remaining();
+        let quantity = capacity.map_or(remaining, |capacity| remaining.min(capacity));
+        let time_in_force = pending_order.order.time_in_force().clone();
+
+        // Without volume left on this bar the order is not matched, its triggers included
+        if quantity > 0 {
+            // Price a market order of this size would get on this bar
+            let market_price = self.fill_model.fill_price(
+                market_data_event,
+                pending_order.order.direction(),
+                quantity,
+            );
+            match pending_order.match_bar(market_data_event, market_price) {
+                Some(_) if time_in_force == TimeInForce::Fok && quantity < remaining => {
+                    debug!(
+                        "Killing order over the volume limit (market id = {:?}): {:?}",
+                        market_data_event.id, pending_order.order
+                    );
+                    self.cancel_order(order_id, "cannot be filled entirely");
+                    return false;
+                }
+                Some(fill_price) => {
+                    debug!(
+                        "Filling {} of order (market id = {:?}) at {}: {:?}",
+                        quantity, market_data_event.id, fill_price, pending_order.order
+                    );
+                    pending_order.filled += quantity;
+                    let filled = self.fill_order(
+                        order_id,
+                        &pending_order.order,
+                        quantity,
+                        fill_price,
+                        pending_order.remaining(),
+                        market_data_event.timestamp,
+                    );
+                    if let Some(capacity) = capacity.as_mut() {
+                        *capacity -= filled;
+                    }
+                    // Done, or closed by a rejected or cut short fill
+                    if pending_order.remaining() == 0 || self.closed_orders.contains_key(&order_id) {
+                        return false;
                     }
                 }
+                None => {}
             }
-            // Set the return to to true if to keep the unprocessed order for the next market data feed
-            // Currently as no cancle order mechanism is implemented we will just drop it anyway
-            false
-        });
+        } else if time_in_force == TimeInForce::Fok {
+            self.cancel_order(order_id, "cannot be filled entirely");
+            return false;
+        }
 
-        // Process filled orders separately to avoid mutable borrow conflicts
-        for (symbol, amount, price, direction) in filled_orders {
-            self.update_fill(symbol, amount, price, direction);
+        // Immediate orders only get one bar, the others rest until they expire
+        match time_in_force {
+            TimeInForce::Ioc | TimeInForce::Fok => {
+                debug!(
+                    "Cancelling unfilled order (market id = {:?}): {:?}",
+                    market_data_event.id, pending_order.order
+                );
+                self.cancel_order(order_id, "not filled immediately");
+                false
+            }
+            _ => true,
         }
-        self.update_asset(market_data_event);
+    }
 
-        let portfolio_info_event = Event::new_portfolio_info(self.portfolio.clone());
-        debug!("Publishing portfolio: {:?}", portfolio_info_event);
-        self.publish(portfolio_info_event);
+    fn cancel_order(&mut self, order_id: u64, reason: &str) {
+        self.closed_orders.insert(order_id, "cancelled");
+        self.publish(Event::new_order_cancelled(order_id, reason.to_string()));
+    }
+
+    fn fill_order(
+        &mut self,
+        order_id: u64,
+        order: &Order,
+        quantity: i32,
+        price: f64,
+        remaining: i32,
+        timestamp: Timestamp,
+    ) -> i32 {
+        // Apply the fill to the portfolio and report the result to the order owner, returns the quantity filled
+        let symbol = order.symbol().to_string();
+        let direction = order.direction();
+        match self.update_fill(timestamp, symbol, quantity, price, direction) {
+            Ok(fill) => {
+                let filled = fill.quantity;
+                if filled == quantity && remaining == 0 {
+                    self.closed_orders.insert(order_id, "filled");
+                    self.publish(Event::new_order_filled(order_id, fill));
+                } else if filled == quantity {
+                    self.publish(Event::new_order_partially_filled(order_id, fill, remaining));
+                } else {
+                    let remaining = remaining + quantity - filled;
+                    self.closed_orders.insert(order_id, "cancelled");
+                    self.publish(Event::new_order_partially_filled(order_id, fill, remaining));
+                    self.publish(Event::new_order_cancelled(
+                        order_id,
+                        format!("insufficient holdings for the remaining {}", remaining),
+                    ));
+                }
+                filled
+            }
+            Err(reason) => {
+                self.closed_orders.insert(order_id, "rejected");
+                self.publish(Event::new_order_rejected(order_id, reason));
+                0
+            }
+        }
     }
 
     fn process_orderplace(&mut self, order_place_event: OrderPlaceEvent) {
-        // Check if order is valid. If yes, modify portfolio and send. If not, drop it.
-        // Add order to to_do_list
+        // Check if order is valid. If yes, add it to the pending orders. If not, reject it.
         debug!("Received order place: {:?}", order_place_event);
+        let order_id = order_place_event.order_id;
         let order = order_place_event.order;
+
+        if let Err(reason) = validate_order(&order) {
+            debug!("Rejecting order {}: {}", order_id, reason);
+            self.closed_orders.insert(order_id, "rejected");
+            self.publish(Event::new_order_rejected(order_id, reason));
+            return;
+        }
+
         // Add the parsed order to the pending_orders Vec
-        self.pending_orders.push(order.clone());
+        self.pending_orders.push(PendingOrder::new(order_id, order));
+        self.publish(Event::new_order_accepted(order_id));
+    }
+
+    fn closed_reason(&self, order_id: u64) -> String {
+        // Why a cancel or modify request cannot apply to an order that is not pending
+        match self.closed_orders.get(&order_id) {
+            Some(state) => format!("already {}", state),
+            None => "unknown id".to_string(),
+        }
+    }
+
+    fn process_ordercancel(&mut self, order_cancel_event: OrderCancelEvent) {
+        debug!("Received order cancel: {:?}", order_cancel_event);
+        let order_id = order_cancel_event.order_id;
+
+        match self
+            .pending_orders
+            .iter()
+            .position(|pending_order| pending_order.order_id == order_id)
+        {
+            Some(index) => {
+                self.pending_orders.remove(index);
+                self.closed_orders.insert(order_id, "cancelled");
+                self.publish(Event::new_order_cancelled(
+                    order_id,
+                    "cancelled by request".to_string(),
+                ));
+            }
+            None => {
+                let reason = self.closed_reason(order_id);
+                self.publish(Event::new_order_cancel_rejected(order_id, reason));
+            }
+        }
+    }
+
+    fn process_ordermodify(&mut self, order_modify_event: OrderModifyEvent) {
+        // Replace a resting order, keeping its id and its place in the queue
+        debug!("Received order modify: {:?}", order_modify_event);
+        let order_id = order_modify_event.order_id;
+        let order = order_modify_event.order;
+
+        let pending_order = match self
+            .pending_orders
+            .iter_mut()
+            .find(|pending_order| pending_order.order_id == order_id)
+        {
+            Some(pending_order) => pending_order,
+            None => {
+                let reason = self.closed_reason(order_id);
+                self.publish(Event::new_order_modify_rejected(order_id, reason));
+                return;
+            }
+        };
+
+        let result = if order.symbol() != pending_order.order.symbol()
+            || order.direction() != pending_order.order.direction()
+        {
+            Err("symbol and direction cannot be modified".to_string())
+        } else {
+            validate_order(&order)
+        };
+
+        match result {
+            Ok(()) => {
+                // Trigger and trailing state start over, the trading day is kept
+                let trading_day = pending_order.trading_day.take();
+                *pending_order = PendingOrder::new(order_id, order);
+                pending_order.trading_day = trading_day;
+                self.publish(Event::new_order_modified(order_id));
+            }
+            Err(reason) => {
+                self.publish(Event::new_order_modify_rejected(order_id, reason));
+            }
+        }
     }
 }
 
+fn validate_order(order: &Order) -> Result<(), String> {
+    if order.amount() <= 0 {
+        return Err(format!("invalid amount {}", order.amount()));
+    }
+    let prices = match order {
+        Order::Market(_) => vec![],
+        Order::LimitPrice(order) => vec![order.limit_price],
+        Order::Stop(order) => vec![order.stop_price],
+        Order::StopLimit(order) => vec![order.stop_price, order.limit_price],
+        Order::TrailingStop(order) => match order.trail {
+            TrailingOffset::Amount(amount) => vec![amount],
+            TrailingOffset::Percent(percent) => vec![percent],
+        },
+    };
+    if prices.iter().any(|price| !price.is_finite() || *price <= 0.0) {
+        return Err(format!("invalid price in {:?}", order));
+    }
+    Ok(())
+}
+
 impl PortfolioUpdater for MockExchange {
     fn update_asset(&mut self, market_data: MarketDataEvent) {
-        // Calculate the value of the specific symbol based on its current close price
-        if let Some(position) = self.portfolio.positions.get(&market_data.symbol) {
-            let position_value = *position as f64 * market_data.close;
-
-            // Update the total asset value
-            self.portfolio.asset = self.portfolio.cash
-                + self
-                    .portfolio
-                    .positions
-                    .iter()
-                    .fold(0.0, |total, (symbol, &_amount)| {
-                        if symbol == &market_data.symbol {
-                            total + position_value
-                        } else {
-                            total
-                        }
-                    });
+        // Every position is valued at the latest close of its own symbol
+        self.last_prices
+            .insert(market_data.symbol.clone(), market_data.close);
+        self.mark_to_market();
 
-            debug!(
-                "Updated portfolio: Value: {}, Cash: {}, Symbol: {}, Position Value: {}",
-                self.portfolio.asset, self.portfolio.cash, market_data.symbol, position_value
-            );
-        }
+        debug!(
+            "Updated portfolio: Value: {}, Cash: {}, Symbol: {}, Close: {}",
+            self.portfolio.asset, self.portfolio.cash, market_data.symbol, market_data.close
+        );
 
-        // Update the available cash too.
-        self.portfolio.available_cash = self.portfolio.cash;
+        // Update the available cash too, short proceeds and their margin are not available.
+        self.portfolio.available_cash = match self.margin {
+            Some(margin) => {
+                self.portfolio.cash - (1.0 + margin.initial_margin) * self.short_market_value()
+            }
+            None => self.portfolio.cash,
+        };
     }
 
-    fn update_fill(&mut self, symbol: String, amount: i32, price: f64, direction: OrderDirection) {
-        match direction {
+    fn update_fill(
+        &mut self,
+        timestamp: Timestamp,
+        symbol: String,
+        amount: i32,
+        price: f64,
+        direction: OrderDirection,
+    ) -> Result<Fill, String> {
+        let (filled, fee) = match direction {
             OrderDirection::Buy => {
                 // Calculate the total cost of the buy
                 let trade_cost = price * amount.abs() as f64;
                 let fee = (self.fee_function)(trade_cost); // Apply fee function
                 let total_cost = trade_cost + fee; // Include fee in total cost
-            
+
+                if let Some(margin) = self.margin {
+                    /*
+                    Short proceeds and their initial margin are not available to
+                    open positions: only the part of the buy covering a short may
+                    use them, since covering releases its reserve.
+                    */
+                    let position = self
+                        .portfolio
+                        .positions
+                        .get(&symbol)
+                        .map_or(0, |position| position.quantity);
+                    let covered = amount.min(-position.min(0));
+                    if covered < amount {
+                        let last_price = self.last_prices.get(&symbol).copied().unwrap_or(price);
+                        let short_after = self.short_market_value() - covered as f64 * last_price;
+                        let available_cash =
+                            self.portfolio.cash - (1.0 + margin.initial_margin) * short_after;
+                        if available_cash < total_cost {
+                            debug!(
+                                "Insufficient available cash to buy {} of {}: total cost {}, available cash {}",
+                                amount, symbol, total_cost, available_cash
+                            );
+                            return Err(format!(
+                                "insufficient available cash: total cost {:.2}, available cash {:.2}",
+                                total_cost, available_cash
+                            ));
+                        }
+                    }
+                }
+
                 // Check if there is sufficient cash to fill the order
                 if self.portfolio.cash < total_cost {
                     debug!(
                         "Insufficient cash to fill Buy Order: Symbol: {}, Amount: {}, Price: {}, Total Cost: {}, Available Cash: {}. Order revoked.",
                         symbol, amount, price, total_cost, self.portfolio.cash
                     );
-                    return; // Exit without processing the order
+                    return Err(format!(
+                        "insufficient cash: total cost {:.2}, cash {:.2}",
+                        total_cost, self.portfolio.cash
+                    ));
                 }
             
-                // Deduct cash and update the position
+                // Deduct cash, the position is updated with the fill below
                 self.portfolio.cash -= total_cost;
             
-                let position_entry = self.portfolio.positions.entry(symbol.clone()).or_insert(0);
-                *position_entry += amount;
-            
                 debug!(
                     "Filled Buy Order: Symbol: {}, Amount: {}, Price: {}, Trade Cost: {}, Fee: {}, Total Cost: {}",
                     symbol, amount, price, trade_cost, fee, total_cost
                 );
+                (amount, fee)
             }
             OrderDirection::Sell => {
                 // Calculate the total value of the sell
@@ -234,28 +847,65 @@ impl PortfolioUpdater for MockExchange {
                 let fee = (self.fee_function)(trade_value); // Apply fee function
                 let net_value = trade_value - fee; // Deduct fee from total value
 
-                // Ensure the sell order is valid before proceeding
-                match self.portfolio.positions.get(&symbol) {
-                    Some(&position_entry) => {
-                        if position_entry < amount.abs() {
-                            debug!("Warning: Insufficient holdings to sell {} of {}. Available: {}. Selling the available amount instead.", amount.abs(), symbol, position_entry);
-                            let partial_trade_value = price * position_entry as f64;
-                            let partial_fee = (self.fee_function)(partial_trade_value);
-                            self.portfolio.cash += partial_trade_value - partial_fee; // Update cash with partial value minus fee
-                            self.portfolio.positions.insert(symbol.clone(), 0);
-                        } else {
-                            let new_pos = position_entry - amount;
-                            self.portfolio.cash += net_value; // Update cash with net value after fee
-                            self.portfolio.positions.insert(symbol.clone(), new_pos);
+                let position = self
+                    .portfolio
+                    .positions
+                    .get(&symbol)
+                    .map_or(0, |position| position.quantity);
+
+                if let Some(margin) = self.margin {
+                    // Selling beyond the position opens a short, which needs the initial margin
+                    let new_pos = position - amount;
+                    if new_pos < 0 {
+                        let last_price = self.last_prices.get(&symbol).copied().unwrap_or(price);
+                        let short_after = self.short_market_value()
+                            - (-(position.min(0)) as f64) * last_price
+                            + (-new_pos) as f64 * last_price;
+                        let equity_after = self.equity() + net_value - amount as f64 * last_price;
+                        if equity_after < margin.initial_margin * short_after {
+                            debug!(
+                                "Insufficient margin to sell {} of {}: equity {}, short value {}",
+                                amount, symbol, equity_after, short_after
+                            );
+                            return Err(format!(
+                                "insufficient margin: equity {:.2}, required {:.2}",
+                                equity_after,
+                                margin.initial_margin * short_after
+                            ));
                         }
                     }
-                    None => {
-                        debug!(
-                            "Warning: No holdings for symbol {} to sell {}.",
-                            symbol,
-                            amount.abs()
-                        );
-                        return; // Exit the function without processing the order
+                    self.portfolio.cash += net_value;
+                } else {
+                    // Ensure the sell order is valid before proceeding
+                    match position {
+                        position_entry if position_entry > 0 => {
+                            if position_entry < amount.abs() {
+                                debug!("Warning: Insufficient holdings to sell {} of {}. Available: {}. Selling the available amount instead.", amount.abs(), symbol, position_entry);
+                                let partial_trade_value = price * position_entry as f64;
+                                let partial_fee = (self.fee_function)(partial_trade_value);
+                                self.portfolio.cash += partial_trade_value - partial_fee; // Update cash with partial value minus fee
+                                let fill = Fill {
+                                    timestamp,
+                                    symbol,
+                                    direction,
+                                    quantity: position_entry,
+                                    price,
+                                    fee: partial_fee,
+                                };
+                                self.record_fill(&fill);
+                                return Ok(fill);
+                            } else {
+                                self.portfolio.cash += net_value; // Update cash with net value after fee
+                            }
+                        }
+                        _ => {
+                            debug!(
+                                "Warning: No holdings for symbol {} to sell {}.",
+                                symbol,
+                                amount.abs()
+                            );
+                            return Err(format!("no holdings of {} to sell", symbol));
+                        }
                     }
                 }
 
@@ -263,7 +913,533 @@ impl PortfolioUpdater for MockExchange {
                     "Filled Sell Order: Symbol: {}, Amount: {}, Price: {}, Trade Value: {}, Fee: {}, Net Value: {}",
                     symbol, amount, price, trade_value, fee, net_value
                 );
+                (amount, fee)
+            }
+        };
+
+        let fill = Fill {
+            timestamp,
+            symbol,
+            direction,
+            quantity: filled,
+            price,
+            fee,
+        };
+        self.record_fill(&fill);
+        Ok(fill)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fill_model::MidPrice;
+    use crate::shared_structures::*;
+    use crossbeam::channel::unbounded;
+
+    fn bar(open: f64, high: f64, low: f64, close: f64) -> MarketDataEvent {
+        bar_at("2024-01-02 04:00:00", open, high, low, close)
+    }
+
+    fn bar_at(timestamp: &str, open: f64, high: f64, low: f64, close: f64) -> MarketDataEvent {
+        MarketDataEvent::new(
+            timestamp.parse().unwrap(),
+            "TEST".to_string(),
+            open,
+            close,
+            high,
+            low,
+            1000,
+        )
+    }
+
+    #[test]
+    fn test_limit_order_fill() {
+        let order = Order::LimitPrice(LimitPriceOrder {
+            symbol: "TEST".to_string(),
+            amount: 10,
+            limit_price: 99.,
+            direction: OrderDirection::Buy,
+            time_in_force: TimeInForce::Gtc,
+        });
+        let mut pending_order = PendingOrder::new(1, order);
+        assert_eq!(pending_order.match_bar(&bar(100., 102., 99.5, 101.), 100.75), None);
+        assert_eq!(pending_order.match_bar(&bar(100., 101., 97., 98.), 99.), Some(99.));
+        assert_eq!(pending_order.match_bar(&bar(98., 99., 96., 97.), 97.5), Some(97.5));
+    }
+
+    #[test]
+    fn test_stop_order_fill() {
+        let order = Order::Stop(StopOrder {
+            symbol: "TEST".to_string(),
+            amount: 10,
+            stop_price: 95.,
+            direction: OrderDirection::Sell,
+            time_in_force: TimeInForce::Gtc,
+        });
+        let mut pending_order = PendingOrder::new(1, order);
+        assert_eq!(pending_order.match_bar(&bar(100., 102., 96., 101.), 99.), None);
+        assert_eq!(pending_order.match_bar(&bar(97., 98., 94., 95.), 96.), Some(95.));
+        assert_eq!(pending_order.match_bar(&bar(90., 92., 88., 91.), 90.), Some(90.));
+    }
+
+    #[test]
+    fn test_stop_limit_order_fill() {
+        let order = Order::StopLimit(StopLimitOrder {
+            symbol: "TEST".to_string(),
+            amount: 10,
+            stop_price: 105.,
+            limit_price: 106.,
+            direction: OrderDirection::Buy,
+            time_in_force: TimeInForce::Gtc,
+        });
+        let mut pending_order = PendingOrder::new(1, order);
+        // Gaps over the limit: triggered but not filled
+        assert_eq!(pending_order.match_bar(&bar(108., 110., 107., 109.), 108.5), None);
+        assert!(pending_order.triggered);
+        // Already triggered, behaves as a limit order
+        assert_eq!(pending_order.match_bar(&bar(107., 107.5, 105., 106.), 106.25), Some(106.));
+    }
+
+    #[test]
+    fn test_trailing_stop_order_fill() {
+        let order = Order::TrailingStop(TrailingStopOrder {
+            symbol: "TEST".to_string(),
+            amount: 10,
+            trail: TrailingOffset::Amount(5.),
+            direction: OrderDirection::Sell,
+            time_in_force: TimeInForce::Gtc,
+        });
+        let mut pending_order = PendingOrder::new(1, order);
+        // Trails from the open at 100, the high of 110 moves the stop to 105
+        assert_eq!(pending_order.match_bar(&bar(100., 110., 99., 108.), 104.5), None);
+        assert_eq!(pending_order.extreme_price, Some(110.));
+        assert_eq!(pending_order.match_bar(&bar(108., 109., 104., 105.), 106.5), Some(105.));
+    }
+
+    fn limit_buy(limit_price: f64, time_in_force: TimeInForce) -> OrderPlaceEvent {
+        let order = Order::LimitPrice(LimitPriceOrder {
+            symbol: "TEST".to_string(),
+            amount: 10,
+            limit_price,
+            direction: OrderDirection::Buy,
+            time_in_force,
+        });
+        match Event::new_order_place(order) {
+            Event::OrderPlace(order_place_event) => order_place_event,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_time_in_force() {
+        let (sender, _receiver) = unbounded();
+        let mut mock_exchange = MockExchange::new(|_| 0., Box::new(MidPrice));
+        mock_exchange.use_sender(sender);
+        mock_exchange.process_orderplace(limit_buy(90., TimeInForce::Gtc));
+        mock_exchange.process_orderplace(limit_buy(90., TimeInForce::Day));
+        mock_exchange.process_orderplace(limit_buy(90., TimeInForce::Ioc));
+        mock_exchange.process_orderplace(limit_buy(90., TimeInForce::Gtd("2024-01-03 23:59:59".parse().unwrap())));
+
+        mock_exchange.match_orders(&bar_at("2024-01-02 04:00:00", 100., 101., 99., 100.));
+        // The IOC order is cancelled after its first bar
+        assert_eq!(mock_exchange.snapshot_portfolio().open_orders.len(), 3);
+
+        mock_exchange.match_orders(&bar_at("2024-01-03 04:00:00", 100., 101., 99., 100.));
+        // The DAY order expires on the next trading day
+        assert_eq!(mock_exchange.snapshot_portfolio().open_orders.len(), 2);
+
+        mock_exchange.match_orders(&bar_at("2024-01-04 04:00:00", 100., 101., 99., 100.));
+        // The GTD order expires after its date, the GTC order keeps resting
+        let open_orders = mock_exchange.snapshot_portfolio().open_orders;
+        assert_eq!(open_orders.len(), 1);
+        assert_eq!(*open_orders[0].order.time_in_force(), TimeInForce::Gtc);
+
+        mock_exchange.match_orders(&bar_at("2024-01-05 04:00:00", 95., 96., 89., 90.));
+        assert!(mock_exchange.snapshot_portfolio().open_orders.is_empty());
+        assert_eq!(mock_exchange.portfolio.positions.get("TEST").map(|position| position.quantity), Some(10));
+    }
+
+    fn market_order(amount: i32, direction: OrderDirection) -> OrderPlaceEvent {
+        let order = Order::Market(MarketOrder {
+            symbol: "TEST".to_string(),
+            amount,
+            direction,
+            time_in_force: TimeInForce::Day,
+        });
+        match Event::new_order_place(order) {
+            Event::OrderPlace(order_place_event) => order_place_event,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_order_lifecycle_events() {
+        let (sender, receiver) = unbounded();
+        let mut mock_exchange = MockExchange::new(|trade_cost| trade_cost * 0.001, Box::new(MidPrice));
+        mock_exchange.use_sender(sender);
+
+        let buy = market_order(100, OrderDirection::Buy);
+        let too_large = market_order(1_000_000, OrderDirection::Buy);
+        let invalid = market_order(0, OrderDirection::Buy);
+        let (buy_id, too_large_id, invalid_id) = (buy.order_id, too_large.order_id, invalid.order_id);
+        mock_exchange.process_orderplace(buy);
+        mock_exchange.process_orderplace(too_large);
+        mock_exchange.process_orderplace(invalid);
+        mock_exchange.match_orders(&bar(100., 101., 99., 100.));
+
+        let events: Vec<Event> = receiver.try_iter().collect();
+        assert!(matches!(&events[0], Event::OrderAccepted(e) if e.order_id == buy_id));
+        assert!(matches!(&events[1], Event::OrderAccepted(e) if e.order_id == too_large_id));
+        assert!(matches!(&events[2], Event::OrderRejected(e) if e.order_id == invalid_id));
+        match &events[3] {
+            Event::OrderFilled(e) => {
+                assert_eq!(e.order_id, buy_id);
+                assert_eq!(e.fill.quantity, 100);
+                assert_eq!(e.fill.price, 100.);
+                assert_eq!(e.fill.fee, 10.);
+            }
+            event => panic!("Expected a fill, got {:?}", event),
+        }
+        assert!(matches!(&events[4], Event::OrderRejected(e) if e.order_id == too_large_id));
+
+        // Selling more than the position fills what is held and cancels the rest
+        let sell = market_order(150, OrderDirection::Sell);
+        let sell_id = sell.order_id;
+        mock_exchange.process_orderplace(sell);
+        mock_exchange.match_orders(&bar(100., 101., 99., 100.));
+        let events: Vec<Event> = receiver.try_iter().collect();
+        assert!(matches!(&events[0], Event::OrderAccepted(_)));
+        assert!(matches!(&events[1], Event::OrderPartiallyFilled(e)
+            if e.order_id == sell_id && e.fill.quantity == 100 && e.remaining == 50));
+        assert!(matches!(&events[2], Event::OrderCancelled(e) if e.order_id == sell_id));
+    }
+
+    #[test]
+    fn test_order_cancel_and_modify() {
+        let (sender, receiver) = unbounded();
+        let mut mock_exchange = MockExchange::new(|_| 0., Box::new(MidPrice));
+        mock_exchange.use_sender(sender);
+
+        let first = limit_buy(90., TimeInForce::Gtc);
+        let second = limit_buy(90., TimeInForce::Gtc);
+        let (first_id, second_id) = (first.order_id, second.order_id);
+        mock_exchange.process_orderplace(first);
+        mock_exchange.process_orderplace(second);
+
+        let cancel = |order_id| match Event::new_order_cancel(order_id) {
+            Event::OrderCancel(order_cancel_event) => order_cancel_event,
+            _ => unreachable!(),
+        };
+        let modify = |order_id, order| match Event::new_order_modify(order_id, order) {
+            Event::OrderModify(order_modify_event) => order_modify_event,
+            _ => unreachable!(),
+        };
+
+        mock_exchange.process_ordercancel(cancel(first_id));
+        mock_exchange.process_ordercancel(cancel(first_id));
+        mock_exchange.process_ordercancel(cancel(12345));
+        mock_exchange.process_ordermodify(modify(second_id, limit_buy(99., TimeInForce::Gtc).order));
+        mock_exchange.match_orders(&bar(100., 101., 98., 100.));
+        mock_exchange.process_ordermodify(modify(second_id, limit_buy(95., TimeInForce::Gtc).order));
+
+        let events: Vec<Event> = receiver
+            .try_iter()
+            .filter(|event| !matches!(event, Event::OrderAccepted(_)))
+            .collect();
+        assert!(matches!(&events[0], Event::OrderCancelled(e) if e.order_id == first_id));
+        assert!(matches!(&events[1], Event::OrderCancelRejected(e) if e.reason == "already cancelled"));
+        assert!(matches!(&events[2], Event::OrderCancelRejected(e) if e.reason == "unknown id"));
+        assert!(matches!(&events[3], Event::OrderModified(e) if e.order_id == second_id));
+        // The modified limit of 99 fills on the bar
+        assert!(matches!(&events[4], Event::OrderFilled(e) if e.order_id == second_id && e.fill.price == 99.));
+        assert!(matches!(&events[5], Event::OrderModifyRejected(e) if e.reason == "already filled"));
+    }
+
+    #[test]
+    fn test_volume_participation() {
+        let (sender, receiver) = unbounded();
+        let mut mock_exchange = MockExchange::new(|_| 0., Box::new(MidPrice));
+        mock_exchange.use_sender(sender);
+        mock_exchange.set_max_participation(0.1);
+
+        // 10% of the volume of 1000 is 100 per bar
+        let gtc = market_order(250, OrderDirection::Buy);
+        let gtc_id = gtc.order_id;
+        let mut ioc = market_order(150, OrderDirection::Buy);
+        if let Order::Market(order) = &mut ioc.order {
+            order.time_in_force = TimeInForce::Ioc;
+            order.symbol = "OTHER".to_string();
+        }
+        let ioc_id = ioc.order_id;
+        let fok = limit_buy(1000., TimeInForce::Fok);
+        let fok_id = fok.order_id;
+        let late_fok = limit_buy(1000., TimeInForce::Fok);
+        let late_fok_id = late_fok.order_id;
+        mock_exchange.process_orderplace(gtc);
+        mock_exchange.process_orderplace(ioc);
+        mock_exchange.process_orderplace(fok);
+        for _ in 0..2 {
+            mock_exchange.match_orders(&bar(100., 101., 99., 100.));
+        }
+        mock_exchange.process_orderplace(late_fok);
+        mock_exchange.match_orders(&bar(100., 101., 99., 100.));
+        mock_exchange.match_orders(&MarketDataEvent::new(
+            "2024-01-02 04:00:00".parse().unwrap(),
+            "OTHER".to_string(),
+            100.,
+            100.,
+            101.,
+            99.,
+            1000,
+        ));
+
+        let events: Vec<Event> = receiver
+            .try_iter()
+            .filter(|event| !matches!(event, Event::OrderAccepted(_)))
+            .collect();
+        assert!(matches!(&events[0], Event::OrderPartiallyFilled(e)
+            if e.order_id == gtc_id && e.fill.quantity == 100 && e.remaining == 150));
+        // The GTC order took the whole first bar, nothing is left for the FOK order of 10
+        assert!(matches!(&events[1], Event::OrderCancelled(e)
+            if e.order_id == fok_id && e.reason == "cannot be filled entirely"));
+        assert!(matches!(&events[2], Event::OrderPartiallyFilled(e)
+            if e.order_id == gtc_id && e.fill.quantity == 100 && e.remaining == 50));
+        // On the third bar the GTC order takes 50 and the late FOK order fits in the other 50
+        assert!(matches!(&events[3], Event::OrderFilled(e)
+            if e.order_id == gtc_id && e.fill.quantity == 50));
+        assert!(matches!(&events[4], Event::OrderFilled(e)
+            if e.order_id == late_fok_id && e.fill.quantity == 10));
+        assert!(matches!(&events[5], Event::OrderPartiallyFilled(e)
+            if e.order_id == ioc_id && e.fill.quantity == 100 && e.remaining == 50));
+        assert!(matches!(&events[6], Event::OrderCancelled(e) if e.order_id == ioc_id));
+        assert_eq!(mock_exchange.portfolio.positions.get("TEST").map(|position| position.quantity), Some(260));
+    }
+
+    #[test]
+    fn test_rejected_fill_leaves_the_volume() {
+        let (sender, receiver) = unbounded();
+        let mut mock_exchange = MockExchange::new(|_| 0., Box::new(MidPrice));
+        mock_exchange.use_sender(sender);
+        mock_exchange.set_max_participation(0.1);
+        mock_exchange.portfolio.cash = 5_000.;
+
+        // The first order would take the 100 of the bar but cannot be paid for
+        let too_expensive = market_order(100, OrderDirection::Buy);
+        let too_expensive_id = too_expensive.order_id;
+        let affordable = limit_buy(1000., TimeInForce::Gtc);
+        let affordable_id = affordable.order_id;
+        mock_exchange.process_orderplace(too_expensive);
+        mock_exchange.process_orderplace(affordable);
+        mock_exchange.match_orders(&bar(100., 101., 99., 100.));
+
+        let events: Vec<Event> = receiver
+            .try_iter()
+            .filter(|event| !matches!(event, Event::OrderAccepted(_)))
+            .collect();
+        assert_eq!(events.len(), 2);
+        assert!(matches!(&events[0], Event::OrderRejected(e) if e.order_id == too_expensive_id));
+        assert!(matches!(&events[1], Event::OrderFilled(e)
+            if e.order_id == affordable_id && e.fill.quantity == 10));
+        assert!(mock_exchange.pending_orders.is_empty());
+    }
+
+    #[test]
+    fn test_short_selling_and_margin_call() {
+        let (sender, receiver) = unbounded();
+        let mut mock_exchange = MockExchange::new(|_| 0., Box::new(MidPrice));
+        mock_exchange.use_sender(sender);
+        mock_exchange.set_margin(MarginConfig {
+            initial_margin: 0.5,
+            maintenance_margin: 0.3,
+            borrow_rate: 0.0252,
+        });
+
+        // Equity of 1M supports at most a 2M short
+        let too_large = market_order(30_000, OrderDirection::Sell);
+        let short = market_order(10_000, OrderDirection::Sell);
+        let too_large_id = too_large.order_id;
+        mock_exchange.process_orderplace(too_large);
+        mock_exchange.process_orderplace(short);
+        mock_exchange.process_marketevent(bar_at("2024-01-02 04:00:00", 100., 101., 99., 100.));
+        assert_eq!(mock_exchange.portfolio.positions.get("TEST").map(|position| position.quantity), Some(-10_000));
+        assert_eq!(mock_exchange.portfolio.cash, 2_000_000.);
+
+        // A new day charges 0.0252 / 252 of the 1M short value held overnight, not at the new close
+        mock_exchange.process_marketevent(bar_at("2024-01-03 04:00:00", 110., 111., 109., 110.));
+        assert!((mock_exchange.portfolio.cash - 1_999_900.).abs() < 1e-6);
+
+        // At 160 equity is about 400k, below 30% of the 1.6M short value
+        mock_exchange.process_marketevent(bar_at("2024-01-03 10:00:00", 150., 161., 149., 160.));
+        assert_eq!(mock_exchange.portfolio.positions.get("TEST").map(|position| position.quantity), Some(0));
+        assert!((mock_exchange.portfolio.cash - 399_900.).abs() < 1e-6);
+
+        let events: Vec<Event> = receiver.try_iter().collect();
+        assert!(events
+            .iter()
+            .any(|event| matches!(event, Event::OrderRejected(e) if e.order_id == too_large_id)));
+        match events.iter().find(|event| matches!(event, Event::MarginCall(_))) {
+            Some(Event::MarginCall(margin_call_event)) => {
+                assert_eq!(margin_call_event.fills[0].quantity, 10_000);
+                assert_eq!(margin_call_event.fills[0].price, 160.);
             }
+            _ => panic!("Expected a margin call"),
         }
     }
+
+    #[test]
+    fn test_short_proceeds_only_cover_shorts() {
+        let (sender, receiver) = unbounded();
+        let mut mock_exchange = MockExchange::new(|_| 0., Box::new(MidPrice));
+        mock_exchange.use_sender(sender);
+        mock_exchange.set_margin(MarginConfig {
+            initial_margin: 0.5,
+            maintenance_margin: 0.3,
+            borrow_rate: 0.,
+        });
+
+        // Shorting 1M of TEST leaves 2M of cash, of which 1.5M is held as proceeds and margin
+        mock_exchange.process_orderplace(market_order(10_000, OrderDirection::Sell));
+        mock_exchange.process_marketevent(bar(100., 101., 99., 100.));
+        assert_eq!(mock_exchange.portfolio.cash, 2_000_000.);
+        assert_eq!(mock_exchange.portfolio.available_cash, 500_000.);
+
+        // Buying 1M of OTHER would spend the proceeds, 400k fits in the available cash
+        let mut too_large = market_order(10_000, OrderDirection::Buy);
+        let mut buy = market_order(4_000, OrderDirection::Buy);
+        for order_place_event in [&mut too_large, &mut buy] {
+            if let Order::Market(order) = &mut order_place_event.order {
+                order.symbol = "OTHER".to_string();
+            }
+        }
+        let too_large_id = too_large.order_id;
+        let buy_id = buy.order_id;
+        mock_exchange.process_orderplace(too_large);
+        mock_exchange.process_orderplace(buy);
+        mock_exchange.process_marketevent(MarketDataEvent::new(
+            "2024-01-02 04:00:00".parse().unwrap(),
+            "OTHER".to_string(),
+            100.,
+            100.,
+            101.,
+            99.,
+            1000,
+        ));
+
+        // Covering the short may use them
+        let cover = market_order(10_000, OrderDirection::Buy);
+        let cover_id = cover.order_id;
+        mock_exchange.process_orderplace(cover);
+        mock_exchange.process_marketevent(bar(100., 101., 99., 100.));
+
+        let events: Vec<Event> = receiver.try_iter().collect();
+        assert!(events.iter().any(|event| matches!(event, Event::OrderRejected(e)
+            if e.order_id == too_large_id && e.reason.starts_with("insufficient available cash"))));
+        assert!(events
+            .iter()
+            .any(|event| matches!(event, Event::OrderFilled(e) if e.order_id == buy_id)));
+        assert!(events
+            .iter()
+            .any(|event| matches!(event, Event::OrderFilled(e) if e.order_id == cover_id)));
+        assert_eq!(mock_exchange.portfolio.positions["TEST"].quantity, 0);
+        assert_eq!(mock_exchange.portfolio.positions["OTHER"].quantity, 4_000);
+        assert_eq!(mock_exchange.portfolio.cash, 600_000.);
+    }
+
+    #[test]
+    fn test_multi_symbol_mark_to_market() {
+        let (sender, _receiver) = unbounded();
+        let mut mock_exchange = MockExchange::new(|_| 0., Box::new(MidPrice));
+        mock_exchange.use_sender(sender);
+        let close = |symbol: &str, price: f64| {
+            MarketDataEvent::new(
+                "2024-01-02 04:00:00".parse().unwrap(),
+                symbol.to_string(),
+                price,
+                price,
+                price,
+                price,
+                1000,
+            )
+        };
+
+        mock_exchange
+            .update_fill("2024-01-02 04:00:00".parse().unwrap(), "A".to_string(), 10, 100., OrderDirection::Buy)
+            .unwrap();
+        mock_exchange
+            .update_fill("2024-01-02 04:00:00".parse().unwrap(), "B".to_string(), 20, 50., OrderDirection::Buy)
+            .unwrap();
+        mock_exchange.update_asset(close("A", 110.));
+        mock_exchange.update_asset(close("B", 40.));
+
+        // A bar of one symbol keeps valuing the other at its last mark
+        assert_eq!(mock_exchange.portfolio.asset, 998_000. + 1_100. + 800.);
+        let position_a = &mock_exchange.portfolio.positions["A"];
+        assert_eq!(position_a.cost_basis, 1_000.);
+        assert_eq!(position_a.market_value, 1_100.);
+        assert_eq!(position_a.unrealized_pnl, 100.);
+        assert_eq!(mock_exchange.portfolio.positions["B"].unrealized_pnl, -200.);
+
+        // Selling half keeps the average cost of the rest
+        mock_exchange
+            .update_fill("2024-01-02 04:00:00".parse().unwrap(), "A".to_string(), 5, 120., OrderDirection::Sell)
+            .unwrap();
+        mock_exchange.update_asset(close("A", 120.));
+        let position_a = &mock_exchange.portfolio.positions["A"];
+        assert_eq!(position_a.quantity, 5);
+        assert_eq!(position_a.cost_basis, 500.);
+        assert_eq!(position_a.unrealized_pnl, 100.);
+        assert_eq!(mock_exchange.portfolio.asset, 998_600. + 600. + 800.);
+    }
+
+    #[test]
+    fn test_position_accounting_and_ledger() {
+        let (sender, _receiver) = unbounded();
+        let mut mock_exchange = MockExchange::new(|value| value * 0.001, Box::new(MidPrice));
+        mock_exchange.use_sender(sender);
+
+        mock_exchange
+            .update_fill("2024-01-02 04:00:00".parse().unwrap(), "A".to_string(), 10, 100., OrderDirection::Buy)
+            .unwrap();
+        mock_exchange
+            .update_fill("2024-01-03 04:00:00".parse().unwrap(), "A".to_string(), 10, 110., OrderDirection::Buy)
+            .unwrap();
+        let position = &mock_exchange.portfolio.positions["A"];
+        assert_eq!(position.average_entry_price, 105.);
+        assert!((position.fees - 2.1).abs() < 1e-9);
+        assert_eq!(position.open_timestamp, Some("2024-01-02 04:00:00".parse().unwrap()));
+
+        // Closing the round trip realizes 20 * (120 - 105)
+        mock_exchange
+            .update_fill("2024-01-04 04:00:00".parse().unwrap(), "A".to_string(), 20, 120., OrderDirection::Sell)
+            .unwrap();
+        let position = &mock_exchange.portfolio.positions["A"];
+        assert_eq!(position.quantity, 0);
+        assert_eq!(position.realized_pnl, 300.);
+        assert_eq!(position.open_timestamp, None);
+
+        let entries = mock_exchange.ledger.entries();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0].realized_pnl, 0.);
+        assert_eq!(entries[2].realized_pnl, 300.);
+        assert_eq!(entries[2].fill.direction, OrderDirection::Sell);
+
+        // Selling through zero closes the long and opens a short at the fill price
+        let mut position = Position::default();
+        let fill = |direction, quantity, price| Fill {
+            timestamp: "2024-01-05 04:00:00".parse().unwrap(),
+            symbol: "A".to_string(),
+            direction,
+            quantity,
+            price,
+            fee: 0.,
+        };
+        position.apply_fill(&fill(OrderDirection::Buy, 10, 100.));
+        assert_eq!(position.apply_fill(&fill(OrderDirection::Sell, 15, 90.)), -100.);
+        assert_eq!(position.quantity, -5);
+        assert_eq!(position.average_entry_price, 90.);
+        assert_eq!(position.cost_basis, -450.);
+        position.mark(80.);
+        assert_eq!(position.unrealized_pnl, 50.);
+    }
 }
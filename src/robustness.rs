@@ -0,0 +1,290 @@
+/*
+Robustness analysis of a finished backtest.
+The returns of the run are resampled many times to see how much of the
+result could be luck: a block bootstrap draws blocks of consecutive returns
+with replacement, keeping some of their autocorrelation, and trade shuffling
+replays the closed trades in random order.
+Shuffling keeps the total return and the Sharpe ratio of the trades, so it
+only spreads the path dependent statistics such as the drawdown and the
+probability of ruin. The intervals of those two come from the trades drawn
+with replacement instead.
+*/
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use serde::Serialize;
+
+#[derive(Debug, Clone)]
+pub struct RobustnessConfig {
+    pub simulations: usize,
+    // Bars per block of the block bootstrap
+    pub block_size: usize,
+    // Coverage of the confidence intervals, e.g. 0.95
+    pub confidence: f64,
+    // A path is ruined once it loses this fraction of its initial value
+    pub ruin_threshold: f64,
+    // Seed of the random number generator, so runs are reproducible
+    pub seed: u64,
+}
+
+impl Default for RobustnessConfig {
+    fn default() -> Self {
+        RobustnessConfig {
+            simulations: 1000,
+            block_size: 21,
+            confidence: 0.95,
+            ruin_threshold: 0.5,
+            seed: 42,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Interval {
+    pub lower: f64,
+    pub median: f64,
+    pub upper: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RobustnessReport {
+    pub method: &'static str,
+    pub simulations: usize,
+    pub confidence: f64,
+    pub total_return: Interval,
+    pub sharpe_ratio: Interval,
+    pub max_drawdown: Interval,
+    pub probability_of_ruin: f64,
+    // Lower, median and upper percentile of the simulated paths at every step, starting at 1
+    #[serde(skip)]
+    pub bands: [Vec<f64>; 3],
+    // A few simulated paths, starting at 1
+    #[serde(skip)]
+    pub sample_paths: Vec<Vec<f64>>,
+}
+
+// Number of simulated paths kept for charts
+const SAMPLE_PATHS: usize = 20;
+
+pub fn block_bootstrap(
+    returns: &[f64],
+    config: &RobustnessConfig,
+    periods_per_year: f64,
+    risk_free_rate: f64,
+) -> Option<RobustnessReport> {
+    // Resample the per-bar returns in blocks of consecutive bars
+    if returns.len() < 2 || config.simulations == 0 {
+        return None;
+    }
+    let block_size = config.block_size.clamp(1, returns.len());
+    let mut rng = StdRng::seed_from_u64(config.seed);
+    let paths: Vec<Vec<f64>> = (0..config.simulations)
+        .map(|_| {
+            let mut sample = Vec::with_capacity(returns.len());
+            while sample.len() < returns.len() {
+                let start = rng.gen_range(0..=returns.len() - block_size);
+                let take = block_size.min(returns.len() - sample.len());
+                sample.extend_from_slice(&returns[start..start + take]);
+            }
+            sample
+        })
+        .collect();
+    Some(summarize("block bootstrap", paths, config, periods_per_year, risk_free_rate))
+}
+
+pub fn shuffle_trades(
+    trade_returns: &[f64],
+    config: &RobustnessConfig,
+    trades_per_year: f64,
+    risk_free_rate: f64,
+) -> Option<RobustnessReport> {
+    // Compound the returns of the trades, relative to the equity they were made with, in random order
+    if trade_returns.len() < 2 || config.simulations == 0 {
+        return None;
+    }
+    let mut rng = StdRng::seed_from_u64(config.seed);
+    let mut order = trade_returns.to_vec();
+    let paths: Vec<Vec<f64>> = (0..config.simulations)
+        .map(|_| {
+            order.shuffle(&mut rng);
+            order.clone()
+        })
+        .collect();
+    let mut report = summarize("trade shuffle", paths, config, trades_per_year, risk_free_rate);
+
+    // Every permutation ends at the same total return with the same Sharpe ratio, bootstrap the trades for them
+    let samples: Vec<Vec<f64>> = (0..config.simulations)
+        .map(|_| {
+            (0..trade_returns.len())
+                .map(|_| trade_returns[rng.gen_range(0..trade_returns.len())])
+                .collect()
+        })
+        .collect();
+    let total_returns: Vec<f64> = samples.iter().map(|returns| total_return(returns)).collect();
+    let sharpe_ratios: Vec<f64> = samples
+        .iter()
+        .map(|returns| sharpe_ratio(returns, trades_per_year, risk_free_rate))
+        .collect();
+    let tail = (1.0 - config.confidence) / 2.0;
+    report.total_return = interval(&total_returns, tail);
+    report.sharpe_ratio = interval(&sharpe_ratios, tail);
+    Some(report)
+}
+
+fn summarize(
+    method: &'static str,
+    simulated_returns: Vec<Vec<f64>>,
+    config: &RobustnessConfig,
+    periods_per_year: f64,
+    risk_free_rate: f64,
+) -> RobustnessReport {
+    let paths: Vec<Vec<f64>> = simulated_returns
+        .iter()
+        .map(|returns| {
+            let mut value = 1.0;
+            std::iter::once(1.0)
+                .chain(returns.iter().map(|r| {
+                    value *= 1.0 + r;
+                    value
+                }))
+                .collect()
+        })
+        .collect();
+
+    let total_returns: Vec<f64> = simulated_returns.iter().map(|returns| total_return(returns)).collect();
+    let sharpe_ratios: Vec<f64> = simulated_returns
+        .iter()
+        .map(|returns| sharpe_ratio(returns, periods_per_year, risk_free_rate))
+        .collect();
+    let max_drawdowns: Vec<f64> = paths.iter().map(|path| max_drawdown(path)).collect();
+    let ruined = paths
+        .iter()
+        .filter(|path| path.iter().any(|&value| value <= 1.0 - config.ruin_threshold))
+        .count();
+
+    let tail = (1.0 - config.confidence) / 2.0;
+    let steps = paths[0].len();
+    let mut bands = [Vec::new(), Vec::new(), Vec::new()];
+    for step in 0..steps {
+        let values: Vec<f64> = paths.iter().map(|path| path[step]).collect();
+        let Interval {
+            lower,
+            median,
+            upper,
+        } = interval(&values, tail);
+        bands[0].push(lower);
+        bands[1].push(median);
+        bands[2].push(upper);
+    }
+
+    RobustnessReport {
+        method,
+        simulations: paths.len(),
+        confidence: config.confidence,
+        total_return: interval(&total_returns, tail),
+        sharpe_ratio: interval(&sharpe_ratios, tail),
+        max_drawdown: interval(&max_drawdowns, tail),
+        probability_of_ruin: ruined as f64 / paths.len() as f64,
+        bands,
+        sample_paths: paths.into_iter().take(SAMPLE_PATHS).collect(),
+    }
+}
+
+fn interval(values: &[f64], tail: f64) -> Interval {
+    // Percentile interval, NaN values are left out
+    let mut sorted: Vec<f64> = values.iter().copied().filter(|v| !v.is_nan()).collect();
+    sorted.sort_by(f64::total_cmp);
+    let at = |fraction: f64| {
+        if sorted.is_empty() {
+            return f64::NAN;
+        }
+        let rank = fraction * (sorted.len() - 1) as f64;
+        let (lower, upper) = (rank.floor() as usize, rank.ceil() as usize);
+        sorted[lower] + (sorted[upper] - sorted[lower]) * (rank - lower as f64)
+    };
+    Interval {
+        lower: at(tail),
+        median: at(0.5),
+        upper: at(1.0 - tail),
+    }
+}
+
+fn total_return(returns: &[f64]) -> f64 {
+    returns.iter().fold(1.0, |value, r| value * (1.0 + r)) - 1.0
+}
+
+fn sharpe_ratio(returns: &[f64], periods_per_year: f64, risk_free_rate: f64) -> f64 {
+    let n = returns.len() as f64;
+    let mean = returns.iter().sum::<f64>() / n;
+    let variance = returns.iter().map(|&r| (r - mean).powi(2)).sum::<f64>() / (n - 1.0);
+    (mean * periods_per_year - risk_free_rate) / (variance.sqrt() * periods_per_year.sqrt())
+}
+
+fn max_drawdown(path: &[f64]) -> f64 {
+    let mut peak = f64::NEG_INFINITY;
+    path.iter().fold(0.0, |max_drawdown: f64, &value| {
+        peak = peak.max(value);
+        max_drawdown.min(value / peak - 1.0)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_block_bootstrap() {
+        let returns: Vec<f64> = (0..100).map(|i| if i % 2 == 0 { 0.01 } else { -0.005 }).collect();
+        let config = RobustnessConfig {
+            simulations: 200,
+            block_size: 10,
+            ..RobustnessConfig::default()
+        };
+        let report = block_bootstrap(&returns, &config, 252., 0.).unwrap();
+        assert_eq!(report.simulations, 200);
+        assert!(report.total_return.lower <= report.total_return.median);
+        assert!(report.total_return.median <= report.total_return.upper);
+        assert!(report.max_drawdown.upper <= 0.);
+        assert_eq!(report.probability_of_ruin, 0.);
+        assert_eq!(report.bands[1].len(), 101);
+        assert_eq!(report.sample_paths.len(), SAMPLE_PATHS);
+
+        // Blocks of an even size starting anywhere hold as many gains as losses
+        let config = RobustnessConfig {
+            block_size: 2,
+            ..config
+        };
+        let report = block_bootstrap(&returns, &config, 252., 0.).unwrap();
+        let expected = (1.01f64 * 0.995).powi(50) - 1.;
+        assert!((report.total_return.lower - expected).abs() < 1e-9);
+        assert!((report.total_return.upper - expected).abs() < 1e-9);
+
+        // The same seed gives the same result
+        let again = block_bootstrap(&returns, &config, 252., 0.).unwrap();
+        assert_eq!(report.sharpe_ratio.median, again.sharpe_ratio.median);
+    }
+
+    #[test]
+    fn test_shuffle_trades() {
+        let trade_returns = [0.05, -0.03, 0.02, -0.06, 0.04];
+        let config = RobustnessConfig {
+            simulations: 500,
+            ruin_threshold: 0.05,
+            ..RobustnessConfig::default()
+        };
+        let report = shuffle_trades(&trade_returns, &config, 50., 0.).unwrap();
+        // Every order compounds to the same return, the trades drawn with replacement spread it around
+        let expected = total_return(&trade_returns);
+        assert!(report.total_return.lower < expected - 0.01);
+        assert!(report.total_return.upper > expected + 0.01);
+        assert!(report.sharpe_ratio.lower < report.sharpe_ratio.upper);
+        // Within the best and the worst draw, all winners or all losers
+        assert!(report.total_return.upper < 1.05f64.powi(5) - 1.);
+        assert!(report.total_return.lower > 0.94f64.powi(5) - 1.);
+        // The worst order loses both losers in a row from the start, the best only the largest loser
+        assert!((report.max_drawdown.lower - (0.97 * 0.94 - 1.)).abs() < 0.01);
+        assert!(report.max_drawdown.upper > -0.07);
+        // Losing 5% or more from the start is possible but not certain
+        assert!(report.probability_of_ruin > 0. && report.probability_of_ruin < 1.);
+    }
+}
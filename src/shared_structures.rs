@@ -1,61 +1,441 @@
 use crate::util::Counter;
-use std::collections::HashMap;
+use chrono::{DateTime, Duration, FixedOffset, NaiveDate, NaiveDateTime, TimeZone};
+use crossbeam::channel::Sender;
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::any::{Any, TypeId};
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
 use std::hash::{Hash, Hasher};
-use std::sync::Mutex;
+use std::ops::Deref;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
 
 lazy_static::lazy_static! {
 static ref EVENT_ID_COUNTER_MDE: Mutex<Counter> = Mutex::new(Counter::new());
+static ref EVENT_ID_COUNTER_MDBE: Mutex<Counter> = Mutex::new(Counter::new());
 static ref EVENT_ID_COUNTER_OPE: Mutex<Counter> = Mutex::new(Counter::new());
-static ref EVENT_ID_COUNTER_PIE: Mutex<Counter> = Mutex::new(Counter::new());}
+static ref EVENT_ID_COUNTER_PIE: Mutex<Counter> = Mutex::new(Counter::new());
+static ref EVENT_ID_COUNTER_EDE: Mutex<Counter> = Mutex::new(Counter::new());
+static ref EVENT_ID_COUNTER_BE: Mutex<Counter> = Mutex::new(Counter::new());
+static ref EVENT_ID_COUNTER_OAE: Mutex<Counter> = Mutex::new(Counter::new());
+static ref EVENT_ID_COUNTER_OFE: Mutex<Counter> = Mutex::new(Counter::new());
+static ref EVENT_ID_COUNTER_OPFE: Mutex<Counter> = Mutex::new(Counter::new());
+static ref EVENT_ID_COUNTER_ORE: Mutex<Counter> = Mutex::new(Counter::new());
+static ref EVENT_ID_COUNTER_OCE: Mutex<Counter> = Mutex::new(Counter::new());
+static ref EVENT_ID_COUNTER_OCNE: Mutex<Counter> = Mutex::new(Counter::new());
+static ref EVENT_ID_COUNTER_OME: Mutex<Counter> = Mutex::new(Counter::new());
+static ref EVENT_ID_COUNTER_OMDE: Mutex<Counter> = Mutex::new(Counter::new());
+static ref EVENT_ID_COUNTER_OCRE: Mutex<Counter> = Mutex::new(Counter::new());
+static ref EVENT_ID_COUNTER_OMRE: Mutex<Counter> = Mutex::new(Counter::new());
+static ref EVENT_ID_COUNTER_MCE: Mutex<Counter> = Mutex::new(Counter::new());
+static ref EVENT_ID_COUNTER_TE: Mutex<Counter> = Mutex::new(Counter::new());
+static ref EVENT_ID_COUNTER_STE: Mutex<Counter> = Mutex::new(Counter::new());
+static ref EVENT_ID_COUNTER_CTE: Mutex<Counter> = Mutex::new(Counter::new());
+static ref TIMER_ID_COUNTER: Mutex<Counter> = Mutex::new(Counter::new());
+static ref EVENT_ID_COUNTER_CE: Mutex<Counter> = Mutex::new(Counter::new());
+static ref EVENT_ID_COUNTER_SE: Mutex<Counter> = Mutex::new(Counter::new());
+static ref EVENT_ID_COUNTER_USE: Mutex<Counter> = Mutex::new(Counter::new());
+static ref ORDER_ID_COUNTER: Mutex<Counter> = Mutex::new(Counter::new());}
+
+// Timestamps
+/*
+Point in time of a bar, fill or order, in the timezone of the market data.
+Timestamps compare and hash by the instant they stand for, and the calendar
+of the market (date, weekday, hour) is available through chrono.
+They are displayed as "2024-01-02 04:00:00", in the timezone of the data,
+and written to reports and exported files in RFC 3339, offset included.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Timestamp(pub DateTime<FixedOffset>);
+
+impl Deref for Timestamp {
+    type Target = DateTime<FixedOffset>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0.format("%Y-%m-%d %H:%M:%S"))
+    }
+}
+
+impl Serialize for Timestamp {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        // Written in RFC 3339 in reports, and exactly, offset included, in binary formats
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&self.0.to_rfc3339())
+        } else {
+            (self.0.timestamp(), self.0.timestamp_subsec_nanos(), self.0.offset().local_minus_utc())
+                .serialize(serializer)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Timestamp {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        if deserializer.is_human_readable() {
+            let text = String::deserialize(deserializer)?;
+            return text.parse().map_err(D::Error::custom);
+        }
+        let (seconds, nanos, offset) = <(i64, u32, i32)>::deserialize(deserializer)?;
+        let offset = FixedOffset::east_opt(offset).ok_or_else(|| D::Error::custom("Invalid timezone offset"))?;
+        DateTime::from_timestamp(seconds, nanos)
+            .map(|datetime| Timestamp(datetime.with_timezone(&offset)))
+            .ok_or_else(|| D::Error::custom("Invalid timestamp"))
+    }
+}
+
+impl FromStr for Timestamp {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        // "2024-01-02 04:00:00", "2024-01-02T04:00:00", "2024-01-02" or RFC 3339, in UTC unless given
+        if let Ok(datetime) = DateTime::parse_from_rfc3339(text) {
+            return Ok(Timestamp(datetime));
+        }
+        ["%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M:%S", "%Y-%m-%d"]
+            .iter()
+            .find_map(|format| TimestampFormat::new(format, FixedOffset::east_opt(0).unwrap()).parse(text).ok())
+            .ok_or_else(|| format!("Invalid timestamp: {}", text))
+    }
+}
+
+/*
+How the market data feeders read timestamps: a chrono format string and the
+timezone of the timestamps that do not carry an offset (%z) themselves.
+A format with only a date gives midnight.
+*/
+#[derive(Debug, Clone)]
+pub struct TimestampFormat {
+    pub format: String,
+    pub timezone: FixedOffset,
+}
+
+impl Default for TimestampFormat {
+    fn default() -> Self {
+        TimestampFormat::new("%Y-%m-%d %H:%M:%S", FixedOffset::east_opt(0).unwrap())
+    }
+}
+
+impl TimestampFormat {
+    pub fn new(format: &str, timezone: FixedOffset) -> Self {
+        TimestampFormat {
+            format: format.to_string(),
+            timezone,
+        }
+    }
+
+    pub fn parse(&self, text: &str) -> Result<Timestamp, String> {
+        if let Ok(datetime) = DateTime::parse_from_str(text, &self.format) {
+            return Ok(Timestamp(datetime));
+        }
+        let local = NaiveDateTime::parse_from_str(text, &self.format)
+            .or_else(|_| {
+                NaiveDate::parse_from_str(text, &self.format)
+                    .map(|date| date.and_hms_opt(0, 0, 0).unwrap())
+            })
+            .map_err(|err| format!("Invalid timestamp {} for format {}: {}", text, self.format, err))?;
+        self.timezone
+            .from_local_datetime(&local)
+            .single()
+            .map(Timestamp)
+            .ok_or_else(|| format!("Invalid timestamp {} in timezone {}", text, self.timezone))
+    }
+}
 
 // Events
-#[derive(Debug, Hash, Eq, PartialEq, Clone)]
+#[derive(Debug, Hash, Eq, PartialEq, Clone, Serialize, Deserialize)]
 pub enum Event {
     MarketData(MarketDataEvent),
+    MarketDataBatch(MarketDataBatchEvent),
     OrderPlace(OrderPlaceEvent),
     PortfolioInfo(PortfolioInfoEvent),
     ShutDown(ShutDownEvent),
+    EndOfData(EndOfDataEvent),
+    Barrier(BarrierEvent),
+    OrderAccepted(OrderAcceptedEvent),
+    OrderFilled(OrderFilledEvent),
+    OrderPartiallyFilled(OrderPartiallyFilledEvent),
+    OrderRejected(OrderRejectedEvent),
+    OrderCancelled(OrderCancelledEvent),
+    // Sent by strategies, which may not use them
+    OrderCancel(OrderCancelEvent),
+    OrderModify(OrderModifyEvent),
+    OrderModified(OrderModifiedEvent),
+    OrderCancelRejected(OrderCancelRejectedEvent),
+    OrderModifyRejected(OrderModifyRejectedEvent),
+    MarginCall(MarginCallEvent),
+    Timer(TimerEvent),
+    // Handled by the event_manager's clock, never dispatched to modules
+    #[serde(skip)]
+    ScheduleTimer(ScheduleTimerEvent),
+    #[serde(skip)]
+    CancelTimer(CancelTimerEvent),
+    // User-defined events, routed by the type of their payload, not serializable
+    #[serde(skip)]
+    Custom(CustomEvent),
+    // Subscription changes while running, handled by the event_manager, never dispatched to modules
+    #[serde(skip)]
+    Subscribe(SubscribeEvent),
+    #[serde(skip)]
+    Unsubscribe(UnsubscribeEvent),
 }
 impl Event {
-    pub fn new_market_data(
-        timestamp: String,
-        symbol: String,
-        open: f64,
-        close: f64,
-        high: f64,
-        low: f64,
-        volume: i32,
-    ) -> Self {
-        let id = EVENT_ID_COUNTER_MDE.lock().unwrap().next();
-        Event::MarketData(MarketDataEvent {
+    pub fn new_market_data_batch(timestamp: Timestamp, bars: Vec<MarketDataEvent>) -> Self {
+        let id = EVENT_ID_COUNTER_MDBE.lock().unwrap().next();
+        Event::MarketDataBatch(MarketDataBatchEvent {
             id,
-            symbol,
             timestamp,
-            open,
-            close,
-            high,
-            low,
-            volume,
+            bars,
         })
     }
 
     pub fn new_order_place(order: Order) -> Self {
         let id = EVENT_ID_COUNTER_OPE.lock().unwrap().next();
-        Event::OrderPlace(OrderPlaceEvent { id, order })
+        let order_id = ORDER_ID_COUNTER.lock().unwrap().next();
+        Event::OrderPlace(OrderPlaceEvent { id, order_id, order })
     }
 
-    pub fn new_portfolio_info(portfolio: Portfolio) -> Self {
+    pub fn new_portfolio_info(portfolio: Portfolio, ledger_entries: Vec<LedgerEntry>) -> Self {
         let id = EVENT_ID_COUNTER_PIE.lock().unwrap().next();
-        Event::PortfolioInfo(PortfolioInfoEvent { id, portfolio })
+        Event::PortfolioInfo(PortfolioInfoEvent {
+            id,
+            portfolio,
+            ledger_entries,
+        })
     }
 
     pub fn new_shut_down() -> Self {
         let id = 1;
         Event::ShutDown(ShutDownEvent { id })
     }
+
+    pub fn new_end_of_data() -> Self {
+        let id = EVENT_ID_COUNTER_EDE.lock().unwrap().next();
+        Event::EndOfData(EndOfDataEvent { id })
+    }
+
+    pub fn new_barrier() -> Self {
+        let id = EVENT_ID_COUNTER_BE.lock().unwrap().next();
+        Event::Barrier(BarrierEvent { id })
+    }
+
+    pub fn new_order_accepted(order_id: u64) -> Self {
+        let id = EVENT_ID_COUNTER_OAE.lock().unwrap().next();
+        Event::OrderAccepted(OrderAcceptedEvent { id, order_id })
+    }
+
+    pub fn new_order_filled(order_id: u64, fill: Fill) -> Self {
+        let id = EVENT_ID_COUNTER_OFE.lock().unwrap().next();
+        Event::OrderFilled(OrderFilledEvent { id, order_id, fill })
+    }
+
+    pub fn new_order_partially_filled(order_id: u64, fill: Fill, remaining: i32) -> Self {
+        let id = EVENT_ID_COUNTER_OPFE.lock().unwrap().next();
+        Event::OrderPartiallyFilled(OrderPartiallyFilledEvent {
+            id,
+            order_id,
+            fill,
+            remaining,
+        })
+    }
+
+    pub fn new_order_rejected(order_id: u64, reason: String) -> Self {
+        let id = EVENT_ID_COUNTER_ORE.lock().unwrap().next();
+        Event::OrderRejected(OrderRejectedEvent {
+            id,
+            order_id,
+            reason,
+        })
+    }
+
+    pub fn new_order_cancelled(order_id: u64, reason: String) -> Self {
+        let id = EVENT_ID_COUNTER_OCE.lock().unwrap().next();
+        Event::OrderCancelled(OrderCancelledEvent {
+            id,
+            order_id,
+            reason,
+        })
+    }
+
+    pub fn new_order_cancel(order_id: u64) -> Self {
+        let id = EVENT_ID_COUNTER_OCNE.lock().unwrap().next();
+        Event::OrderCancel(OrderCancelEvent { id, order_id })
+    }
+
+    pub fn new_order_modify(order_id: u64, order: Order) -> Self {
+        let id = EVENT_ID_COUNTER_OME.lock().unwrap().next();
+        Event::OrderModify(OrderModifyEvent {
+            id,
+            order_id,
+            order,
+        })
+    }
+
+    pub fn new_order_modified(order_id: u64) -> Self {
+        let id = EVENT_ID_COUNTER_OMDE.lock().unwrap().next();
+        Event::OrderModified(OrderModifiedEvent { id, order_id })
+    }
+
+    pub fn new_order_cancel_rejected(order_id: u64, reason: String) -> Self {
+        let id = EVENT_ID_COUNTER_OCRE.lock().unwrap().next();
+        Event::OrderCancelRejected(OrderCancelRejectedEvent {
+            id,
+            order_id,
+            reason,
+        })
+    }
+
+    pub fn new_order_modify_rejected(order_id: u64, reason: String) -> Self {
+        let id = EVENT_ID_COUNTER_OMRE.lock().unwrap().next();
+        Event::OrderModifyRejected(OrderModifyRejectedEvent {
+            id,
+            order_id,
+            reason,
+        })
+    }
+
+    pub fn new_margin_call(
+        timestamp: Timestamp,
+        equity: f64,
+        requirement: f64,
+        fills: Vec<Fill>,
+    ) -> Self {
+        let id = EVENT_ID_COUNTER_MCE.lock().unwrap().next();
+        Event::MarginCall(MarginCallEvent {
+            id,
+            timestamp,
+            equity,
+            requirement,
+            fills,
+        })
+    }
+
+    pub fn new_timer(timer_id: u64, name: String, timestamp: Timestamp) -> Self {
+        let id = EVENT_ID_COUNTER_TE.lock().unwrap().next();
+        Event::Timer(TimerEvent {
+            id,
+            timer_id,
+            name,
+            timestamp,
+        })
+    }
+
+    pub fn new_schedule_timer(name: String, at: Timestamp, every: Option<Duration>) -> Self {
+        // The timer id is assigned here, TimerEvents of the timer carry it
+        let id = EVENT_ID_COUNTER_STE.lock().unwrap().next();
+        let timer_id = TIMER_ID_COUNTER.lock().unwrap().next();
+        Event::ScheduleTimer(ScheduleTimerEvent {
+            id,
+            timer_id,
+            name,
+            at,
+            every,
+        })
+    }
+
+    pub fn new_cancel_timer(timer_id: u64) -> Self {
+        let id = EVENT_ID_COUNTER_CTE.lock().unwrap().next();
+        Event::CancelTimer(CancelTimerEvent { id, timer_id })
+    }
+
+    pub fn new_custom<E: Any + Send + Sync>(payload: E) -> Self {
+        // Subscribers of E receive the event, see CustomEvent
+        let id = EVENT_ID_COUNTER_CE.lock().unwrap().next();
+        Event::Custom(CustomEvent {
+            id,
+            type_id: TypeId::of::<E>(),
+            type_name: std::any::type_name::<E>(),
+            payload: Arc::new(payload),
+        })
+    }
+
+    pub fn new_subscribe<E: 'static>(sender: Sender<Event>, filter: Option<EventFilter>) -> Self {
+        // Subscribe the module with this sender (ModuleReceive::get_sender) to E
+        let id = EVENT_ID_COUNTER_SE.lock().unwrap().next();
+        Event::Subscribe(SubscribeEvent {
+            id,
+            type_id: TypeId::of::<E>(),
+            sender,
+            filter,
+        })
+    }
+
+    pub fn new_unsubscribe<E: 'static>(sender: Sender<Event>) -> Self {
+        let id = EVENT_ID_COUNTER_USE.lock().unwrap().next();
+        Event::Unsubscribe(UnsubscribeEvent {
+            id,
+            type_id: Some(TypeId::of::<E>()),
+            sender,
+        })
+    }
+
+    pub fn new_unsubscribe_all(sender: Sender<Event>) -> Self {
+        // Remove the module from every event type, e.g. before it stops
+        let id = EVENT_ID_COUNTER_USE.lock().unwrap().next();
+        Event::Unsubscribe(UnsubscribeEvent {
+            id,
+            type_id: None,
+            sender,
+        })
+    }
+
+    pub fn symbol(&self) -> Option<&str> {
+        // The symbol an event is about, None for batches and events about no single symbol
+        match self {
+            Event::MarketData(event) => Some(&event.symbol),
+            Event::OrderPlace(event) => Some(event.order.symbol()),
+            Event::OrderModify(event) => Some(event.order.symbol()),
+            Event::OrderFilled(event) => Some(&event.fill.symbol),
+            Event::OrderPartiallyFilled(event) => Some(&event.fill.symbol),
+            _ => None,
+        }
+    }
+
+    pub fn order_id(&self) -> Option<u64> {
+        // The order an order lifecycle event refers to
+        match self {
+            Event::OrderPlace(event) => Some(event.order_id),
+            Event::OrderAccepted(event) => Some(event.order_id),
+            Event::OrderFilled(event) => Some(event.order_id),
+            Event::OrderPartiallyFilled(event) => Some(event.order_id),
+            Event::OrderRejected(event) => Some(event.order_id),
+            Event::OrderCancelled(event) => Some(event.order_id),
+            Event::OrderCancel(event) => Some(event.order_id),
+            Event::OrderModify(event) => Some(event.order_id),
+            Event::OrderModified(event) => Some(event.order_id),
+            Event::OrderCancelRejected(event) => Some(event.order_id),
+            Event::OrderModifyRejected(event) => Some(event.order_id),
+            _ => None,
+        }
+    }
+
+    pub fn order_id_mut(&mut self) -> Option<&mut u64> {
+        // The same, to point the event at another order
+        match self {
+            Event::OrderPlace(event) => Some(&mut event.order_id),
+            Event::OrderAccepted(event) => Some(&mut event.order_id),
+            Event::OrderFilled(event) => Some(&mut event.order_id),
+            Event::OrderPartiallyFilled(event) => Some(&mut event.order_id),
+            Event::OrderRejected(event) => Some(&mut event.order_id),
+            Event::OrderCancelled(event) => Some(&mut event.order_id),
+            Event::OrderCancel(event) => Some(&mut event.order_id),
+            Event::OrderModify(event) => Some(&mut event.order_id),
+            Event::OrderModified(event) => Some(&mut event.order_id),
+            Event::OrderCancelRejected(event) => Some(&mut event.order_id),
+            Event::OrderModifyRejected(event) => Some(&mut event.order_id),
+            _ => None,
+        }
+    }
 }
 // ShutDownEvent
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ShutDownEvent {
     pub id: u64,
 }
@@ -74,13 +454,62 @@ impl Hash for ShutDownEvent {
     }
 }
 
+// EndOfDataEvent
+/*
+Published by a data feeder once its data is exhausted. The event_manager
+counts these to know when every feeder has finished.
+*/
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EndOfDataEvent {
+    pub id: u64,
+}
+
+impl PartialEq for EndOfDataEvent {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+impl Eq for EndOfDataEvent {}
+
+impl Hash for EndOfDataEvent {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.id.hash(state);
+    }
+}
+
+// BarrierEvent
+/*
+Sent by the event_manager directly to every subscriber to wait until
+they are done with all previously dispatched events. Modules should
+simply ignore it.
+*/
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BarrierEvent {
+    pub id: u64,
+}
+
+impl PartialEq for BarrierEvent {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+impl Eq for BarrierEvent {}
+
+impl Hash for BarrierEvent {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.id.hash(state);
+    }
+}
+
 // MarketDataEvent
 #[allow(dead_code)]
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MarketDataEvent {
     pub id: u64,
     pub symbol: String,
-    pub timestamp: String,
+    pub timestamp: Timestamp,
     pub open: f64,
     pub close: f64,
     pub high: f64,
@@ -88,6 +517,30 @@ pub struct MarketDataEvent {
     pub volume: i32,
 }
 
+impl MarketDataEvent {
+    pub fn new(
+        timestamp: Timestamp,
+        symbol: String,
+        open: f64,
+        close: f64,
+        high: f64,
+        low: f64,
+        volume: i32,
+    ) -> Self {
+        let id = EVENT_ID_COUNTER_MDE.lock().unwrap().next();
+        MarketDataEvent {
+            id,
+            symbol,
+            timestamp,
+            open,
+            close,
+            high,
+            low,
+            volume,
+        }
+    }
+}
+
 impl PartialEq for MarketDataEvent {
     fn eq(&self, other: &Self) -> bool {
         self.id == other.id // Events are equal if their `id` is the same
@@ -102,25 +555,116 @@ impl Hash for MarketDataEvent {
     }
 }
 
+// MarketDataBatchEvent
+/*
+All bars of a universe sharing the same timestamp, published together
+so that modules see a consistent cross-section of the market.
+*/
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MarketDataBatchEvent {
+    pub id: u64,
+    pub timestamp: Timestamp,
+    pub bars: Vec<MarketDataEvent>,
+}
+
+impl PartialEq for MarketDataBatchEvent {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+impl Eq for MarketDataBatchEvent {}
+
+impl Hash for MarketDataBatchEvent {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.id.hash(state);
+    }
+}
+
 // OrderPlaceEvent
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct OrderPlaceEvent {
     pub id: u64,
+    // Unique across the run, used to refer to the order afterwards
+    pub order_id: u64,
     pub order: Order,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Order {
     // FireAndDrop(FireAndDropOrder),
+    Market(MarketOrder),
     LimitPrice(LimitPriceOrder),
+    Stop(StopOrder),
+    StopLimit(StopLimitOrder),
+    TrailingStop(TrailingStopOrder),
+}
+
+impl Order {
+    pub fn symbol(&self) -> &str {
+        match self {
+            Order::Market(order) => &order.symbol,
+            Order::LimitPrice(order) => &order.symbol,
+            Order::Stop(order) => &order.symbol,
+            Order::StopLimit(order) => &order.symbol,
+            Order::TrailingStop(order) => &order.symbol,
+        }
+    }
+
+    pub fn amount(&self) -> i32 {
+        match self {
+            Order::Market(order) => order.amount,
+            Order::LimitPrice(order) => order.amount,
+            Order::Stop(order) => order.amount,
+            Order::StopLimit(order) => order.amount,
+            Order::TrailingStop(order) => order.amount,
+        }
+    }
+
+    pub fn time_in_force(&self) -> &TimeInForce {
+        match self {
+            Order::Market(order) => &order.time_in_force,
+            Order::LimitPrice(order) => &order.time_in_force,
+            Order::Stop(order) => &order.time_in_force,
+            Order::StopLimit(order) => &order.time_in_force,
+            Order::TrailingStop(order) => &order.time_in_force,
+        }
+    }
+
+    pub fn direction(&self) -> OrderDirection {
+        match self {
+            Order::Market(order) => order.direction,
+            Order::LimitPrice(order) => order.direction,
+            Order::Stop(order) => order.direction,
+            Order::StopLimit(order) => order.direction,
+            Order::TrailingStop(order) => order.direction,
+        }
+    }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub enum OrderDirection {
     Buy,
     Sell,
 }
 
+/*
+How long an order stays in the exchange if it is not filled.
+*/
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum TimeInForce {
+    // Expires once a bar from a later trading day arrives
+    Day,
+    // Good till cancelled
+    Gtc,
+    // Immediate or cancel: whatever does not fill on the next bar is cancelled
+    Ioc,
+    // Fill or kill: fills entirely on the next bar or is cancelled
+    Fok,
+    // Good till date: expires after the given timestamp, use the end of a day to keep it through that day
+    Gtd(Timestamp),
+}
+
 // #[derive(Debug, Clone)]
 // pub struct FireAndDropOrder {
 //     pub symbol: String,
@@ -128,12 +672,76 @@ pub enum OrderDirection {
 //     pub direction: OrderDirection,
 // }
 
-#[derive(Debug, Clone)]
+/*
+Fills on the next bar of its symbol at the exchange's fill price.
+*/
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MarketOrder {
+    pub symbol: String,
+    pub amount: i32,
+    pub direction: OrderDirection,
+    pub time_in_force: TimeInForce,
+}
+
+/*
+A buy fills once the bar trades at or below limit_price, a sell once it
+trades at or above. The fill is never worse than limit_price.
+*/
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LimitPriceOrder {
     pub symbol: String,
     pub amount: i32,
     pub limit_price: f64,
     pub direction: OrderDirection,
+    pub time_in_force: TimeInForce,
+}
+
+/*
+Becomes a market order once the bar trades through stop_price (at or above
+for a buy, at or below for a sell). The fill is never better than stop_price.
+*/
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StopOrder {
+    pub symbol: String,
+    pub amount: i32,
+    pub stop_price: f64,
+    pub direction: OrderDirection,
+    pub time_in_force: TimeInForce,
+}
+
+/*
+Becomes a limit order at limit_price once the bar trades through stop_price.
+*/
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StopLimitOrder {
+    pub symbol: String,
+    pub amount: i32,
+    pub stop_price: f64,
+    pub limit_price: f64,
+    pub direction: OrderDirection,
+    pub time_in_force: TimeInForce,
+}
+
+/*
+A stop order whose stop_price follows the market. For a sell the stop sits
+trail below the highest price seen since the order was placed, for a buy
+it sits trail above the lowest price.
+*/
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrailingStopOrder {
+    pub symbol: String,
+    pub amount: i32,
+    pub trail: TrailingOffset,
+    pub direction: OrderDirection,
+    pub time_in_force: TimeInForce,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum TrailingOffset {
+    // Absolute price distance
+    Amount(f64),
+    // Fraction of the reference price, e.g. 0.05 for 5%
+    Percent(f64),
 }
 
 impl PartialEq for OrderPlaceEvent {
@@ -150,34 +758,657 @@ impl Hash for OrderPlaceEvent {
     }
 }
 
-// PortfolioInfoEvent
-#[derive(Debug, Clone)]
-pub struct PortfolioInfoEvent {
+// Order lifecycle events
+/*
+Published by the mock_exchange for every OrderPlaceEvent it receives:
+accepted first, then filled (possibly in parts), rejected or cancelled.
+*/
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Fill {
+    pub timestamp: Timestamp,
+    pub symbol: String,
+    pub direction: OrderDirection,
+    pub quantity: i32,
+    pub price: f64,
+    pub fee: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OrderAcceptedEvent {
     pub id: u64,
-    pub portfolio: Portfolio,
-    // TBD: other fields
+    pub order_id: u64,
 }
-impl PartialEq for PortfolioInfoEvent {
+
+impl PartialEq for OrderAcceptedEvent {
     fn eq(&self, other: &Self) -> bool {
-        self.id == other.id // Events are equal if their `id` is the same
+        self.id == other.id
     }
 }
 
-impl Eq for PortfolioInfoEvent {}
+impl Eq for OrderAcceptedEvent {}
 
-impl Hash for PortfolioInfoEvent {
+impl Hash for OrderAcceptedEvent {
     fn hash<H: Hasher>(&self, state: &mut H) {
-        self.id.hash(state); // Use the `id` field to compute the hash
+        self.id.hash(state);
     }
 }
 
-#[derive(Debug, Clone)]
-
-pub struct Portfolio {
-    pub asset: f64,
-    pub cash: f64,
-    pub available_cash: f64,
-    pub positions: HashMap<String, i32>,
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OrderFilledEvent {
+    pub id: u64,
+    pub order_id: u64,
+    pub fill: Fill,
+}
+
+impl PartialEq for OrderFilledEvent {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+impl Eq for OrderFilledEvent {}
+
+impl Hash for OrderFilledEvent {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.id.hash(state);
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OrderPartiallyFilledEvent {
+    pub id: u64,
+    pub order_id: u64,
+    pub fill: Fill,
+    // Quantity still to be filled
+    pub remaining: i32,
+}
+
+impl PartialEq for OrderPartiallyFilledEvent {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+impl Eq for OrderPartiallyFilledEvent {}
+
+impl Hash for OrderPartiallyFilledEvent {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.id.hash(state);
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OrderRejectedEvent {
+    pub id: u64,
+    pub order_id: u64,
+    pub reason: String,
+}
+
+impl PartialEq for OrderRejectedEvent {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+impl Eq for OrderRejectedEvent {}
+
+impl Hash for OrderRejectedEvent {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.id.hash(state);
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OrderCancelledEvent {
+    pub id: u64,
+    pub order_id: u64,
+    pub reason: String,
+}
+
+impl PartialEq for OrderCancelledEvent {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+impl Eq for OrderCancelledEvent {}
+
+impl Hash for OrderCancelledEvent {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.id.hash(state);
+    }
+}
+
+// Order cancel and modify requests
+/*
+Sent by strategies to pull or amend an order resting in the mock_exchange.
+A cancel is confirmed with an OrderCancelledEvent, a modify with an
+OrderModifiedEvent. Either can be refused, e.g. if the order is already filled.
+*/
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OrderCancelEvent {
+    pub id: u64,
+    pub order_id: u64,
+}
+
+impl PartialEq for OrderCancelEvent {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+impl Eq for OrderCancelEvent {}
+
+impl Hash for OrderCancelEvent {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.id.hash(state);
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OrderModifyEvent {
+    pub id: u64,
+    pub order_id: u64,
+    // Replaces the resting order, must keep its symbol and direction
+    pub order: Order,
+}
+
+impl PartialEq for OrderModifyEvent {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+impl Eq for OrderModifyEvent {}
+
+impl Hash for OrderModifyEvent {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.id.hash(state);
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OrderModifiedEvent {
+    pub id: u64,
+    pub order_id: u64,
+}
+
+impl PartialEq for OrderModifiedEvent {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+impl Eq for OrderModifiedEvent {}
+
+impl Hash for OrderModifiedEvent {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.id.hash(state);
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OrderCancelRejectedEvent {
+    pub id: u64,
+    pub order_id: u64,
+    pub reason: String,
+}
+
+impl PartialEq for OrderCancelRejectedEvent {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+impl Eq for OrderCancelRejectedEvent {}
+
+impl Hash for OrderCancelRejectedEvent {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.id.hash(state);
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OrderModifyRejectedEvent {
+    pub id: u64,
+    pub order_id: u64,
+    pub reason: String,
+}
+
+impl PartialEq for OrderModifyRejectedEvent {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+impl Eq for OrderModifyRejectedEvent {}
+
+impl Hash for OrderModifyRejectedEvent {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.id.hash(state);
+    }
+}
+
+// MarginCallEvent
+/*
+Published by the mock_exchange when equity falls below the maintenance
+margin and the short positions are force-liquidated.
+*/
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MarginCallEvent {
+    pub id: u64,
+    pub timestamp: Timestamp,
+    // Equity and maintenance requirement that triggered the call
+    pub equity: f64,
+    pub requirement: f64,
+    // Buys that covered the short positions
+    pub fills: Vec<Fill>,
+}
+
+impl PartialEq for MarginCallEvent {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+impl Eq for MarginCallEvent {}
+
+impl Hash for MarginCallEvent {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.id.hash(state);
+    }
+}
+
+// Timer events
+/*
+Sent by the event_manager when a timer is due on its clock: in a backtest
+the clock follows the timestamps of the market data, so a timer fires
+before the first bar later than its deadline, or right after a bar at
+the same timestamp. timestamp is the deadline of this occurrence.
+*/
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TimerEvent {
+    pub id: u64,
+    pub timer_id: u64,
+    pub name: String,
+    pub timestamp: Timestamp,
+}
+
+impl PartialEq for TimerEvent {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+impl Eq for TimerEvent {}
+
+impl Hash for TimerEvent {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.id.hash(state);
+    }
+}
+
+/*
+Published by a module to start a timer. It fires first at `at`, then again
+every `every` if it is recurring, until it is cancelled.
+*/
+#[derive(Debug, Clone)]
+pub struct ScheduleTimerEvent {
+    pub id: u64,
+    pub timer_id: u64,
+    pub name: String,
+    pub at: Timestamp,
+    pub every: Option<Duration>,
+}
+
+impl PartialEq for ScheduleTimerEvent {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+impl Eq for ScheduleTimerEvent {}
+
+impl Hash for ScheduleTimerEvent {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.id.hash(state);
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CancelTimerEvent {
+    pub id: u64,
+    pub timer_id: u64,
+}
+
+impl PartialEq for CancelTimerEvent {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+impl Eq for CancelTimerEvent {}
+
+impl Hash for CancelTimerEvent {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.id.hash(state);
+    }
+}
+
+// CustomEvent
+/*
+An event of a type defined outside the framework, such as a signal, a risk
+alert or a factor value passed between user modules. Any type can be the
+payload: publish it with Event::new_custom(payload), subscribe to it with
+event_manager.subscribe::<PayloadType, Module>(&module), and read it back
+with payload::<PayloadType>(). The payload is shared between subscribers,
+not cloned.
+*/
+#[derive(Clone)]
+pub struct CustomEvent {
+    pub id: u64,
+    pub type_id: TypeId,
+    pub type_name: &'static str,
+    payload: Arc<dyn Any + Send + Sync>,
+}
+
+impl CustomEvent {
+    pub fn payload<E: Any>(&self) -> Option<&E> {
+        // None if the payload is of another type
+        self.payload.downcast_ref::<E>()
+    }
+}
+
+impl fmt::Debug for CustomEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CustomEvent")
+            .field("id", &self.id)
+            .field("type_name", &self.type_name)
+            .finish()
+    }
+}
+
+impl PartialEq for CustomEvent {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+impl Eq for CustomEvent {}
+
+impl Hash for CustomEvent {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.id.hash(state);
+    }
+}
+
+/*
+Filter of a subscription, applied by the event_manager before it sends an
+event to the subscriber. Symbols keeps the bars, orders and fills of the
+given symbols and cuts batches down to their bars of these symbols; events
+about no single symbol, such as portfolio updates, always pass. Predicate
+keeps the events it returns true for.
+*/
+#[derive(Clone)]
+pub enum EventFilter {
+    Symbols(HashSet<String>),
+    Predicate(Arc<dyn Fn(&Event) -> bool + Send + Sync>),
+}
+
+impl EventFilter {
+    pub fn symbols(symbols: &[&str]) -> Self {
+        EventFilter::Symbols(symbols.iter().map(|symbol| symbol.to_string()).collect())
+    }
+
+    pub fn predicate(predicate: impl Fn(&Event) -> bool + Send + Sync + 'static) -> Self {
+        EventFilter::Predicate(Arc::new(predicate))
+    }
+
+    pub fn apply(&self, event: &Event) -> Option<Event> {
+        // The event to send to the subscriber, None to skip it
+        match self {
+            EventFilter::Predicate(predicate) => predicate(event).then(|| event.clone()),
+            EventFilter::Symbols(symbols) => match event {
+                Event::MarketDataBatch(market_data_batch_event) => {
+                    let bars: Vec<MarketDataEvent> = market_data_batch_event
+                        .bars
+                        .iter()
+                        .filter(|bar| symbols.contains(&bar.symbol))
+                        .cloned()
+                        .collect();
+                    (!bars.is_empty()).then(|| {
+                        Event::MarketDataBatch(MarketDataBatchEvent {
+                            id: market_data_batch_event.id,
+                            timestamp: market_data_batch_event.timestamp,
+                            bars,
+                        })
+                    })
+                }
+                event => match event.symbol() {
+                    Some(symbol) if !symbols.contains(symbol) => None,
+                    _ => Some(event.clone()),
+                },
+            },
+        }
+    }
+}
+
+impl fmt::Debug for EventFilter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EventFilter::Symbols(symbols) => f.debug_tuple("Symbols").field(symbols).finish(),
+            EventFilter::Predicate(_) => f.write_str("Predicate"),
+        }
+    }
+}
+
+/*
+Published by a module to subscribe a module to an event type while the run
+goes on, e.g. one started late. It carries the sender of the subscriber,
+from ModuleReceive::get_sender.
+*/
+#[derive(Debug, Clone)]
+pub struct SubscribeEvent {
+    pub id: u64,
+    pub type_id: TypeId,
+    pub sender: Sender<Event>,
+    pub filter: Option<EventFilter>,
+}
+
+impl PartialEq for SubscribeEvent {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+impl Eq for SubscribeEvent {}
+
+impl Hash for SubscribeEvent {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.id.hash(state);
+    }
+}
+
+// Removes the subscriptions of a module to an event type, or to all of them without a type
+#[derive(Debug, Clone)]
+pub struct UnsubscribeEvent {
+    pub id: u64,
+    pub type_id: Option<TypeId>,
+    pub sender: Sender<Event>,
+}
+
+impl PartialEq for UnsubscribeEvent {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+impl Eq for UnsubscribeEvent {}
+
+impl Hash for UnsubscribeEvent {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.id.hash(state);
+    }
+}
+
+// PortfolioInfoEvent
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PortfolioInfoEvent {
+    pub id: u64,
+    pub portfolio: Portfolio,
+    // Entries the exchange added to its ledger since the previous portfolio update
+    pub ledger_entries: Vec<LedgerEntry>,
+}
+impl PartialEq for PortfolioInfoEvent {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id // Events are equal if their `id` is the same
+    }
+}
+
+impl Eq for PortfolioInfoEvent {}
+
+impl Hash for PortfolioInfoEvent {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.id.hash(state); // Use the `id` field to compute the hash
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+
+pub struct Portfolio {
+    pub asset: f64,
+    pub cash: f64,
+    pub available_cash: f64,
+    pub positions: HashMap<String, Position>,
+    // Orders resting in the exchange, waiting to be filled
+    pub open_orders: Vec<OpenOrder>,
+}
+
+/*
+A position in one symbol, negative quantity for a short.
+cost_basis and market_value are signed like the quantity, so
+unrealized_pnl = market_value - cost_basis holds for longs and shorts.
+Positions are carried at average cost: reducing a position realizes
+the difference between the fill price and the average entry price.
+*/
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Position {
+    pub quantity: i32,
+    pub average_entry_price: f64,
+    // Quantity times the average entry price
+    pub cost_basis: f64,
+    // Quantity times the latest mark
+    pub market_value: f64,
+    pub unrealized_pnl: f64,
+    // Realized over the life of the symbol, before fees
+    pub realized_pnl: f64,
+    // Total fees paid on the symbol
+    pub fees: f64,
+    // Timestamp of the fill that opened the current position, None when flat
+    pub open_timestamp: Option<Timestamp>,
+}
+
+impl Position {
+    pub fn apply_fill(&mut self, fill: &Fill) -> f64 {
+        // Apply a fill and return the P&L it realized, before fees
+        let quantity = match fill.direction {
+            OrderDirection::Buy => fill.quantity,
+            OrderDirection::Sell => -fill.quantity,
+        };
+        self.fees += fill.fee;
+
+        let mut realized_pnl = 0.0;
+        let mut opened = quantity;
+        if self.quantity != 0 && self.quantity.signum() != quantity.signum() {
+            let closed = quantity.signum() * quantity.abs().min(self.quantity.abs());
+            realized_pnl = -closed as f64 * (fill.price - self.average_entry_price);
+            self.quantity += closed;
+            self.cost_basis = self.quantity as f64 * self.average_entry_price;
+            opened = quantity - closed;
+        }
+        if self.quantity == 0 {
+            self.average_entry_price = 0.0;
+            self.cost_basis = 0.0;
+            self.open_timestamp = None;
+        }
+
+        // What is left after closing opens or adds to a position
+        if opened != 0 {
+            if self.quantity == 0 {
+                self.open_timestamp = Some(fill.timestamp);
+            }
+            self.quantity += opened;
+            self.cost_basis += opened as f64 * fill.price;
+            self.average_entry_price = self.cost_basis / self.quantity as f64;
+        }
+
+        self.realized_pnl += realized_pnl;
+        realized_pnl
+    }
+
+    pub fn mark(&mut self, price: f64) {
+        // Value the position at the latest price
+        self.market_value = self.quantity as f64 * price;
+        self.unrealized_pnl = self.market_value - self.cost_basis;
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LedgerEntry {
+    pub fill: Fill,
+    // P&L the fill realized on its position, before fees
+    pub realized_pnl: f64,
+}
+
+// Append-only record of every fill, in execution order
+#[derive(Debug, Clone, Default)]
+pub struct Ledger {
+    entries: Vec<LedgerEntry>,
+}
+
+impl Ledger {
+    pub fn record(&mut self, fill: Fill, realized_pnl: f64) {
+        self.entries.push(LedgerEntry { fill, realized_pnl });
+    }
+
+    pub fn entries(&self) -> &[LedgerEntry] {
+        &self.entries
+    }
+
+    pub fn write_csv(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        let mut writer = csv::Writer::from_path(path)?;
+        writer.write_record([
+            "timestamp",
+            "symbol",
+            "direction",
+            "quantity",
+            "price",
+            "fee",
+            "realized_pnl",
+        ])?;
+        for entry in &self.entries {
+            let fill = &entry.fill;
+            writer.write_record([
+                fill.timestamp.to_rfc3339(),
+                fill.symbol.clone(),
+                format!("{:?}", fill.direction),
+                fill.quantity.to_string(),
+                fill.price.to_string(),
+                fill.fee.to_string(),
+                entry.realized_pnl.to_string(),
+            ])?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OpenOrder {
+    pub order_id: u64,
+    pub order: Order,
+    // Quantity not filled yet
+    pub remaining: i32,
 }
 
 impl Portfolio {
@@ -187,6 +1418,7 @@ impl Portfolio {
             cash: initial_cash,
             available_cash: initial_cash,
             positions: HashMap::new(),
+            open_orders: Vec::new(),
         }
     }
 }
@@ -194,5 +1426,13 @@ impl Portfolio {
 pub trait PortfolioUpdater {
     fn update_asset(&mut self, market_data: MarketDataEvent);
     // fn set_volume(&mut self, portfolio: &mut Portfolio);
-    fn update_fill(&mut self, symbol: String, amount: i32, price: f64, direction: OrderDirection);
+    // Returns the executed fill, or the reason the fill was refused.
+    fn update_fill(
+        &mut self,
+        timestamp: Timestamp,
+        symbol: String,
+        amount: i32,
+        price: f64,
+        direction: OrderDirection,
+    ) -> Result<Fill, String>;
 }
@@ -34,6 +34,7 @@ You can also define a structure in the strategy_helper.rs, if
 such structure will be used by multiple strategies.
 */
 #[derive(PartialEq)]
+#[allow(clippy::enum_variant_names)]
 enum LastSignal{
     IsBuy,
     IsSell,
@@ -44,7 +45,6 @@ pub struct MAcross {
     portfolio_local: Portfolio,
     moving_window: MovingWindow,
     price_factor: f64,
-    volume_factor: f32,
     last_signal: LastSignal,
     short: usize,
     long: usize,
@@ -56,13 +56,11 @@ impl MAcross{
         let portfolio_local = Portfolio::new(0.0);
         let moving_window = MovingWindow::new(long);
         let price_factor: f64 = 1.05;
-        let volume_factor: f32 = 1.;
         let last_signal = LastSignal::IsNone;
         MAcross {
             portfolio_local,
             moving_window,
             price_factor,
-            volume_factor,
             last_signal,
             short,
             long,
@@ -89,14 +87,12 @@ impl Strategy for MAcross {
         if ma_short > ma_long && self.last_signal != LastSignal::IsBuy {
             let quantity = (self.portfolio_local.available_cash / (market_data_event.close * self.price_factor)).floor() as i32;
 
-            let max_volume = (market_data_event.volume as f32 *self.volume_factor).floor() as i32;
-            let buy_volume = if quantity > max_volume {max_volume} else {quantity};
             self.last_signal = LastSignal::IsBuy;
 
             if quantity > 0 {
-                let limit_price_order = LimitPriceOrder{ symbol: market_data_event.symbol, amount: buy_volume, limit_price:market_data_event.low*2., direction: OrderDirection::Buy };
-                let order_place_event = Event::new_order_place(Order::LimitPrice(limit_price_order));
-                self.portfolio_local.available_cash = self.portfolio_local.available_cash - quantity as f64*market_data_event.close;
+                let market_order = MarketOrder{ symbol: market_data_event.symbol, amount: quantity, direction: OrderDirection::Buy, time_in_force: TimeInForce::Day };
+                let order_place_event = Event::new_order_place(Order::Market(market_order));
+                self.portfolio_local.available_cash -= quantity as f64*market_data_event.close;
                 Some(order_place_event)
             }
             else {
@@ -107,11 +103,11 @@ impl Strategy for MAcross {
         // ma_short < ma_long sell and last signal is not sell
         else if ma_short < ma_long && self.last_signal != LastSignal::IsSell {
             self.last_signal = LastSignal::IsSell;            
-            if let Some(current_position) = self.portfolio_local.positions.get(&market_data_event.symbol) {
+            if let Some(current_position) = self.portfolio_local.positions.get(&market_data_event.symbol).map(|position| position.quantity) {
 
-                if *current_position > 0 {
-                    let limit_price_order = LimitPriceOrder{ symbol: market_data_event.symbol, amount: *current_position, limit_price:market_data_event.low*0., direction: OrderDirection::Sell };
-                    let order_place_event = Event::new_order_place(Order::LimitPrice(limit_price_order));
+                if current_position > 0 {
+                    let market_order = MarketOrder{ symbol: market_data_event.symbol, amount: current_position, direction: OrderDirection::Sell, time_in_force: TimeInForce::Day };
+                    let order_place_event = Event::new_order_place(Order::Market(market_order));
                     Some(order_place_event)
                 }
                 else{
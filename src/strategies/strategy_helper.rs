@@ -55,7 +55,7 @@ mod tests {
         let mut i = 0;
         while i < 10 {
             moving_window.update(i as f32);
-            i = i +1
+            i += 1
         }
 
     }
@@ -1,15 +1,48 @@
 use crate::MarketDataEvent;
 
-use crate::event_manager::{ModulePublish, ModuleReceive};
+use crate::event_manager::{ModuleHandle, ModulePublish, ModuleReceive};
 use crate::shared_structures::*;
 use crossbeam::channel::{bounded, Receiver, Sender};
 use simplelog::*;
+use std::collections::HashMap;
 
 pub trait Strategy {
     /// Called when market data is received.
     /// Returns an optional order event if the strategy decides to trade.
     fn process(&mut self, market_data_event: MarketDataEvent) -> Option<Event>;
     fn update(&mut self, portfolio: Portfolio);
+
+    /// Called when a batch of bars sharing one timestamp is received.
+    /// By default every bar is passed to `process` in turn; override it
+    /// to trade on the whole cross-section at once.
+    fn process_batch(&mut self, bars: Vec<MarketDataEvent>) -> Vec<Event> {
+        bars.into_iter()
+            .filter_map(|bar| self.process(bar))
+            .collect()
+    }
+
+    /// Called with the lifecycle events (accepted, filled, partially filled,
+    /// rejected, cancelled) of the orders this strategy placed, and with the
+    /// answers to its cancel and modify requests.
+    fn on_order_event(&mut self, _order_event: Event) {}
+
+    /// Called when the exchange force-liquidates the short positions.
+    fn on_margin_call(&mut self, _margin_call_event: MarginCallEvent) {}
+
+    /// Called when a timer this strategy scheduled is due, or any timer
+    /// scheduled on the event_manager directly. Like `process_batch` it
+    /// returns the events to publish: orders, or timers to schedule or
+    /// cancel (`Event::new_schedule_timer`, `Event::new_cancel_timer`).
+    fn on_timer(&mut self, _timer_event: TimerEvent) -> Vec<Event> {
+        Vec::new()
+    }
+
+    /// Called with the user-defined events the strategy_manager subscribed
+    /// to, read the payload with `custom_event.payload::<E>()`. Returns the
+    /// events to publish, which may be custom events too.
+    fn on_custom_event(&mut self, _custom_event: &CustomEvent) -> Vec<Event> {
+        Vec::new()
+    }
 }
 
 pub struct StrategyManager {
@@ -19,6 +52,18 @@ pub struct StrategyManager {
     portfolio_local: Portfolio,
     strategies: Vec<Box<dyn Strategy + Send>>,
     weights: Vec<f64>,
+    // Index of the strategy that placed each live order, by order id
+    order_owners: HashMap<u64, usize>,
+    // Index of the strategy that scheduled each timer, by timer id
+    timer_owners: HashMap<u64, usize>,
+    // Index of the strategy that sent the last cancel or modify request, by order id
+    request_senders: HashMap<u64, usize>,
+}
+
+impl Default for StrategyManager {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl StrategyManager {
@@ -35,6 +80,9 @@ impl StrategyManager {
             portfolio_local,
             strategies: Vec::new(),
             weights,
+            order_owners: HashMap::new(),
+            timer_owners: HashMap::new(),
+            request_senders: HashMap::new(),
         }
     }
 
@@ -43,31 +91,10 @@ impl StrategyManager {
     }
 
     pub fn run(&mut self) {
-        if self.publish_sender.is_none() {
-            panic!("Publish sender is not initialized!");
-        }
-        if self.strategies.len() != self.weights.len() {
-            panic!("Number of weights should equal number of strategies");
-        }
-        let mut events_to_publish: Vec<Event> = Vec::new();
-
+        self.validate();
         loop {
             let event = self.subscribe_receiver.recv().unwrap();
-
-            match event {
-                Event::MarketData(market_data_event) => {
-                    // println!("Strategy: Received: {:?}", market_data_event);
-                    self.process_marketevent(market_data_event, &mut events_to_publish);
-                    // thread::sleep(time::Duration::from_secs(1));
-                }
-                Event::PortfolioInfo(portfolio_info_event) => {
-                    // println!("Strategy: Received: {:?}", portfolio_info_event);
-                    self.process_portfolioinfo(portfolio_info_event);
-                }
-                _ => {
-                    // println!("Strategy: Unsupported event: {:?}", event);
-                }
-            }
+            self.handle_event(event);
         }
     }
 
@@ -82,9 +109,97 @@ impl StrategyManager {
         }
     }
 
+    fn process_orderevent(&mut self, event: Event) {
+        /*
+        Route an order lifecycle event back to the strategy that placed the order.
+        The answers to a cancel or modify request go to the strategy that sent
+        it, which still hears that the order was already filled or cancelled, or
+        that its id is unknown, once the order is no longer tracked.
+        */
+        let order_id = match event.order_id() {
+            Some(order_id) => order_id,
+            None => return,
+        };
+        let owner = match event {
+            // Terminal events, the order is no longer tracked afterwards
+            Event::OrderFilled(_) | Event::OrderRejected(_) => self.order_owners.remove(&order_id),
+            Event::OrderCancelled(_) => {
+                self.request_senders.remove(&order_id);
+                self.order_owners.remove(&order_id)
+            }
+            Event::OrderModified(_) | Event::OrderCancelRejected(_) | Event::OrderModifyRejected(_) => self
+                .request_senders
+                .remove(&order_id)
+                .or_else(|| self.order_owners.get(&order_id).copied()),
+            _ => self.order_owners.get(&order_id).copied(),
+        };
+        match owner {
+            Some(index) => self.strategies[index].on_order_event(event),
+            None => debug!("No strategy found for order event: {:?}", event),
+        }
+    }
+
+    fn track_orders(&mut self, index: usize, events: &[Event]) {
+        // Remember which strategy placed each order, sent each request and scheduled each timer
+        for event in events {
+            match event {
+                Event::OrderPlace(order_place_event) => {
+                    self.order_owners.insert(order_place_event.order_id, index);
+                }
+                Event::OrderCancel(OrderCancelEvent { order_id, .. })
+                | Event::OrderModify(OrderModifyEvent { order_id, .. }) => {
+                    self.request_senders.insert(*order_id, index);
+                }
+                Event::ScheduleTimer(schedule_timer_event) => {
+                    self.timer_owners.insert(schedule_timer_event.timer_id, index);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn process_timer(&mut self, timer_event: TimerEvent, events: &mut Vec<Event>) {
+        // Route a timer to the strategy that scheduled it, or to all of them
+        let owners: Vec<usize> = match self.timer_owners.get(&timer_event.timer_id) {
+            Some(&index) => vec![index],
+            None => (0..self.strategies.len()).collect(),
+        };
+        for index in owners {
+            let strategy_events = self.strategies[index].on_timer(timer_event.clone());
+            self.track_orders(index, &strategy_events);
+            events.extend(strategy_events);
+        }
+        for event in events.drain(..) {
+            debug!(
+                "Publish event (timer = {}, timestamp = {}): {:?}",
+                timer_event.name, timer_event.timestamp, event
+            );
+            self.publish(event);
+        }
+    }
+
+    fn process_custom(&mut self, custom_event: CustomEvent, events: &mut Vec<Event>) {
+        // Every strategy sees the custom events, and picks the payloads it knows
+        for index in 0..self.strategies.len() {
+            let strategy_events = self.strategies[index].on_custom_event(&custom_event);
+            self.track_orders(index, &strategy_events);
+            events.extend(strategy_events);
+        }
+        for event in events.drain(..) {
+            debug!(
+                "Publish event (custom event = {}): {:?}",
+                custom_event.type_name, event
+            );
+            self.publish(event);
+        }
+    }
+
     fn process_marketevent(&mut self, market_data_event: MarketDataEvent, events: &mut Vec<Event>) {
-        for strategy in &mut self.strategies {
-            if let Some(order_place_event) = strategy.process(market_data_event.clone()) {
+        for index in 0..self.strategies.len() {
+            if let Some(order_place_event) =
+                self.strategies[index].process(market_data_event.clone())
+            {
+                self.track_orders(index, std::slice::from_ref(&order_place_event));
                 events.push(order_place_event);
             }
 
@@ -98,6 +213,26 @@ impl StrategyManager {
         }
     }
 
+    fn process_marketbatch(
+        &mut self,
+        market_data_batch_event: MarketDataBatchEvent,
+        events: &mut Vec<Event>,
+    ) {
+        for index in 0..self.strategies.len() {
+            let strategy_events =
+                self.strategies[index].process_batch(market_data_batch_event.bars.clone());
+            self.track_orders(index, &strategy_events);
+            events.extend(strategy_events);
+        }
+        for event in events.drain(..) {
+            debug!(
+                "Publish order place (batch id = {:?}, timestamp = {}): {:?}",
+                market_data_batch_event.id, market_data_batch_event.timestamp, event
+            );
+            self.publish(event);
+        }
+    }
+
     fn publish(&self, event: Event) {
         if let Some(publish_sender) = &self.publish_sender {
             publish_sender.send(event).unwrap();
@@ -118,3 +253,149 @@ impl ModulePublish for StrategyManager {
         self.publish_sender = Some(sender);
     }
 }
+
+impl ModuleHandle for StrategyManager {
+    fn validate(&self) {
+        if self.publish_sender.is_none() {
+            panic!("Publish sender is not initialized!");
+        }
+        if self.strategies.len() != self.weights.len() {
+            panic!("Number of weights should equal number of strategies");
+        }
+    }
+
+    fn handle_event(&mut self, event: Event) {
+        let mut events_to_publish: Vec<Event> = Vec::new();
+
+        match event {
+            Event::MarketData(market_data_event) => {
+                self.process_marketevent(market_data_event, &mut events_to_publish);
+            }
+            Event::MarketDataBatch(market_data_batch_event) => {
+                self.process_marketbatch(market_data_batch_event, &mut events_to_publish);
+            }
+            Event::PortfolioInfo(portfolio_info_event) => {
+                self.process_portfolioinfo(portfolio_info_event);
+            }
+            Event::OrderAccepted(_)
+            | Event::OrderFilled(_)
+            | Event::OrderPartiallyFilled(_)
+            | Event::OrderRejected(_)
+            | Event::OrderCancelled(_)
+            | Event::OrderModified(_)
+            | Event::OrderCancelRejected(_)
+            | Event::OrderModifyRejected(_) => {
+                self.process_orderevent(event);
+            }
+            Event::MarginCall(margin_call_event) => {
+                for strategy in &mut self.strategies {
+                    strategy.on_margin_call(margin_call_event.clone());
+                }
+            }
+            Event::Timer(timer_event) => {
+                self.process_timer(timer_event, &mut events_to_publish);
+            }
+            Event::Custom(custom_event) => {
+                self.process_custom(custom_event, &mut events_to_publish);
+            }
+            _ => {
+                // println!("Strategy: Unsupported event: {:?}", event);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crossbeam::channel::unbounded;
+    use std::sync::{Arc, Mutex};
+
+    // Buys on the first bar, then asks to cancel the order and one it never placed
+    struct LateCanceller {
+        log: Arc<Mutex<Vec<String>>>,
+        order_id: Option<u64>,
+    }
+
+    impl Strategy for LateCanceller {
+        fn process(&mut self, _market_data_event: MarketDataEvent) -> Option<Event> {
+            unreachable!()
+        }
+
+        fn process_batch(&mut self, bars: Vec<MarketDataEvent>) -> Vec<Event> {
+            match self.order_id {
+                None => {
+                    let order = Event::new_order_place(Order::Market(MarketOrder {
+                        symbol: bars[0].symbol.clone(),
+                        amount: 10,
+                        direction: OrderDirection::Buy,
+                        time_in_force: TimeInForce::Gtc,
+                    }));
+                    self.order_id = order.order_id();
+                    vec![order]
+                }
+                Some(order_id) => vec![Event::new_order_cancel(order_id), Event::new_order_cancel(u64::MAX)],
+            }
+        }
+
+        fn update(&mut self, _portfolio: Portfolio) {}
+
+        fn on_order_event(&mut self, order_event: Event) {
+            let entry = match order_event {
+                Event::OrderFilled(_) => "filled".to_string(),
+                Event::OrderCancelRejected(event) if Some(event.order_id) == self.order_id => {
+                    format!("cancel rejected: {}", event.reason)
+                }
+                Event::OrderCancelRejected(event) => format!("cancel of another order rejected: {}", event.reason),
+                event => format!("{:?}", event),
+            };
+            self.log.lock().unwrap().push(entry);
+        }
+    }
+
+    fn batch(day: u32) -> Event {
+        let timestamp: Timestamp = format!("2024-01-0{}", day).parse().unwrap();
+        let bar = MarketDataEvent::new(timestamp, "TEST".to_string(), 10., 10., 10., 10., 1000);
+        Event::new_market_data_batch(timestamp, vec![bar])
+    }
+
+    #[test]
+    fn test_cancel_rejections_reach_the_sender() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut strategy_manager = StrategyManager::new();
+        strategy_manager.add_strategy(Box::new(LateCanceller {
+            log: log.clone(),
+            order_id: None,
+        }));
+        let (sender, receiver) = unbounded();
+        strategy_manager.use_sender(sender);
+
+        strategy_manager.handle_event(batch(1));
+        let order_id = receiver.try_recv().unwrap().order_id().unwrap();
+        let fill = Fill {
+            timestamp: "2024-01-02".parse().unwrap(),
+            symbol: "TEST".to_string(),
+            direction: OrderDirection::Buy,
+            quantity: 10,
+            price: 10.,
+            fee: 0.,
+        };
+        strategy_manager.handle_event(Event::new_order_filled(order_id, fill));
+
+        // The order is no longer tracked, the answers go to the strategy that asked
+        strategy_manager.handle_event(batch(2));
+        let requests: Vec<Option<u64>> = receiver.try_iter().map(|event| event.order_id()).collect();
+        assert_eq!(requests, vec![Some(order_id), Some(u64::MAX)]);
+        strategy_manager.handle_event(Event::new_order_cancel_rejected(order_id, "already filled".to_string()));
+        strategy_manager.handle_event(Event::new_order_cancel_rejected(u64::MAX, "unknown id".to_string()));
+
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec![
+                "filled",
+                "cancel rejected: already filled",
+                "cancel of another order rejected: unknown id",
+            ]
+        );
+    }
+}
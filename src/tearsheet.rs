@@ -0,0 +1,427 @@
+/*
+Self-contained HTML tearsheet of a backtest.
+Charts are inline SVG and the styles and script are embedded, so the page
+has no external assets and can be shared as a single file.
+Hovering a line chart shows the values under the cursor, hovering a
+heatmap cell shows its month and return.
+*/
+use crate::shared_structures::Timestamp;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+const WIDTH: f64 = 960.0;
+const HEIGHT: f64 = 280.0;
+const MARGIN_LEFT: f64 = 70.0;
+const MARGIN_RIGHT: f64 = 20.0;
+const MARGIN_TOP: f64 = 15.0;
+const MARGIN_BOTTOM: f64 = 30.0;
+
+const STYLE: &str = "
+body { font-family: sans-serif; margin: 24px auto; max-width: 1000px; color: #222; }
+h1 { font-size: 24px; }
+h2 { font-size: 18px; margin-top: 32px; }
+table { border-collapse: collapse; font-size: 13px; }
+th, td { border: 1px solid #ddd; padding: 4px 10px; text-align: right; }
+th { background: #f4f4f4; }
+td:first-child, th:first-child { text-align: left; }
+.chart { position: relative; }
+.tooltip { position: absolute; display: none; pointer-events: none; background: rgba(255, 255, 255, 0.95);
+  border: 1px solid #999; padding: 4px 8px; font-size: 12px; white-space: pre; }
+.legend span { margin-right: 16px; font-size: 13px; }
+.scroll { max-height: 400px; overflow-y: auto; display: inline-block; }
+";
+
+// Show the values of every series at the index under the cursor
+const SCRIPT: &str = "
+document.querySelectorAll('.chart').forEach(function (chart) {
+  var svg = chart.querySelector('svg');
+  var data = JSON.parse(chart.querySelector('script').textContent);
+  var tooltip = chart.querySelector('.tooltip');
+  var cursor = svg.querySelector('.cursor');
+  svg.addEventListener('mousemove', function (event) {
+    var box = svg.getBoundingClientRect();
+    var x = (event.clientX - box.left) * data.width / box.width;
+    var index = Math.round((x - data.left) / data.plot_width * (data.labels.length - 1));
+    if (index < 0 || index >= data.labels.length) { tooltip.style.display = 'none'; return; }
+    var text = data.labels[index];
+    data.series.forEach(function (series) {
+      var value = series.values[index];
+      text += '\\n' + series.name + ': ' + (value === null ? '-' : value.toFixed(4));
+    });
+    var position = data.left + index / Math.max(data.labels.length - 1, 1) * data.plot_width;
+    cursor.setAttribute('x1', position);
+    cursor.setAttribute('x2', position);
+    cursor.style.display = 'block';
+    tooltip.textContent = text;
+    tooltip.style.display = 'block';
+    tooltip.style.left = (event.clientX - box.left + 12) + 'px';
+    tooltip.style.top = (event.clientY - box.top + 12) + 'px';
+  });
+  svg.addEventListener('mouseleave', function () {
+    tooltip.style.display = 'none';
+    cursor.style.display = 'none';
+  });
+});
+";
+
+pub struct Series<'a> {
+    pub name: &'a str,
+    pub color: &'a str,
+    pub points: &'a [(String, f64)],
+}
+
+pub struct Tearsheet {
+    title: String,
+    sections: Vec<String>,
+}
+
+impl Tearsheet {
+    pub fn new(title: &str) -> Self {
+        Tearsheet {
+            title: title.to_string(),
+            sections: Vec::new(),
+        }
+    }
+
+    pub fn add_line_chart(&mut self, heading: &str, series: &[Series], fill_to_zero: bool) {
+        /*
+        Draw every series against its index, the x labels come from the first one.
+        With fill_to_zero the area between the first series and zero is shaded,
+        e.g. for an underwater drawdown chart.
+        */
+        let labels: Vec<&str> = series
+            .first()
+            .map(|first| first.points.iter().map(|(label, _)| label.as_str()).collect())
+            .unwrap_or_default();
+        let length = series.iter().map(|s| s.points.len()).max().unwrap_or(0);
+        let values = series.iter().flat_map(|s| s.points.iter().map(|&(_, value)| value));
+        let mut y_min = values.clone().filter(|v| v.is_finite()).fold(f64::INFINITY, f64::min);
+        let mut y_max = values.filter(|v| v.is_finite()).fold(f64::NEG_INFINITY, f64::max);
+        if fill_to_zero {
+            y_min = y_min.min(0.0);
+            y_max = y_max.max(0.0);
+        }
+        if !y_min.is_finite() || !y_max.is_finite() {
+            (y_min, y_max) = (0.0, 1.0);
+        }
+        if y_max - y_min < 1e-12 {
+            y_max = y_min + 1.0;
+        }
+
+        let plot_width = WIDTH - MARGIN_LEFT - MARGIN_RIGHT;
+        let plot_height = HEIGHT - MARGIN_TOP - MARGIN_BOTTOM;
+        let x_at = |index: usize| {
+            MARGIN_LEFT + index as f64 / (length.max(2) - 1) as f64 * plot_width
+        };
+        let y_at = |value: f64| MARGIN_TOP + (y_max - value) / (y_max - y_min) * plot_height;
+
+        let mut svg = String::new();
+        write!(
+            svg,
+            r#"<svg viewBox="0 0 {} {}" width="100%" xmlns="http://www.w3.org/2000/svg">"#,
+            WIDTH, HEIGHT
+        )
+        .unwrap();
+
+        // Horizontal grid with the y labels
+        for tick in 0..=4 {
+            let value = y_min + (y_max - y_min) * tick as f64 / 4.0;
+            let y = y_at(value);
+            write!(
+                svg,
+                r##"<line x1="{:.1}" y1="{:.1}" x2="{:.1}" y2="{:.1}" stroke="#e5e5e5"/><text x="{:.1}" y="{:.1}" font-size="11" text-anchor="end">{}</text>"##,
+                MARGIN_LEFT,
+                y,
+                WIDTH - MARGIN_RIGHT,
+                y,
+                MARGIN_LEFT - 6.0,
+                y + 4.0,
+                format_tick(value, y_max - y_min)
+            )
+            .unwrap();
+        }
+        // First, middle and last x labels
+        if !labels.is_empty() {
+            for (index, anchor) in [(0, "start"), (labels.len() / 2, "middle"), (labels.len() - 1, "end")] {
+                write!(
+                    svg,
+                    r#"<text x="{:.1}" y="{:.1}" font-size="11" text-anchor="{}">{}</text>"#,
+                    x_at(index),
+                    HEIGHT - 8.0,
+                    anchor,
+                    escape(&labels[index].chars().take(10).collect::<String>())
+                )
+                .unwrap();
+            }
+        }
+
+        for (series_index, s) in series.iter().enumerate() {
+            let path = s
+                .points
+                .iter()
+                .enumerate()
+                .filter(|(_, (_, value))| value.is_finite())
+                .map(|(index, &(_, value))| format!("{:.1},{:.1}", x_at(index), y_at(value)))
+                .collect::<Vec<String>>()
+                .join(" L");
+            if path.is_empty() {
+                continue;
+            }
+            if fill_to_zero && series_index == 0 {
+                write!(
+                    svg,
+                    r#"<path d="M{:.1},{:.1} L{} L{:.1},{:.1} Z" fill="{}" fill-opacity="0.3" stroke="none"/>"#,
+                    x_at(0),
+                    y_at(0.0),
+                    path,
+                    x_at(s.points.len().saturating_sub(1)),
+                    y_at(0.0),
+                    s.color
+                )
+                .unwrap();
+            }
+            write!(
+                svg,
+                r#"<path d="M{}" fill="none" stroke="{}" stroke-width="1.2"/>"#,
+                path, s.color
+            )
+            .unwrap();
+        }
+        write!(
+            svg,
+            r##"<line class="cursor" x1="0" y1="{:.1}" x2="0" y2="{:.1}" stroke="#888" stroke-dasharray="3,3" style="display:none"/></svg>"##,
+            MARGIN_TOP,
+            HEIGHT - MARGIN_BOTTOM
+        )
+        .unwrap();
+
+        let data = serde_json::json!({
+            "width": WIDTH,
+            "left": MARGIN_LEFT,
+            "plot_width": plot_width,
+            "labels": labels,
+            "series": series
+                .iter()
+                .map(|s| serde_json::json!({
+                    "name": s.name,
+                    "values": s.points
+                        .iter()
+                        .map(|&(_, value)| if value.is_finite() { Some(value) } else { None })
+                        .collect::<Vec<Option<f64>>>(),
+                }))
+                .collect::<Vec<serde_json::Value>>(),
+        });
+
+        let mut section = format!("<h2>{}</h2><div class=\"legend\">", escape(heading));
+        for s in series {
+            write!(
+                section,
+                r#"<span><b style="color:{}">&#9644;</b> {}</span>"#,
+                s.color,
+                escape(s.name)
+            )
+            .unwrap();
+        }
+        write!(
+            section,
+            r#"</div><div class="chart">{}<div class="tooltip"></div><script type="application/json">{}</script></div>"#,
+            svg,
+            // No '<' in the data, so a name cannot close the script tag; JSON reads \u003c back as '<'
+            data.to_string().replace('<', "\\u003c")
+        )
+        .unwrap();
+        self.sections.push(section);
+    }
+
+    pub fn add_heatmap(&mut self, heading: &str, monthly_returns: &[(String, f64)]) {
+        // Monthly returns as a year by month grid, labels are "YYYY-MM"
+        let mut years: BTreeMap<&str, [Option<f64>; 12]> = BTreeMap::new();
+        for (month, value) in monthly_returns {
+            let (year, month_number) = match (month.get(..4), month.get(5..7).and_then(|m| m.parse::<usize>().ok())) {
+                (Some(year), Some(month_number)) if (1..=12).contains(&month_number) => (year, month_number),
+                _ => continue,
+            };
+            years.entry(year).or_insert([None; 12])[month_number - 1] = Some(*value);
+        }
+        let max_abs = monthly_returns
+            .iter()
+            .map(|(_, value)| value.abs())
+            .filter(|value| value.is_finite())
+            .fold(0.0, f64::max)
+            .max(1e-12);
+
+        let mut section = format!("<h2>{}</h2><table><tr><th>Year</th>", escape(heading));
+        for month in ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"] {
+            write!(section, "<th>{}</th>", month).unwrap();
+        }
+        section.push_str("</tr>");
+        for (year, months) in &years {
+            write!(section, "<tr><td>{}</td>", year).unwrap();
+            for (index, value) in months.iter().enumerate() {
+                match value {
+                    Some(value) => {
+                        // Green for gains and red for losses, stronger for larger moves
+                        let intensity = (value.abs() / max_abs).min(1.0);
+                        let color = if *value >= 0.0 { "0,150,60" } else { "210,40,40" };
+                        write!(
+                            section,
+                            r#"<td style="background:rgba({},{:.2})" title="{}-{:02}: {:.2}%">{:.1}%</td>"#,
+                            color,
+                            0.1 + 0.8 * intensity,
+                            year,
+                            index + 1,
+                            value * 100.0,
+                            value * 100.0
+                        )
+                        .unwrap();
+                    }
+                    None => section.push_str("<td></td>"),
+                }
+            }
+            section.push_str("</tr>");
+        }
+        section.push_str("</table>");
+        self.sections.push(section);
+    }
+
+    pub fn add_table(&mut self, heading: &str, headers: &[&str], rows: &[Vec<String>]) {
+        let mut section = format!("<h2>{}</h2><div class=\"scroll\"><table><tr>", escape(heading));
+        for header in headers {
+            write!(section, "<th>{}</th>", escape(header)).unwrap();
+        }
+        section.push_str("</tr>");
+        for row in rows {
+            section.push_str("<tr>");
+            for cell in row {
+                write!(section, "<td>{}</td>", escape(cell)).unwrap();
+            }
+            section.push_str("</tr>");
+        }
+        section.push_str("</table></div>");
+        self.sections.push(section);
+    }
+
+    pub fn render(&self) -> String {
+        format!(
+            "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>{}</title><style>{}</style></head>\n<body><h1>{}</h1>\n{}\n<script>{}</script></body></html>\n",
+            escape(&self.title),
+            STYLE,
+            escape(&self.title),
+            self.sections.join("\n"),
+            SCRIPT
+        )
+    }
+
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, self.render())
+    }
+}
+
+pub fn monthly_returns(history: &[(Timestamp, f64)]) -> Vec<(String, f64)> {
+    // Return of every calendar month, from the last value of the previous month
+    let mut returns: Vec<(String, f64)> = Vec::new();
+    let mut previous_close = match history.first() {
+        Some(&(_, value)) => value,
+        None => return returns,
+    };
+    let mut current: Option<(String, f64)> = None;
+    for (timestamp, value) in history {
+        let month = timestamp.format("%Y-%m").to_string();
+        if let Some((current_month, close)) = current.take() {
+            if current_month != month {
+                returns.push((current_month, close / previous_close - 1.0));
+                previous_close = close;
+            }
+        }
+        current = Some((month, *value));
+    }
+    if let Some((month, close)) = current {
+        returns.push((month, close / previous_close - 1.0));
+    }
+    returns
+}
+
+fn format_tick(value: f64, range: f64) -> String {
+    if range >= 100.0 {
+        format!("{:.0}", value)
+    } else if range >= 1.0 {
+        format!("{:.2}", value)
+    } else {
+        format!("{:.4}", value)
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn points(values: &[(&str, f64)]) -> Vec<(String, f64)> {
+        values
+            .iter()
+            .map(|&(label, value)| (label.to_string(), value))
+            .collect()
+    }
+
+    #[test]
+    fn test_monthly_returns() {
+        let history: Vec<(Timestamp, f64)> = [
+            ("2024-01-02", 100.),
+            ("2024-01-31", 110.),
+            ("2024-02-01", 99.),
+            ("2024-02-29", 121.),
+            ("2024-03-01", 133.1),
+        ]
+        .iter()
+        .map(|&(day, value)| (day.parse().unwrap(), value))
+        .collect();
+        let returns = monthly_returns(&history);
+        let months: Vec<&str> = returns.iter().map(|(month, _)| month.as_str()).collect();
+        assert_eq!(months, vec!["2024-01", "2024-02", "2024-03"]);
+        assert!((returns[0].1 - 0.1).abs() < 1e-9);
+        assert!((returns[1].1 - 0.1).abs() < 1e-9);
+        assert!((returns[2].1 - 0.1).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_render_is_self_contained() {
+        let equity = points(&[("2024-01-02", 1.), ("2024-01-03", 1.1), ("2024-02-01", 0.9)]);
+        let mut tearsheet = Tearsheet::new("Test <run>");
+        tearsheet.add_line_chart(
+            "Equity",
+            &[Series {
+                name: "Strategy</script><script>alert(1)</script>",
+                color: "red",
+                points: &equity,
+            }],
+            false,
+        );
+        let history: Vec<(Timestamp, f64)> = equity
+            .iter()
+            .map(|(label, value)| (label.parse().unwrap(), *value))
+            .collect();
+        tearsheet.add_heatmap("Monthly Returns", &monthly_returns(&history));
+        tearsheet.add_table("Trades", &["Symbol", "P&L"], &[vec!["A".to_string(), "1.00".to_string()]]);
+
+        let html = tearsheet.render();
+        assert!(html.contains("<h1>Test &lt;run&gt;</h1>"));
+        assert!(html.contains("<svg"));
+        assert!(html.contains("title=\"2024-02: -18.18%\""));
+        assert!(html.contains("<th>P&amp;L</th>"));
+        // The series name is escaped in the legend and in the chart data
+        assert_eq!(html.matches("</script>").count(), html.matches("<script").count());
+        assert!(html.contains("Strategy\\u003c/script>\\u003cscript>alert(1)\\u003c/script>"));
+        // No external scripts, styles or images
+        assert!(!html.contains("src="));
+        assert!(!html.contains("href="));
+    }
+}